@@ -10,7 +10,6 @@ export default Ember.Route.extend({
         {
             ajax('/me').then((response) => {
                 var user = this.store.push(this.store.normalize('user', response.user));
-                user.set('api_token', response.api_token);
                 this.session.set('currentUser', user);
             }).catch(() => this.session.logoutUser()).
               finally(() => {
@@ -19,7 +19,7 @@ cd tmp/index-co
 cat > config.json <<-EOF
 {
   "dl": "http://localhost:8888/api/v1/crates",
-  "api": "http://localhost:8888/"
+  "api": "http://localhost:8888"
 }
 EOF
 git add config.json
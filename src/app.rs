@@ -1,26 +1,27 @@
 use std::error::Error;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 
 use conduit::Request;
 use conduit_middleware::Middleware;
-use git2;
 use oauth2;
 use r2d2;
-use s3;
 use curl::http;
 use yaqb::Connection;
 
-use {db, Config};
+use {db, storage, Config};
+use storage::Storage;
 
 pub struct App {
     pub database: db::Pool,
+    /// Connections for updating rate limit buckets, which happens outside of
+    /// the request's transaction while it holds a connection from `database`.
+    pub rate_limit_database: db::Pool,
     pub database_url: String,
     pub github: oauth2::Config,
-    pub bucket: s3::Bucket,
+    pub storage: Box<Storage>,
     pub s3_proxy: Option<String>,
     pub session_key: String,
-    pub git_repo: Mutex<git2::Repository>,
     pub git_repo_checkout: PathBuf,
     pub config: Config,
 }
@@ -40,24 +41,21 @@ impl App {
 
         github.scopes.push(String::from("read:org"));
 
-        let db_config = r2d2::Config::builder()
-            .pool_size(if config.env == ::Env::Production {10} else {1})
-            .helper_threads(if config.env == ::Env::Production {3} else {1})
-            .build();
+        let db_config = || {
+            r2d2::Config::builder()
+                .pool_size(if config.env == ::Env::Production {10} else {1})
+                .helper_threads(if config.env == ::Env::Production {3} else {1})
+                .build()
+        };
 
-        let repo = git2::Repository::open(&config.git_repo_checkout).unwrap();
         return App {
-            database: db::pool(&config.db_url, db_config),
+            database: db::pool(&config.db_url, db_config()),
+            rate_limit_database: db::pool(&config.db_url, db_config()),
             database_url: config.db_url.clone(),
             github: github,
-            bucket: s3::Bucket::new(config.s3_bucket.clone(),
-                                    config.s3_region.clone(),
-                                    config.s3_access_key.clone(),
-                                    config.s3_secret_key.clone(),
-                                    config.api_protocol()),
+            storage: storage::new(config),
             s3_proxy: config.s3_proxy.clone(),
             session_key: config.session_key.clone(),
-            git_repo: Mutex::new(repo),
             git_repo_checkout: config.git_repo_checkout.clone(),
             config: config.clone(),
         };
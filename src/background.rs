@@ -0,0 +1,212 @@
+//! A queue of changes to the git index, stored in the `background_jobs` table.
+//!
+//! Pushing to the index is slow and can fail, so instead of doing it while
+//! handling a request we enqueue a job in the same transaction as the
+//! corresponding database change. The `background-worker` binary then applies
+//! the jobs in the order they were enqueued, retrying a failing job with an
+//! increasing delay before moving on to any of the jobs after it.
+//!
+//! A job which has failed `MAX_RETRIES` times is given up on and an error is
+//! logged. It stays in the table along with its last error, and is picked up
+//! again once its `retries` are reset to 0. Until then it only holds up the
+//! jobs after it which change the same index files, so that those are still
+//! applied in order, while the rest of the queue carries on.
+
+use std::cmp;
+use std::collections::HashSet;
+
+use git2;
+use pg::GenericConnection;
+use pg::rows::Row;
+use rustc_serialize::json;
+use time::{Duration, Timespec};
+
+use Model;
+use git;
+use util::{CargoResult, internal};
+
+/// The longest we'll wait before retrying a failed job, in seconds.
+const MAX_BACKOFF: i64 = 60 * 60;
+
+/// How many times a job may fail before it is given up on.
+pub const MAX_RETRIES: i32 = 16;
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub enum Job {
+    AddCrate(git::Crate),
+    Yank { krate: String, version: String, yanked: bool },
+    Rename { old: String, new: String },
+    UpdateConfig(String),
+}
+
+pub struct BackgroundJob {
+    pub id: i32,
+    pub job: String,
+    pub retries: i32,
+    pub last_retry: Option<Timespec>,
+    pub last_error: Option<String>,
+    pub created_at: Timespec,
+}
+
+impl Job {
+    /// Adds this job to the end of the queue. It will not be run before the
+    /// transaction `conn` belongs to is committed.
+    pub fn enqueue(&self, conn: &GenericConnection) -> CargoResult<()> {
+        let job = json::encode(self).unwrap();
+        try!(conn.execute("INSERT INTO background_jobs (job) VALUES ($1)",
+                          &[&job]));
+        Ok(())
+    }
+
+    /// Whether an identical job is already waiting in the queue, including
+    /// one which was given up on.
+    pub fn is_pending(&self, conn: &GenericConnection) -> CargoResult<bool> {
+        let job = json::encode(self).unwrap();
+        let stmt = try!(conn.prepare("SELECT 1 FROM background_jobs
+                                      WHERE job = $1 LIMIT 1"));
+        let rows = try!(stmt.query(&[&job]));
+        Ok(rows.iter().next().is_some())
+    }
+
+    /// The files in the index this job changes, by the lowercased name of the
+    /// crate they belong to.
+    fn files(&self) -> Vec<String> {
+        match *self {
+            Job::AddCrate(ref krate) => vec![krate.name.to_lowercase()],
+            Job::Yank { ref krate, .. } => vec![krate.to_lowercase()],
+            Job::Rename { ref new, .. } => vec![new.to_lowercase()],
+            Job::UpdateConfig(..) => vec!["config.json".to_string()],
+        }
+    }
+
+    fn run(&self, repo: &git2::Repository) -> CargoResult<()> {
+        match *self {
+            Job::AddCrate(ref krate) => git::add_crate(repo, krate),
+            Job::Yank { ref krate, ref version, yanked } => {
+                git::yank(repo, krate, version, yanked)
+            }
+            Job::Rename { ref old, ref new } => {
+                git::rename_crate(repo, old, new)
+            }
+            Job::UpdateConfig(ref contents) => {
+                git::update_config(repo, contents)
+            }
+        }
+    }
+}
+
+impl BackgroundJob {
+    /// The earliest time at which this job should be tried again.
+    pub fn next_retry(&self) -> Option<Timespec> {
+        self.last_retry.map(|t| {
+            let backoff = 2i64.pow(cmp::min(self.retries, 12) as u32);
+            t + Duration::seconds(cmp::min(backoff, MAX_BACKOFF))
+        })
+    }
+}
+
+impl Model for BackgroundJob {
+    fn from_row(row: &Row) -> BackgroundJob {
+        BackgroundJob {
+            id: row.get("id"),
+            job: row.get("job"),
+            retries: row.get("retries"),
+            last_retry: row.get("last_retry"),
+            last_error: row.get("last_error"),
+            created_at: row.get("created_at"),
+        }
+    }
+
+    fn table_name(_: Option<BackgroundJob>) -> &'static str { "background_jobs" }
+}
+
+/// Runs jobs in the order they were enqueued until the queue is empty,
+/// returning how many were run.
+///
+/// If a job fails, the failure is recorded on the job and returned, and no
+/// other jobs are run. The same happens if the oldest job previously failed
+/// and is still waiting to be retried, except that `Ok` is returned. Jobs
+/// which were given up on are skipped, along with the jobs after them which
+/// change the same files.
+pub fn run_pending(conn: &GenericConnection,
+                   repo: &git2::Repository) -> CargoResult<usize> {
+    let mut ran = 0;
+    loop {
+        let tx = try!(conn.transaction());
+        let job = {
+            let stmt = try!(tx.prepare("SELECT * FROM background_jobs
+                                        ORDER BY id ASC FOR UPDATE"));
+            let rows = try!(stmt.query(&[]));
+            let jobs = rows.iter().map(|r| -> BackgroundJob {
+                Model::from_row(&r)
+            });
+            next_job(jobs)
+        };
+        let job = match job {
+            Some(job) => job,
+            None => return Ok(ran),
+        };
+        match job.next_retry() {
+            Some(t) if t > ::now() => return Ok(ran),
+            _ => {}
+        }
+
+        let result = json::decode::<Job>(&job.job).map_err(|_| {
+            internal(format!("couldn't decode job {}: `{}`", job.id, job.job))
+        }).and_then(|j| j.run(repo));
+        match result {
+            Ok(()) => {
+                try!(tx.execute("DELETE FROM background_jobs WHERE id = $1",
+                                &[&job.id]));
+            }
+            Err(e) => {
+                if job.retries + 1 >= MAX_RETRIES {
+                    error!("giving up on job {} after {} failures, the jobs \
+                            after it changing the same files are held up \
+                            until its retries are reset: {}", job.id,
+                           job.retries + 1, e);
+                }
+                try!(tx.execute("UPDATE background_jobs
+                                    SET retries = retries + 1,
+                                        last_retry = $1,
+                                        last_error = $2
+                                  WHERE id = $3",
+                                &[&::now(), &e.to_string(), &job.id]));
+                tx.set_commit();
+                try!(tx.finish());
+                return Err(internal(format!("job {} failed: {}", job.id, e)))
+            }
+        }
+        tx.set_commit();
+        try!(tx.finish());
+        ran += 1;
+    }
+}
+
+/// The first of `jobs` which may run, skipping the ones which were given up
+/// on and the ones changing the same files as those. A job which can't be
+/// decoded isn't known to leave any file alone, so nothing after it runs once
+/// it was given up on.
+fn next_job<I>(jobs: I) -> Option<BackgroundJob>
+    where I: Iterator<Item=BackgroundJob>
+{
+    let mut blocked = HashSet::new();
+    for job in jobs {
+        let files = json::decode::<Job>(&job.job).ok().map(|j| j.files());
+        if job.retries < MAX_RETRIES {
+            let held_up = match files {
+                Some(ref files) => files.iter().any(|f| blocked.contains(f)),
+                None => false,
+            };
+            if !held_up {
+                return Some(job)
+            }
+        } else {
+            match files {
+                Some(files) => blocked.extend(files),
+                None => return None,
+            }
+        }
+    }
+    None
+}
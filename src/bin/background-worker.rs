@@ -0,0 +1,82 @@
+// Applies the changes to the git index which have been queued up in the
+// `background_jobs` table.
+//
+// Usage:
+//      cargo run --bin background-worker [sleep-seconds]
+
+#![deny(warnings)]
+
+extern crate cargo_registry;
+extern crate env_logger;
+extern crate git2;
+extern crate postgres;
+#[macro_use] extern crate log;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use cargo_registry::background;
+
+fn main() {
+    env_logger::init().unwrap();
+    let sleep = env::args().nth(1).map(|s| s.parse().unwrap()).unwrap_or(5);
+    let repo = checkout(&env("GIT_REPO_URL"),
+                        &PathBuf::from(env("GIT_REPO_CHECKOUT")));
+
+    // The connection is kept across runs, and only replaced after an error
+    // in case that was the connection going away.
+    let mut conn = None;
+    loop {
+        if conn.is_none() {
+            match postgres::Connection::connect(&env("DATABASE_URL")[..],
+                                                &postgres::SslMode::None) {
+                Ok(c) => conn = Some(c),
+                Err(e) => error!("failed to connect to the database: {}", e),
+            }
+        }
+        let failed = match conn {
+            Some(ref conn) => match background::run_pending(conn, &repo) {
+                Ok(..) => false,
+                Err(e) => { error!("{}", e); true }
+            },
+            None => false,
+        };
+        if failed {
+            conn = None;
+        }
+        thread::sleep(Duration::new(sleep, 0));
+    }
+}
+
+fn checkout(url: &str, checkout: &Path) -> git2::Repository {
+    let repo = match git2::Repository::open(checkout) {
+        Ok(r) => r,
+        Err(..) => {
+            let _ = fs::remove_dir_all(checkout);
+            fs::create_dir_all(checkout).unwrap();
+            let mut cb = git2::RemoteCallbacks::new();
+            cb.credentials(cargo_registry::git::credentials);
+            let mut opts = git2::FetchOptions::new();
+            opts.remote_callbacks(cb);
+            git2::build::RepoBuilder::new()
+                                     .fetch_options(opts)
+                                     .clone(url, checkout).unwrap()
+        }
+    };
+    {
+        let mut cfg = repo.config().unwrap();
+        cfg.set_str("user.name", "bors").unwrap();
+        cfg.set_str("user.email", "bors@rust-lang.org").unwrap();
+    }
+    repo
+}
+
+fn env(s: &str) -> String {
+    match env::var(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
+}
@@ -0,0 +1,217 @@
+// Checks that every version in the database agrees with its line in the
+// registry index and with the `.crate` file in storage.
+//
+// Each mismatch is printed as a line of JSON. With `--fix`, mismatches which
+// can safely be fixed are: a wrong `yanked` flag in the index is corrected by
+// a background job, and an empty checksum in the database is filled in from
+// the stored tarball when it agrees with the index. A yank which is already
+// queued isn't queued again. The exit status is 0 when there were no
+// mismatches or all of them were fixed.
+//
+// Versions whose index line has no checksum, which predate checksums, aren't
+// checked against storage, and neither are versions without a checksum in the
+// database. Errors reading from storage are reported along with the
+// mismatches.
+//
+// Usage:
+//      cargo run --bin check-consistency [--fix]
+
+#![deny(warnings)]
+
+extern crate cargo_registry;
+extern crate openssl;
+extern crate postgres;
+extern crate rustc_serialize;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use openssl::crypto::hash::{hash, Type};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{self, Json};
+
+use cargo_registry::{Crate, Model, Version};
+use cargo_registry::background::Job;
+use cargo_registry::git;
+use cargo_registry::storage::{self, Storage};
+
+#[derive(RustcEncodable)]
+struct Mismatch {
+    krate: String,
+    version: String,
+    kind: &'static str,
+    database: Option<String>,
+    found: Option<String>,
+    fixed: bool,
+}
+
+fn main() {
+    let fix = env::args().nth(1).as_ref().map(|s| &s[..]) == Some("--fix");
+    let checkout = PathBuf::from(env("GIT_REPO_CHECKOUT"));
+    let storage = storage::open(&storage::from_env(), None, "https");
+    let conn = postgres::Connection::connect(&env("DATABASE_URL")[..],
+                                             &postgres::SslMode::None).unwrap();
+    let tx = conn.transaction().unwrap();
+
+    let mut unfixed = 0;
+    let stmt = tx.prepare("SELECT * FROM crates ORDER BY id ASC").unwrap();
+    for row in stmt.query(&[]).unwrap().iter() {
+        let krate: Crate = Model::from_row(&row);
+        for mismatch in check(&tx, &checkout, &*storage, &krate, fix) {
+            println!("{}", json::encode(&mismatch).unwrap());
+            if !mismatch.fixed {
+                unfixed += 1;
+            }
+        }
+    }
+
+    if fix {
+        tx.set_commit();
+    }
+    tx.finish().unwrap();
+    if unfixed > 0 {
+        std::process::exit(1);
+    }
+}
+
+fn env(s: &str) -> String {
+    match env::var(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
+}
+
+/// Reads the lines of the index file for `name`, keyed by version.
+fn index_lines(checkout: &Path, name: &str) -> HashMap<String, git::Crate> {
+    let mut contents = String::new();
+    if let Ok(mut f) = File::open(git::index_file(checkout, name)) {
+        f.read_to_string(&mut contents).unwrap();
+    }
+    contents.lines().filter_map(|line| {
+        json::decode::<git::Crate>(line).ok()
+    }).filter(|line| line.name == name).map(|line| {
+        (line.vers.clone(), line)
+    }).collect()
+}
+
+fn check(tx: &postgres::Transaction, checkout: &Path, storage: &Storage,
+         krate: &Crate, fix: bool) -> Vec<Mismatch> {
+    let mut ret = Vec::new();
+    let aliases = krate.aliases(tx).unwrap();
+    let mut files = HashMap::new();
+    let stmt = tx.prepare("SELECT * FROM versions WHERE crate_id = $1
+                           ORDER BY id ASC").unwrap();
+    for row in stmt.query(&[&krate.id]).unwrap().iter() {
+        let mut version: Version = Model::from_row(&row);
+        let vers = version.num.to_string();
+        // Versions published before a rename stay under the old name
+        let name = krate.published_name(&aliases, &version).to_string();
+        let mismatch = |kind, database: Option<String>, found: Option<String>| {
+            Mismatch {
+                krate: name.clone(),
+                version: vers.clone(),
+                kind: kind,
+                database: database,
+                found: found,
+                fixed: false,
+            }
+        };
+
+        let lines = files.entry(name.clone()).or_insert_with(|| {
+            index_lines(checkout, &name)
+        });
+        let line = match lines.get(&vers) {
+            Some(line) => line,
+            None => {
+                ret.push(mismatch("missing-index-line", None, None));
+                continue
+            }
+        };
+
+        // The yanked flag
+        let yanked = line.yanked.unwrap_or(false);
+        if yanked != version.yanked {
+            let mut m = mismatch("yanked", Some(version.yanked.to_string()),
+                                 Some(yanked.to_string()));
+            if fix {
+                let job = Job::Yank {
+                    krate: name.clone(),
+                    version: vers.clone(),
+                    yanked: version.yanked,
+                };
+                if !job.is_pending(tx).unwrap() {
+                    job.enqueue(tx).unwrap();
+                }
+                m.fixed = true;
+            }
+            ret.push(m);
+        }
+
+        // The checksum, against the stored tarball and the database. Versions
+        // without one in the index predate checksums, so they're skipped.
+        let path = krate.version_storage_path(tx, &version).unwrap();
+        let tarball = if line.cksum.is_empty() {
+            None
+        } else {
+            match storage.read(&path) {
+                Ok(Some(tarball)) => Some(tarball),
+                Ok(None) => {
+                    ret.push(mismatch("missing-tarball", None, Some(path)));
+                    None
+                }
+                Err(e) => {
+                    ret.push(mismatch("storage-error", None,
+                                      Some(e.to_string())));
+                    None
+                }
+            }
+        };
+        if let Some(tarball) = tarball {
+            let cksum = hash(Type::SHA256, &tarball).to_hex();
+            if cksum != line.cksum {
+                ret.push(mismatch("index-cksum", Some(cksum.clone()),
+                                  Some(line.cksum.clone())));
+            }
+            match version.checksum.clone() {
+                // Versions published before checksums were recorded
+                None => {}
+                Some(ref db) if *db == cksum => {}
+                Some(ref db) if !db.is_empty() => {
+                    ret.push(mismatch("database-cksum", Some(db.clone()),
+                                      Some(cksum)));
+                }
+                Some(..) => {
+                    let mut m = mismatch("database-cksum", None,
+                                         Some(cksum.clone()));
+                    if fix && cksum == line.cksum {
+                        version.set_checksum(tx, &cksum).unwrap();
+                        m.fixed = true;
+                    }
+                    ret.push(m);
+                }
+            }
+        }
+
+        // The dependencies
+        let expected = version.git_encode(tx, &name).unwrap().deps;
+        let encode = |deps: &[git::Dependency]| {
+            let mut deps = deps.iter().map(|d| json::encode(d).unwrap())
+                               .collect::<Vec<_>>();
+            deps.sort();
+            deps
+        };
+        let (expected, found) = (encode(&expected[..]), encode(&line.deps[..]));
+        let same = expected.len() == found.len() &&
+                   expected.iter().zip(found.iter()).all(|(a, b)| {
+                       Json::from_str(a).ok() == Json::from_str(b).ok()
+                   });
+        if !same {
+            ret.push(mismatch("dependencies", Some(expected.join(",")),
+                              Some(found.join(","))));
+        }
+    }
+    ret
+}
@@ -0,0 +1,189 @@
+// Writes a snapshot of the public parts of the database to a gzipped tarball
+// with one CSV file per table, along with a `manifest.json` describing the
+// columns of each table.
+//
+// Secrets such as API tokens, GitHub access tokens and email addresses are
+// never included, and neither are owners which have since been removed.
+//
+// Each table is streamed to a temporary file next to the tarball before being
+// added to it, so the tables don't need to fit in memory.
+//
+// Usage:
+//      cargo run --bin dump-db path/to/dump.tar.gz
+
+#![deny(warnings)]
+
+extern crate flate2;
+extern crate postgres;
+extern crate rustc_serialize;
+extern crate tar;
+extern crate time;
+
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use rustc_serialize::json;
+
+/// The tables which are exported, with the columns to export from each and a
+/// condition the exported rows must meet. If no columns are listed then all of
+/// them are exported except for `EXCLUDED`.
+const TABLES: &'static [(&'static str, &'static [&'static str], &'static str)] = &[
+    ("crates", &[], "TRUE"),
+    ("versions", &[], "TRUE"),
+    ("dependencies", &[], "TRUE"),
+    ("keywords", &[], "TRUE"),
+    ("crates_keywords", &[], "TRUE"),
+    ("crate_owners", &[], "deleted = FALSE"),
+    ("users", &["id", "gh_login", "name", "gh_avatar"], "TRUE"),
+    ("version_downloads", &[], "TRUE"),
+];
+
+/// How many rows are read from the database at a time.
+const BATCH_SIZE: i32 = 1000;
+
+/// Columns which are never exported from any table.
+const EXCLUDED: &'static [&'static str] = &[
+    "api_token", "gh_access_token", "email", "textsearchable_index_col",
+];
+
+#[derive(RustcEncodable)]
+struct Manifest {
+    timestamp: String,
+    tables: Vec<Table>,
+}
+
+#[derive(RustcEncodable)]
+struct Table {
+    name: String,
+    file: String,
+    rows: u64,
+    columns: Vec<Column>,
+}
+
+#[derive(RustcEncodable)]
+struct Column {
+    name: String,
+    data_type: String,
+}
+
+fn main() {
+    let dst = env::args().nth(1).expect("must specify the file to write to");
+    let conn = postgres::Connection::connect(&env("DATABASE_URL")[..],
+                                             &postgres::SslMode::None).unwrap();
+
+    // Every table is read from the same snapshot, so rows in one table
+    // always refer to rows which are present in the others.
+    let tx = conn.transaction().unwrap();
+    tx.batch_execute("SET TRANSACTION ISOLATION LEVEL REPEATABLE READ,
+                                     READ ONLY").unwrap();
+
+    let now = time::now_utc();
+    let dir = time::strftime("%Y-%m-%d-%H%M%S", &now).unwrap();
+    let mut ar = tar::Builder::new(GzEncoder::new(File::create(&dst).unwrap(),
+                                                  Compression::Default));
+    let mut manifest = Manifest {
+        timestamp: now.rfc3339().to_string(),
+        tables: Vec::new(),
+    };
+    for &(table, columns, condition) in TABLES {
+        let columns = self::columns(&tx, table, columns);
+        let tmp = format!("{}.{}.csv.tmp", dst, table);
+        let rows = dump(&tx, table, &columns, condition,
+                        BufWriter::new(File::create(&tmp).unwrap()));
+        let file = format!("{}.csv", table);
+        let mut csv = File::open(&tmp).unwrap();
+        let size = csv.metadata().unwrap().len();
+        append(&mut ar, &format!("{}/{}", dir, file), &mut csv, size);
+        fs::remove_file(&tmp).unwrap();
+        println!("{}: {} rows", table, rows);
+        manifest.tables.push(Table {
+            name: table.to_string(),
+            file: file,
+            rows: rows,
+            columns: columns,
+        });
+    }
+    let manifest = json::as_pretty_json(&manifest).to_string();
+    append(&mut ar, &format!("{}/manifest.json", dir),
+           &mut manifest.as_bytes(), manifest.len() as u64);
+    ar.into_inner().unwrap().finish().unwrap();
+}
+
+fn env(s: &str) -> String {
+    match env::var(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
+}
+
+/// Looks up the exported columns of `table` in the order they were created.
+fn columns(tx: &postgres::Transaction, table: &str,
+           only: &[&str]) -> Vec<Column> {
+    let stmt = tx.prepare("SELECT column_name::text, data_type::text
+                             FROM information_schema.columns
+                            WHERE table_schema = 'public'
+                              AND table_name = $1
+                            ORDER BY ordinal_position").unwrap();
+    let rows = stmt.query(&[&table]).unwrap();
+    let columns = rows.iter().map(|row| {
+        Column { name: row.get(0), data_type: row.get(1) }
+    }).filter(|c| !EXCLUDED.contains(&&c.name[..])).filter(|c| {
+        only.is_empty() || only.contains(&&c.name[..])
+    }).collect();
+    columns
+}
+
+/// Writes every row of `table` which meets `condition` to `dst` as CSV, with
+/// a header line of column names, returning how many rows there were.
+fn dump<W: Write>(tx: &postgres::Transaction, table: &str, columns: &[Column],
+                  condition: &str, mut dst: W) -> u64 {
+    let names = columns.iter().map(|c| &c.name[..]).collect::<Vec<_>>();
+    let select = columns.iter().map(|c| format!("\"{}\"::text", c.name))
+                        .collect::<Vec<_>>();
+    let stmt = tx.prepare(&format!("SELECT {} FROM \"{table}\" WHERE {}
+                                    ORDER BY \"{table}\".\"{}\"",
+                                   select.join(", "), condition, names[0],
+                                   table = table)).unwrap();
+
+    let header = names.iter().map(|n| escape(Some(*n))).collect::<Vec<_>>();
+    writeln!(dst, "{}", header.join(",")).unwrap();
+    let mut rows = 0;
+    for row in stmt.lazy_query(tx, &[], BATCH_SIZE).unwrap() {
+        let row = row.unwrap();
+        let fields = (0..columns.len()).map(|i| {
+            let field: Option<String> = row.get(i);
+            escape(field.as_ref().map(|s| &s[..]))
+        }).collect::<Vec<_>>();
+        writeln!(dst, "{}", fields.join(",")).unwrap();
+        rows += 1;
+    }
+    dst.flush().unwrap();
+    rows
+}
+
+/// Formats a field for CSV. `NULL` is written as nothing at all, whereas an
+/// empty string is written as `""`.
+fn escape(field: Option<&str>) -> String {
+    match field {
+        None => String::new(),
+        Some(s) if s.is_empty() || s.contains(|c: char| "\",\r\n".contains(c)) => {
+            format!("\"{}\"", s.replace("\"", "\"\""))
+        }
+        Some(s) => s.to_string(),
+    }
+}
+
+fn append<W: Write, R: Read>(ar: &mut tar::Builder<W>, path: &str,
+                            data: &mut R, size: u64) {
+    let mut header = tar::Header::new_gnu();
+    header.set_path(path).unwrap();
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(time::now_utc().to_timespec().sec as u64);
+    header.set_cksum();
+    ar.append(&header, data).unwrap();
+}
@@ -3,7 +3,9 @@
 
 extern crate cargo_registry;
 extern crate migrate;
+extern crate openssl;
 extern crate postgres;
+extern crate rustc_serialize;
 
 use std::env;
 use std::collections::HashSet;
@@ -482,6 +484,216 @@ fn migrations() -> Vec<Migration> {
                              UNIQUE (owner_id, crate_id)", &[]));
             Ok(())
         }),
+        Migration::add_column(20151102101512, "versions", "checksum",
+                              "VARCHAR"),
+        Migration::add_table(20151109143207, "version_readmes", "
+            version_id      INTEGER PRIMARY KEY REFERENCES versions (id)
+                            ON DELETE CASCADE,
+            content         VARCHAR NOT NULL,
+            rendered        VARCHAR NOT NULL
+        "),
+        Migration::add_table(20151116093341, "api_tokens", "
+            id              SERIAL PRIMARY KEY,
+            user_id         INTEGER NOT NULL REFERENCES users (id)
+                            ON DELETE CASCADE,
+            name            VARCHAR NOT NULL,
+            token           VARCHAR NOT NULL UNIQUE,
+            created_at      TIMESTAMP NOT NULL DEFAULT now(),
+            last_used_at    TIMESTAMP,
+            scopes          VARCHAR,
+            crates          VARCHAR
+        "),
+        index(20151116093342, "api_tokens", "user_id"),
+        Migration::new(20151123110215, |tx| {
+            digest_api_tokens(tx)
+        }, |_tx| {
+            // Digests can't be turned back into tokens, so everyone will need
+            // to generate a new token after rolling this back.
+            Ok(())
+        }),
+        Migration::add_table(20151130152104, "crate_aliases", "
+            id              SERIAL PRIMARY KEY,
+            crate_id        INTEGER NOT NULL REFERENCES crates (id)
+                            ON DELETE CASCADE,
+            name            VARCHAR NOT NULL,
+            created_at      TIMESTAMP NOT NULL DEFAULT now()
+        "),
+        Migration::run(20151130152105,
+                       "CREATE INDEX index_crate_aliases_name \
+                        ON crate_aliases (canon_crate_name(name))",
+                       "DROP INDEX index_crate_aliases_name"),
+        index(20151130152106, "crate_aliases", "crate_id"),
+        Migration::add_table(20151207104318, "background_jobs", "
+            id              SERIAL PRIMARY KEY,
+            job             VARCHAR NOT NULL,
+            retries         INTEGER NOT NULL DEFAULT 0,
+            last_retry      TIMESTAMP,
+            last_error      VARCHAR,
+            created_at      TIMESTAMP NOT NULL DEFAULT now()
+        "),
+        Migration::add_table(20151214101522, "rate_limit_buckets", "
+            route_group     VARCHAR NOT NULL,
+            key             VARCHAR NOT NULL,
+            tokens          DOUBLE PRECISION NOT NULL,
+            last_refill     TIMESTAMP NOT NULL,
+            PRIMARY KEY (route_group, key)
+        "),
+        Migration::add_table(20151215094410, "version_download_clients", "
+            version_id      INTEGER NOT NULL REFERENCES versions (id)
+                            ON DELETE CASCADE,
+            date            DATE NOT NULL,
+            fingerprint     VARCHAR NOT NULL,
+            client          VARCHAR NOT NULL,
+            downloads       INTEGER NOT NULL,
+            PRIMARY KEY (version_id, date, fingerprint)
+        "),
+        Migration::add_column(20151215094411, "version_downloads",
+                              "unique_downloads", "INTEGER NOT NULL DEFAULT 0"),
+        Migration::add_column(20151215094412, "crate_downloads",
+                              "unique_downloads", "INTEGER NOT NULL DEFAULT 0"),
+        Migration::add_column(20151215094413, "versions",
+                              "unique_downloads", "INTEGER NOT NULL DEFAULT 0"),
+        Migration::add_column(20151215094414, "crates",
+                              "unique_downloads", "INTEGER NOT NULL DEFAULT 0"),
+        Migration::new(20151216103054, |tx| {
+            // Merge the rows which racing downloads created for the same
+            // version and day, and store just the day from now on.
+            try!(tx.execute("CREATE TEMPORARY TABLE merged_version_downloads AS
+                             SELECT MIN(id) AS id, version_id,
+                                    SUM(downloads)::int AS downloads,
+                                    SUM(counted)::int AS counted,
+                                    MAX(unique_downloads) AS unique_downloads,
+                                    bool_and(processed) AS processed,
+                                    date(date) AS date
+                               FROM version_downloads
+                              GROUP BY version_id, date(date)", &[]));
+            try!(tx.execute("DELETE FROM version_downloads
+                              USING merged_version_downloads m
+                              WHERE version_downloads.version_id = m.version_id
+                                AND date(version_downloads.date) = m.date
+                                AND version_downloads.id != m.id", &[]));
+            try!(tx.execute("UPDATE version_downloads
+                                SET downloads = m.downloads,
+                                    counted = m.counted,
+                                    unique_downloads = m.unique_downloads,
+                                    processed = m.processed
+                               FROM merged_version_downloads m
+                              WHERE version_downloads.id = m.id", &[]));
+            try!(tx.execute("DROP TABLE merged_version_downloads", &[]));
+            try!(tx.execute("DROP INDEX index_version_downloads_date", &[]));
+            try!(tx.execute("ALTER TABLE version_downloads
+                             ALTER COLUMN date TYPE DATE", &[]));
+            try!(tx.execute("CREATE INDEX index_version_downloads_date
+                             ON version_downloads (date)", &[]));
+            try!(tx.execute("ALTER TABLE version_downloads
+                             ADD CONSTRAINT version_downloads_unique
+                             UNIQUE (version_id, date)", &[]));
+            Ok(())
+        }, |tx| {
+            try!(tx.execute("ALTER TABLE version_downloads
+                             DROP CONSTRAINT version_downloads_unique", &[]));
+            try!(tx.execute("DROP INDEX index_version_downloads_date", &[]));
+            try!(tx.execute("ALTER TABLE version_downloads
+                             ALTER COLUMN date TYPE TIMESTAMP", &[]));
+            try!(tx.execute("CREATE INDEX index_version_downloads_date
+                             ON version_downloads (date(date))", &[]));
+            Ok(())
+        }),
+        Migration::run(20151217152846,
+                       "ALTER TABLE teams ALTER COLUMN github_id DROP NOT NULL",
+                       "ALTER TABLE teams ALTER COLUMN github_id SET NOT NULL"),
+        Migration::add_table(20151217152847, "team_members", "
+            team_id         INTEGER NOT NULL REFERENCES teams (id)
+                            ON DELETE CASCADE,
+            user_id         INTEGER NOT NULL REFERENCES users (id)
+                            ON DELETE CASCADE,
+            PRIMARY KEY (team_id, user_id)
+        "),
+        Migration::add_table(20151218091533, "team_memberships", "
+            team_id             INTEGER NOT NULL REFERENCES teams (id)
+                                ON DELETE CASCADE,
+            user_id             INTEGER NOT NULL REFERENCES users (id)
+                                ON DELETE CASCADE,
+            is_member           BOOLEAN NOT NULL,
+            checked_at          TIMESTAMP NOT NULL,
+            refresh_requested   BOOLEAN NOT NULL DEFAULT FALSE,
+            PRIMARY KEY (team_id, user_id)
+        "),
+        Migration::add_table(20151218142207, "crate_owner_invitations", "
+            invited_user_id     INTEGER NOT NULL REFERENCES users (id)
+                                ON DELETE CASCADE,
+            invited_by_user_id  INTEGER NOT NULL REFERENCES users (id)
+                                ON DELETE CASCADE,
+            crate_id            INTEGER NOT NULL REFERENCES crates (id)
+                                ON DELETE CASCADE,
+            created_at          TIMESTAMP NOT NULL,
+            PRIMARY KEY (invited_user_id, crate_id)
+        "),
+        Migration::run(20151219101514,
+                       "CREATE INDEX index_crates_name_prefix \
+                        ON crates (canon_crate_name(name) text_pattern_ops)",
+                       "DROP INDEX index_crates_name_prefix"),
+        Migration::add_table(20151221094502, "crate_renames", "
+            id              SERIAL PRIMARY KEY,
+            crate_id        INTEGER NOT NULL REFERENCES crates (id)
+                            ON DELETE CASCADE,
+            name            VARCHAR NOT NULL,
+            created_at      TIMESTAMP NOT NULL DEFAULT now()
+        "),
+        index(20151221094503, "crate_renames", "crate_id"),
+        Migration::new(20151221094504, |tx| {
+            // Every rename is recorded in `crate_renames` from now on, which
+            // leaves `crate_aliases` with a single row per reserved name.
+            try!(tx.execute("INSERT INTO crate_renames (crate_id, name, created_at)
+                             SELECT crate_id, name, created_at
+                               FROM crate_aliases ORDER BY id ASC", &[]));
+            try!(tx.execute("DELETE FROM crate_aliases a
+                              USING crate_aliases b
+                              WHERE canon_crate_name(a.name) =
+                                    canon_crate_name(b.name)
+                                AND a.id > b.id", &[]));
+            try!(tx.execute("DROP INDEX index_crate_aliases_name", &[]));
+            try!(tx.execute("CREATE UNIQUE INDEX index_crate_aliases_name \
+                             ON crate_aliases (canon_crate_name(name))", &[]));
+            Ok(())
+        }, |tx| {
+            try!(tx.execute("DROP INDEX index_crate_aliases_name", &[]));
+            try!(tx.execute("CREATE INDEX index_crate_aliases_name \
+                             ON crate_aliases (canon_crate_name(name))", &[]));
+            try!(tx.execute("DELETE FROM crate_aliases", &[]));
+            try!(tx.execute("INSERT INTO crate_aliases (crate_id, name, created_at)
+                             SELECT crate_id, name, created_at
+                               FROM crate_renames ORDER BY id ASC", &[]));
+            Ok(())
+        }),
+        Migration::add_column(20151221094505, "dependencies", "name",
+                              "VARCHAR"),
+        Migration::new(20151221094506, |tx| {
+            // The name each dependency was published with is the one its
+            // crate had when the depending version was published.
+            try!(tx.execute("UPDATE dependencies
+                                SET name = COALESCE(
+                                    (SELECT crate_renames.name
+                                       FROM crate_renames, versions
+                                      WHERE versions.id = dependencies.version_id
+                                        AND crate_renames.crate_id =
+                                            dependencies.crate_id
+                                        AND crate_renames.created_at >
+                                            versions.created_at
+                                      ORDER BY crate_renames.created_at ASC,
+                                               crate_renames.id ASC
+                                      LIMIT 1),
+                                    (SELECT crates.name FROM crates
+                                      WHERE crates.id = dependencies.crate_id))",
+                            &[]));
+            try!(tx.execute("ALTER TABLE dependencies
+                             ALTER COLUMN name SET NOT NULL", &[]));
+            Ok(())
+        }, |tx| {
+            try!(tx.execute("ALTER TABLE dependencies
+                             ALTER COLUMN name DROP NOT NULL", &[]));
+            Ok(())
+        }),
     ];
     // NOTE: Generate a new id via `date +"%Y%m%d%H%M%S"`
 
@@ -549,3 +761,25 @@ fn fix_duplicate_crate_owners(tx: &postgres::Transaction) -> postgres::Result<()
     }
     Ok(())
 }
+
+// DO NOT UPDATE OR USE FOR NEW MIGRATIONS
+fn digest_api_tokens(tx: &postgres::Transaction) -> postgres::Result<()> {
+    use openssl::crypto::hash::{hash, Type};
+    use rustc_serialize::hex::ToHex;
+
+    for table in ["users", "api_tokens"].iter() {
+        let column = if *table == "users" {"api_token"} else {"token"};
+        let select = format!("SELECT id, {} FROM {}", column, table);
+        let update = format!("UPDATE {} SET {} = $1 WHERE id = $2", table,
+                             column);
+        let stmt = try!(tx.prepare(&select));
+        let update = try!(tx.prepare(&update));
+        for row in try!(stmt.query(&[])).iter() {
+            let id: i32 = row.get(0);
+            let token: String = row.get(1);
+            let digest = hash(Type::SHA256, token.as_bytes()).to_hex();
+            try!(update.execute(&[&digest, &id]));
+        }
+    }
+    Ok(())
+}
@@ -8,11 +8,9 @@
 
 extern crate cargo_registry;
 extern crate postgres;
-extern crate time;
 extern crate rand;
 
 use std::env;
-use time::Duration;
 use rand::{StdRng, Rng};
 
 fn main() {
@@ -38,17 +36,15 @@ fn update(tx: &postgres::Transaction) -> postgres::Result<()> {
         arg.parse::<i32>().ok()
     });
     for id in ids {
-        let now = time::now_utc().to_timespec();
         let mut rng = StdRng::new().unwrap();
         let mut dls = rng.gen_range(5000i32, 10000);
 
-        for day in 0..90 {
-            let moment = now + Duration::days(-day);
+        for day in 0..90i32 {
             dls += rng.gen_range(-100, 100);
             try!(tx.execute("INSERT INTO version_downloads \
                               (version_id, downloads, counted, date, processed) \
-                              VALUES ($1, $2, 0, $3, false)",
-                            &[&id, &dls, &moment]));
+                              VALUES ($1, $2, 0, current_date - $3::int, false)",
+                            &[&id, &dls, &day]));
         }
     }
     Ok(())
@@ -0,0 +1,262 @@
+// Regenerates every file in the registry index from the database, reporting
+// how the files which are checked out differ from the regenerated ones.
+//
+// Nothing is changed unless `--write` is passed, in which case the differing
+// files in the checkout are overwritten. Only files at the path of a crate's
+// index file are ever touched, anything else in the checkout is left alone.
+//
+// Passing `--squash` also writes the files, and then commits the regenerated
+// index as a single commit with no history and force pushes it. Make sure the
+// background worker isn't running when doing so, otherwise the changes it
+// pushes may be lost.
+//
+// Usage:
+//      cargo run --bin rebuild-index [--write] [--squash]
+
+#![deny(warnings)]
+
+extern crate cargo_registry;
+extern crate git2;
+extern crate postgres;
+extern crate rustc_serialize;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::json::{self, Json};
+
+use cargo_registry::{Crate, Model, Version};
+use cargo_registry::git;
+
+fn main() {
+    let mut write = false;
+    let mut squash = false;
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "--write" => write = true,
+            "--squash" => { write = true; squash = true; }
+            _ => panic!("unknown argument `{}`, usage: \
+                         rebuild-index [--write] [--squash]", arg),
+        }
+    }
+    let checkout = PathBuf::from(env("GIT_REPO_CHECKOUT"));
+    let conn = postgres::Connection::connect(&env("DATABASE_URL")[..],
+                                             &postgres::SslMode::None).unwrap();
+    let tx = conn.transaction().unwrap();
+
+    let current = current_index(&checkout);
+    let expected = expected_index(&tx, &checkout, &current);
+    let changed = update(&checkout, &expected, &current, write);
+    if write {
+        println!("{} of {} index files changed", changed, expected.len());
+    } else {
+        println!("{} of {} index files differ, pass `--write` to update them",
+                 changed, expected.len());
+    }
+
+    if squash {
+        commit_squashed(&checkout);
+    }
+}
+
+fn env(s: &str) -> String {
+    match env::var(s).ok() {
+        Some(s) => s,
+        None => panic!("must have `{}` defined", s),
+    }
+}
+
+/// Builds the contents of every index file from the database, keyed by path.
+fn expected_index(tx: &postgres::Transaction, checkout: &Path,
+                  current: &BTreeMap<PathBuf, String>)
+                  -> BTreeMap<PathBuf, Vec<git::Crate>> {
+    let mut index = BTreeMap::new();
+    let stmt = tx.prepare("SELECT * FROM crates ORDER BY id ASC").unwrap();
+    for row in stmt.query(&[]).unwrap().iter() {
+        let krate: Crate = Model::from_row(&row);
+        let aliases = krate.aliases(tx).unwrap();
+
+        // Each version is listed under the name it was published under, so
+        // the versions of a renamed crate are split across several files.
+        // Every name the crate had has a file, even if it's empty.
+        let mut files = BTreeMap::new();
+        let names = aliases.iter().map(|&(ref name, _)| name)
+                           .chain(Some(&krate.name));
+        for name in names {
+            files.insert(git::index_file(checkout, name), Vec::new());
+        }
+        for version in versions(tx, krate.id) {
+            let name = krate.published_name(&aliases, &version).to_string();
+            files.entry(git::index_file(checkout, &name))
+                 .or_insert(Vec::new()).push((name, version));
+        }
+        for (path, versions) in files {
+            let lines = lines(tx, current.get(&path), &versions);
+            index.insert(path, lines);
+        }
+    }
+    index
+}
+
+/// Returns the versions of a crate in the order they were published.
+fn versions(tx: &postgres::Transaction, crate_id: i32) -> Vec<Version> {
+    let stmt = tx.prepare("SELECT * FROM versions WHERE crate_id = $1
+                           ORDER BY id ASC").unwrap();
+    let rows = stmt.query(&[&crate_id]).unwrap();
+    let versions = rows.iter().map(|r| -> Version { Model::from_row(&r) })
+                       .collect();
+    versions
+}
+
+/// Encodes the lines of one index file, given its `current` contents and the
+/// versions it lists along with the name each was published under.
+fn lines(tx: &postgres::Transaction, current: Option<&String>,
+         versions: &[(String, Version)]) -> Vec<git::Crate> {
+    // Versions published before we started recording checksums don't have
+    // one in the database, so keep whatever the index currently says.
+    let mut checksums = HashMap::new();
+    if let Some(contents) = current {
+        for line in contents.lines().filter_map(|l| Json::from_str(l).ok()) {
+            let vers = line.find("vers").and_then(|v| v.as_string());
+            let cksum = line.find("cksum").and_then(|c| c.as_string());
+            if let (Some(vers), Some(cksum)) = (vers, cksum) {
+                checksums.insert(vers.to_string(), cksum.to_string());
+            }
+        }
+    }
+
+    versions.iter().map(|&(ref name, ref version)| {
+        let mut line = version.git_encode(tx, name).unwrap();
+        if line.cksum.is_empty() {
+            match checksums.get(&line.vers) {
+                Some(cksum) => line.cksum = cksum.clone(),
+                None => println!("warning: no checksum for `{}#{}`",
+                                 name, line.vers),
+            }
+        }
+        line
+    }).collect()
+}
+
+/// Reads every index file in the checkout, keyed by path. Files which aren't
+/// where the index file of a crate of the same name would be, such as
+/// `config.json`, are skipped.
+fn current_index(checkout: &Path) -> BTreeMap<PathBuf, String> {
+    let mut index = BTreeMap::new();
+    for path in files(checkout) {
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if !Crate::valid_name(&name) || git::index_file(checkout, &name) != path {
+            continue
+        }
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        index.insert(path, contents);
+    }
+    index
+}
+
+/// Returns all files in `dir` and its subdirectories, skipping hidden ones
+/// such as `.git`.
+fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_name().to_string_lossy().starts_with(".") { continue }
+        if entry.file_type().unwrap().is_dir() {
+            ret.extend(files(&entry.path()));
+        } else {
+            ret.push(entry.path());
+        }
+    }
+    ret
+}
+
+/// Prints the differences between each index file and what's in the
+/// database, writing out the differing files if `write` is set, and returns
+/// how many files differ.
+fn update(checkout: &Path, expected: &BTreeMap<PathBuf, Vec<git::Crate>>,
+          current: &BTreeMap<PathBuf, String>, write: bool) -> usize {
+    let paths = expected.keys().chain(current.keys()).collect::<BTreeSet<_>>();
+    let mut changed = 0;
+    for path in paths {
+        let name = relative(checkout, path);
+        let new = expected.get(path).map(|lines| {
+            lines.iter().map(|l| json::encode(l).unwrap()).collect::<Vec<_>>()
+        });
+        let old = current.get(path).map(|contents| {
+            contents.lines().map(|l| l.to_string()).collect::<Vec<_>>()
+        });
+        match (new, old) {
+            (Some(new), None) => {
+                println!("added {}", name.display());
+                if write { write_file(path, &new) }
+            }
+            (None, Some(..)) => {
+                println!("removed {}", name.display());
+                if write { fs::remove_file(path).unwrap() }
+            }
+            (Some(new), Some(old)) => {
+                let parse = |lines: &[String]| {
+                    lines.iter().map(|l| Json::from_str(l).ok())
+                         .collect::<Vec<_>>()
+                };
+                let (new_json, old_json) = (parse(&new[..]), parse(&old[..]));
+                if new_json == old_json { continue }
+                println!("changed {}", name.display());
+                for (line, json) in old.iter().zip(old_json.iter()) {
+                    if !new_json.contains(json) { println!("  - {}", line) }
+                }
+                for (line, json) in new.iter().zip(new_json.iter()) {
+                    if !old_json.contains(json) { println!("  + {}", line) }
+                }
+                if write { write_file(path, &new) }
+            }
+            (None, None) => continue,
+        }
+        changed += 1;
+    }
+    changed
+}
+
+fn write_file(path: &Path, lines: &[String]) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let mut f = File::create(path).unwrap();
+    for line in lines {
+        f.write_all(line.as_bytes()).unwrap();
+        f.write_all(b"\n").unwrap();
+    }
+}
+
+fn relative(base: &Path, path: &Path) -> PathBuf {
+    let mut base = base.iter();
+    path.iter().skip_while(|s| Some(*s) == base.next()).collect()
+}
+
+/// Replaces the history of the index with a single commit of the checkout.
+fn commit_squashed(checkout: &Path) {
+    let repo = git2::Repository::open(checkout).unwrap();
+    let mut index = repo.index().unwrap();
+    index.clear().unwrap();
+    for path in files(checkout) {
+        index.add_path(&relative(checkout, &path)).unwrap();
+    }
+    index.write().unwrap();
+    let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+    let sig = repo.signature().unwrap();
+    let id = repo.commit(None, &sig, &sig,
+                         "Collapse index history, regenerated from the \
+                          database", &tree, &[]).unwrap();
+    repo.reference("refs/heads/master", id, true,
+                   "rebuild-index: squash").unwrap();
+
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(git::credentials);
+    let mut opts = git2::PushOptions::new();
+    opts.remote_callbacks(callbacks);
+    let mut origin = repo.find_remote("origin").unwrap();
+    origin.push(&["+refs/heads/master"], Some(&mut opts)).unwrap();
+    println!("pushed squashed index as {}", id);
+}
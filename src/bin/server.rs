@@ -5,13 +5,20 @@ extern crate conduit_middleware;
 extern crate civet;
 extern crate git2;
 extern crate env_logger;
+#[macro_use]
+extern crate log;
 
+use cargo_registry::background::Job;
+use cargo_registry::rate_limit::RateLimit;
 use civet::Server;
+use std::collections::HashMap;
 use std::env;
 use std::fs::{self, File};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
 
 fn main() {
     env_logger::init().unwrap();
@@ -42,22 +49,61 @@ fn main() {
     } else {
         cargo_registry::Env::Development
     };
+    // Where cargo is told to find the API and to download crates from, which
+    // may be a custom hostname for a private registry.
+    let api_url = env::var("API_URL").unwrap_or_else(|_| {
+        if heroku {"https://crates.io"} else {"http://localhost:8888"}.to_string()
+    });
+    let dl_url = env::var("DL_URL").unwrap_or(format!("{}/api/v1/crates",
+                                                      api_url));
+    // Publishing is limited to 10 crates a minute by default, and other API
+    // requests aren't limited unless configured.
+    let mut rate_limits = HashMap::new();
+    let publish = env::var("PUBLISH_RATE_LIMIT").unwrap_or("10/60".to_string());
+    rate_limits.insert("publish".to_string(),
+                       RateLimit::parse(&publish)
+                                 .expect("invalid PUBLISH_RATE_LIMIT"));
+    if let Ok(api) = env::var("API_RATE_LIMIT") {
+        rate_limits.insert("api".to_string(),
+                           RateLimit::parse(&api).expect("invalid API_RATE_LIMIT"));
+    }
+    // Github team memberships are checked again after an hour by default.
+    let team_membership_ttl = env::var("TEAM_MEMBERSHIP_TTL").ok().map(|s| {
+        s.parse().expect("invalid TEAM_MEMBERSHIP_TTL")
+    }).unwrap_or(60 * 60);
+
     let config = cargo_registry::Config {
-        s3_bucket: env("S3_BUCKET"),
-        s3_access_key: env("S3_ACCESS_KEY"),
-        s3_secret_key: env("S3_SECRET_KEY"),
-        s3_region: env::var("S3_REGION").ok(),
+        storage: cargo_registry::storage::from_env(),
         s3_proxy: None,
         session_key: env("SESSION_KEY"),
+        fingerprint_secret: env("FINGERPRINT_SECRET"),
         git_repo_checkout: checkout,
         gh_client_id: env("GH_CLIENT_ID"),
         gh_client_secret: env("GH_CLIENT_SECRET"),
         db_url: env("DATABASE_URL"),
         env: cargo_env,
         max_upload_size: 10 * 1024 * 1024,
+        api_url: api_url,
+        dl_url: dl_url,
+        auth_required: env::var("AUTH_REQUIRED").is_ok(),
+        rate_limits: rate_limits,
+        team_membership_ttl: team_membership_ttl,
+        allow_stale_team_memberships:
+            env::var("ALLOW_STALE_TEAM_MEMBERSHIPS").is_ok(),
     };
     let app = cargo_registry::App::new(&config);
-    let app = cargo_registry::middleware(Arc::new(app));
+
+    // Make sure the index's config.json points cargo at this server
+    let contents = cargo_registry::git::config_json(&config);
+    if !cargo_registry::git::config_is_current(&config.git_repo_checkout,
+                                               &contents) {
+        let conn = app.database.get().unwrap();
+        Job::UpdateConfig(contents).enqueue(&*conn).unwrap();
+        info!("config.json is out of date, queued an update");
+    }
+    let app = Arc::new(app);
+    spawn_membership_refresher(app.clone());
+    let app = cargo_registry::middleware(app);
 
     let port = if heroku {
         8888
@@ -78,6 +124,21 @@ fn main() {
     rx.recv().unwrap();
 }
 
+// Github team memberships which were used recently get checked again in the
+// background, so that publishing rarely has to wait on Github.
+fn spawn_membership_refresher(app: Arc<cargo_registry::App>) {
+    thread::spawn(move || {
+        loop {
+            match cargo_registry::owner::refresh_memberships(&app) {
+                Ok(0) => {}
+                Ok(n) => info!("refreshed {} team memberships", n),
+                Err(e) => error!("failed to refresh team memberships: {}", e),
+            }
+            thread::sleep(Duration::new(60, 0));
+        }
+    });
+}
+
 fn env(s: &str) -> String {
     match env::var(s).ok() {
         Some(s) => s,
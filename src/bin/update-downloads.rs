@@ -1,17 +1,27 @@
+// Adds the downloads recorded in `version_downloads` since the last run to
+// the totals of each version, each crate, each crate per day and the site.
+//
+// How much of each row of `version_downloads` has already been added to the
+// totals is recorded in its `counted` and `unique_downloads` columns, which
+// act as a high-water mark: every run adds only what's above them, so running
+// again without new downloads changes nothing. Runs hold an advisory lock so
+// that overlapping runs wait for each other rather than counting the same
+// downloads twice, and each run happens in a single transaction.
+//
+// Usage:
+//      cargo run --bin update-downloads [daemon <seconds between runs>]
+
 #![deny(warnings)]
 
 extern crate cargo_registry;
 extern crate postgres;
 extern crate semver;
-extern crate time;
 
 use std::env;
-use std::collections::HashMap;
 use std::time::Duration;
 
-use cargo_registry::{VersionDownload, Version, Model};
-
-static LIMIT: i64 = 1000;
+/// The key of the advisory lock held while updating.
+const LOCK_KEY: i64 = 0x75706461_74652d64; // "update-d"
 
 #[allow(dead_code)] // dead in tests
 fn main() {
@@ -39,98 +49,113 @@ fn env(s: &str) -> String {
 }
 
 fn update(conn: &postgres::GenericConnection) -> postgres::Result<()> {
-    let mut max = 0;
-    loop {
-        // FIXME(rust-lang/rust#27401): weird declaration to make sure this
-        // variable gets dropped.
-        let tx; tx = try!(conn.transaction());
-        {
-            let stmt = try!(tx.prepare("SELECT * FROM version_downloads \
-                                        WHERE processed = FALSE AND id > $1
-                                        ORDER BY id ASC
-                                        LIMIT $2"));
-            let mut rows = try!(stmt.query(&[&max, &LIMIT]));
-            match try!(collect(&tx, &mut rows)) {
-                None => break,
-                Some(m) => max = m,
-            }
-        }
-        tx.set_commit();
-        try!(tx.finish());
-    }
-    Ok(())
-}
-
-fn collect(tx: &postgres::Transaction,
-           rows: &mut postgres::rows::Rows) -> postgres::Result<Option<i32>> {
-    use time::Duration;
+    // FIXME(rust-lang/rust#27401): weird declaration to make sure this
+    // variable gets dropped.
+    let tx; tx = try!(conn.transaction());
+    try!(tx.execute("SELECT pg_advisory_xact_lock($1)", &[&LOCK_KEY]));
 
-    // Anything older than 24 hours ago will be frozen and will not be queried
-    // against again.
-    let cutoff = time::now_utc().to_timespec();
-    let cutoff = cutoff + Duration::days(1);
+    // Work out what hasn't been counted yet. Downloads which happen while
+    // we're updating are left for the next run, as only these amounts are
+    // added to `counted`.
+    let rows = try!(tx.execute("\
+        CREATE TEMPORARY TABLE download_deltas AS
+        SELECT version_downloads.id, version_downloads.version_id,
+               versions.crate_id, version_downloads.date,
+               version_downloads.downloads - version_downloads.counted
+                   AS downloads,
+               (SELECT COUNT(*) FROM version_download_clients
+                 WHERE version_download_clients.version_id =
+                           version_downloads.version_id
+                   AND version_download_clients.date =
+                           version_downloads.date)::int
+                   - version_downloads.unique_downloads AS unique_downloads
+          FROM version_downloads
+         INNER JOIN versions ON versions.id = version_downloads.version_id
+         WHERE version_downloads.processed = FALSE", &[]));
+    println!("updating {} rows of downloads", rows);
 
-    let mut map = HashMap::new();
-    for row in rows.iter() {
-        let download: VersionDownload = Model::from_row(&row);
-        assert!(map.insert(download.id, download).is_none());
-    }
-    println!("updating {} versions (cutoff {})", map.len(),
-             time::at(cutoff).rfc822());
-    if map.len() == 0 { return Ok(None) }
-
-    let mut max = 0;
-    let mut total = 0;
-    for (id, download) in map.iter() {
-        if *id > max { max = *id; }
-        if download.date > cutoff && download.counted == download.downloads {
-            continue
-        }
-        let amt = download.downloads - download.counted;
-
-        let crate_id = Version::find(tx, download.version_id).unwrap().crate_id;
-
-        // Update the total number of version downloads
-        try!(tx.execute("UPDATE versions
-                         SET downloads = downloads + $1
-                         WHERE id = $2",
-                        &[&amt, &download.version_id]));
-        // Update the total number of crate downloads
-        try!(tx.execute("UPDATE crates SET downloads = downloads + $1
-                         WHERE id = $2", &[&amt, &crate_id]));
-
-        // Update the total number of crate downloads for today
-        let cnt = try!(tx.execute("UPDATE crate_downloads
-                                   SET downloads = downloads + $2
-                                   WHERE crate_id = $1 AND date = date($3)",
-                                  &[&crate_id, &amt, &download.date]));
-        if cnt == 0 {
-            try!(tx.execute("INSERT INTO crate_downloads
-                             (crate_id, downloads, date)
-                             VALUES ($1, $2, $3)",
-                            &[&crate_id, &amt, &download.date]));
-        }
+    // Only today's downloads can still change, so older rows are done with
+    // once they've been counted.
+    try!(tx.execute("UPDATE version_downloads
+                        SET counted = counted + d.downloads,
+                            unique_downloads =
+                                version_downloads.unique_downloads +
+                                d.unique_downloads,
+                            processed = d.date < current_date
+                       FROM download_deltas d
+                      WHERE version_downloads.id = d.id", &[]));
+    // The clients are only needed to count unique downloads, so they can go
+    // once their day has been processed.
+    try!(tx.execute("DELETE FROM version_download_clients
+                      USING version_downloads
+                      WHERE version_downloads.version_id =
+                                version_download_clients.version_id
+                        AND version_downloads.date =
+                                version_download_clients.date
+                        AND version_downloads.processed", &[]));
+    try!(tx.execute("UPDATE versions
+                        SET downloads = versions.downloads + d.downloads,
+                            unique_downloads = versions.unique_downloads +
+                                               d.unique_downloads
+                       FROM (SELECT version_id,
+                                    SUM(downloads)::int AS downloads,
+                                    SUM(unique_downloads)::int
+                                        AS unique_downloads
+                               FROM download_deltas
+                              GROUP BY version_id) d
+                      WHERE versions.id = d.version_id
+                        AND (d.downloads <> 0 OR d.unique_downloads <> 0)",
+                    &[]));
+    try!(tx.execute("UPDATE crates
+                        SET downloads = crates.downloads + d.downloads,
+                            unique_downloads = crates.unique_downloads +
+                                               d.unique_downloads
+                       FROM (SELECT crate_id,
+                                    SUM(downloads)::int AS downloads,
+                                    SUM(unique_downloads)::int
+                                        AS unique_downloads
+                               FROM download_deltas
+                              GROUP BY crate_id) d
+                      WHERE crates.id = d.crate_id
+                        AND (d.downloads <> 0 OR d.unique_downloads <> 0)",
+                    &[]));
+    try!(tx.execute("UPDATE crate_downloads
+                        SET downloads = crate_downloads.downloads + d.downloads,
+                            unique_downloads = crate_downloads.unique_downloads
+                                               + d.unique_downloads
+                       FROM (SELECT crate_id, date,
+                                    SUM(downloads)::int AS downloads,
+                                    SUM(unique_downloads)::int
+                                        AS unique_downloads
+                               FROM download_deltas
+                              GROUP BY crate_id, date) d
+                      WHERE crate_downloads.crate_id = d.crate_id
+                        AND date(crate_downloads.date) = d.date", &[]));
+    try!(tx.execute("INSERT INTO crate_downloads
+                            (crate_id, downloads, unique_downloads, date)
+                     SELECT crate_id, SUM(downloads)::int,
+                            SUM(unique_downloads)::int, date
+                       FROM download_deltas d
+                      GROUP BY crate_id, date
+                     HAVING NOT EXISTS (SELECT 1 FROM crate_downloads
+                                         WHERE crate_downloads.crate_id =
+                                                   d.crate_id
+                                           AND date(crate_downloads.date) =
+                                                   d.date)", &[]));
+    try!(tx.execute("UPDATE metadata
+                        SET total_downloads = total_downloads +
+                            (SELECT COALESCE(SUM(downloads), 0)
+                               FROM download_deltas)", &[]));
 
-        // Flag this row as having been processed if we're passed the cutoff,
-        // and unconditionally increment the number of counted downloads.
-        try!(tx.execute("UPDATE version_downloads
-                         SET processed = $2, counted = counted + $3
-                         WHERE id = $1",
-                        &[id, &(download.date < cutoff), &amt]));
-        total += amt as i64;
-    }
-
-    // After everything else is done, update the global counter of total
-    // downloads.
-    try!(tx.execute("UPDATE metadata SET total_downloads = total_downloads + $1",
-                    &[&total]));
-
-    Ok(Some(max))
+    try!(tx.execute("DROP TABLE download_deltas", &[]));
+    tx.set_commit();
+    tx.finish()
 }
 
 #[cfg(test)]
 mod test {
     use std::collections::HashMap;
+    use std::thread;
 
     use postgres;
     use semver;
@@ -231,4 +256,187 @@ mod test {
         ::update(&tx).unwrap();
         assert_eq!(Version::find(&tx, version.id).unwrap().downloads, 2);
     }
+
+    #[test]
+    fn unique_downloads() {
+        let conn = conn();
+        let tx = conn.transaction().unwrap();
+        let user = user(&tx);
+        let krate = Crate::find_or_insert(&tx, "foo", user.id, &None,
+                                          &None, &None, &None, &[], &None,
+                                          &None, &None).unwrap();
+        let version = Version::insert(&tx, krate.id,
+                                      &semver::Version::parse("1.0.0").unwrap(),
+                                      &HashMap::new(), &[]).unwrap();
+        tx.execute("INSERT INTO version_downloads \
+                    (version_id, downloads, counted, date, processed)
+                    VALUES ($1, 3, 0, current_date, false)",
+                   &[&version.id]).unwrap();
+        let client = |fingerprint: &str, downloads: i32| {
+            tx.execute("INSERT INTO version_download_clients \
+                        (version_id, date, fingerprint, client, downloads)
+                        VALUES ($1, current_date, $2, 'cargo', $3)",
+                       &[&version.id, &fingerprint, &downloads]).unwrap();
+        };
+        client("a", 2);
+        client("b", 1);
+        ::update(&tx).unwrap();
+        let version = Version::find(&tx, version.id).unwrap();
+        assert_eq!(version.downloads, 3);
+        assert_eq!(version.unique_downloads, 2);
+        assert_eq!(Crate::find(&tx, krate.id).unwrap().unique_downloads, 2);
+
+        // Only the newly seen client is added the next time around
+        client("c", 1);
+        tx.execute("UPDATE version_downloads SET downloads = 4
+                    WHERE version_id = $1", &[&version.id]).unwrap();
+        ::update(&tx).unwrap();
+        let version = Version::find(&tx, version.id).unwrap();
+        assert_eq!(version.downloads, 4);
+        assert_eq!(version.unique_downloads, 3);
+        assert_eq!(Crate::find(&tx, krate.id).unwrap().unique_downloads, 3);
+    }
+
+    fn total_downloads(conn: &postgres::GenericConnection) -> i64 {
+        let stmt = conn.prepare("SELECT total_downloads FROM metadata").unwrap();
+        let rows = stmt.query(&[]).unwrap();
+        rows.iter().next().unwrap().get(0)
+    }
+
+    #[test]
+    fn prunes_processed_clients() {
+        let conn = conn();
+        let tx = conn.transaction().unwrap();
+        let user = user(&tx);
+        let krate = Crate::find_or_insert(&tx, "foo", user.id, &None,
+                                          &None, &None, &None, &[], &None,
+                                          &None, &None).unwrap();
+        let version = Version::insert(&tx, krate.id,
+                                      &semver::Version::parse("1.0.0").unwrap(),
+                                      &HashMap::new(), &[]).unwrap();
+        tx.execute("INSERT INTO version_downloads \
+                    (version_id, downloads, counted, date, processed)
+                    VALUES ($1, 1, 0, current_date - interval '1 day', false),
+                           ($1, 1, 0, current_date, false)",
+                   &[&version.id]).unwrap();
+        tx.execute("INSERT INTO version_download_clients \
+                    (version_id, date, fingerprint, client, downloads)
+                    VALUES ($1, current_date - 1, 'a', 'cargo', 1),
+                           ($1, current_date, 'a', 'cargo', 1)",
+                   &[&version.id]).unwrap();
+        ::update(&tx).unwrap();
+        assert_eq!(Version::find(&tx, version.id).unwrap().unique_downloads, 2);
+
+        // Only today's clients are kept, as they may still download again
+        let stmt = tx.prepare("SELECT COUNT(*) FROM version_download_clients
+                               WHERE version_id = $1
+                                 AND date = current_date").unwrap();
+        let today: i64 = stmt.query(&[&version.id]).unwrap().iter()
+                             .next().unwrap().get(0);
+        let stmt = tx.prepare("SELECT COUNT(*) FROM version_download_clients
+                               WHERE version_id = $1").unwrap();
+        let all: i64 = stmt.query(&[&version.id]).unwrap().iter()
+                           .next().unwrap().get(0);
+        assert_eq!((today, all), (1, 1));
+    }
+
+    #[test]
+    fn repeated_runs() {
+        let conn = conn();
+        let tx = conn.transaction().unwrap();
+        let user = user(&tx);
+        let krate = Crate::find_or_insert(&tx, "foo", user.id, &None,
+                                          &None, &None, &None, &[], &None,
+                                          &None, &None).unwrap();
+        let version = Version::insert(&tx, krate.id,
+                                      &semver::Version::parse("1.0.0").unwrap(),
+                                      &HashMap::new(), &[]).unwrap();
+        tx.execute("INSERT INTO version_downloads \
+                    (version_id, downloads, counted, date, processed)
+                    VALUES ($1, 3, 0, current_date - interval '1 day', false),
+                           ($1, 2, 0, current_date, false)",
+                   &[&version.id]).unwrap();
+        let total = total_downloads(&tx);
+        for _ in 0..3 {
+            ::update(&tx).unwrap();
+            assert_eq!(Version::find(&tx, version.id).unwrap().downloads, 5);
+            assert_eq!(Crate::find(&tx, krate.id).unwrap().downloads, 5);
+            assert_eq!(total_downloads(&tx), total + 5);
+        }
+    }
+
+    /// Deletes the data `concurrent_runs` committed when dropped, so that it's
+    /// cleaned up even if the test fails.
+    struct Cleanup { krate: i32, version: i32, user: i32 }
+
+    impl Drop for Cleanup {
+        fn drop(&mut self) {
+            let result = conn().batch_execute(&format!("
+                UPDATE metadata SET total_downloads = total_downloads -
+                    (SELECT COALESCE(SUM(counted), 0) FROM version_downloads
+                      WHERE version_id = {version});
+                DELETE FROM crate_downloads WHERE crate_id = {krate};
+                DELETE FROM version_downloads WHERE version_id = {version};
+                DELETE FROM versions WHERE id = {version};
+                DELETE FROM crate_owners WHERE crate_id = {krate};
+                DELETE FROM crates WHERE id = {krate};
+                DELETE FROM users WHERE id = {user};
+            ", krate = self.krate, version = self.version, user = self.user));
+            // Don't turn a failing test into an abort
+            if !thread::panicking() {
+                result.unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn concurrent_runs() {
+        // Each run commits its own transaction, so this test's data has to be
+        // committed too, and is cleaned up afterwards.
+        let conn = conn();
+        let (krate, version) = {
+            let tx = conn.transaction().unwrap();
+            let user = User::find_or_insert(&tx, "concurrent-downloads", None,
+                                            None, None, "access_token",
+                                            "concurrent-downloads").unwrap();
+            let krate = Crate::find_or_insert(&tx, "concurrent-downloads",
+                                              user.id, &None, &None, &None,
+                                              &None, &[], &None, &None,
+                                              &None).unwrap();
+            let version = Version::insert(&tx, krate.id,
+                                          &semver::Version::parse("1.0.0").unwrap(),
+                                          &HashMap::new(), &[]).unwrap();
+            tx.execute("INSERT INTO version_downloads \
+                        (version_id, downloads, counted, date, processed)
+                        VALUES ($1, 7, 0, current_date, false)",
+                       &[&version.id]).unwrap();
+            tx.set_commit();
+            tx.finish().unwrap();
+            (krate, version)
+        };
+        let _cleanup = Cleanup {
+            krate: krate.id,
+            version: version.id,
+            user: krate.user_id,
+        };
+
+        let threads = (0..4).map(|_| {
+            thread::spawn(|| ::update(&conn()).unwrap())
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let version_downloads = Version::find(&conn, version.id).unwrap().downloads;
+        let crate_downloads: i32 = {
+            let stmt = conn.prepare("SELECT downloads FROM crate_downloads
+                                     WHERE crate_id = $1").unwrap();
+            let rows = stmt.query(&[&krate.id]).unwrap();
+            rows.iter().map(|r| r.get::<_, i32>(0)).fold(0, |a, b| a + b)
+        };
+        let krate_downloads = Crate::find(&conn, krate.id).unwrap().downloads;
+        assert_eq!(version_downloads, 7);
+        assert_eq!(crate_downloads, 7);
+        assert_eq!(krate_downloads, 7);
+    }
 }
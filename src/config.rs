@@ -1,19 +1,41 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 
+use rate_limit::RateLimit;
+use storage::Location;
+
 #[derive(Clone)]
 pub struct Config {
-    pub s3_bucket: String,
-    pub s3_region: Option<String>,
-    pub s3_access_key: String,
-    pub s3_secret_key: String,
+    /// Where uploaded `.crate` files are kept.
+    pub storage: Location,
     pub s3_proxy: Option<String>,
     pub session_key: String,
+    /// The secret hashed into the fingerprints of clients downloading crates.
+    pub fingerprint_secret: String,
     pub git_repo_checkout: PathBuf,
     pub gh_client_id: String,
     pub gh_client_secret: String,
     pub db_url: String,
     pub env: ::Env,
     pub max_upload_size: u64,
+    /// The public URL of the API, written to the index's `config.json`.
+    pub api_url: String,
+    /// The public URL which crates are downloaded from, written to the
+    /// index's `config.json`.
+    pub dl_url: String,
+    /// Whether cargo must send a token for every request to the registry,
+    /// including downloads and index files.
+    pub auth_required: bool,
+    /// The rate limits of each group of routes, keyed by the group's name.
+    /// Groups without a limit aren't limited.
+    pub rate_limits: HashMap<String, RateLimit>,
+    /// How long, in seconds, the result of asking Github whether someone is
+    /// a member of a team is trusted before asking again.
+    pub team_membership_ttl: i64,
+    /// Whether a membership which is older than `team_membership_ttl`, but
+    /// less than twice as old, is still trusted when Github can't be reached
+    /// to check it again.
+    pub allow_stale_team_memberships: bool,
 }
 
 impl Config {
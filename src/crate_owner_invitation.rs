@@ -0,0 +1,224 @@
+//! Invitations for users to become owners of a crate.
+//!
+//! Listing a user in `PUT /crates/:crate_id/owners` no longer makes them an
+//! owner right away. Instead they're invited, and only become an owner once
+//! they accept the invitation. Until then they have no rights to the crate.
+
+use conduit::{Request, Response};
+use conduit_router::RequestParams;
+use pg::GenericConnection;
+use pg::rows::Row;
+use time::{Duration, Timespec};
+
+use {Model, User};
+use app::RequestApp;
+use db::RequestTransaction;
+use krate::Crate;
+use owner::{Rights, rights};
+use token::Scope;
+use user::RequestUser;
+use util::{RequestUtils, CargoResult, ChainError, human, internal};
+
+/// How long an invitation can be accepted for, in days.
+pub const EXPIRY_DAYS: i64 = 30;
+
+pub struct CrateOwnerInvitation {
+    pub invited_user_id: i32,
+    pub invited_by_user_id: i32,
+    pub crate_id: i32,
+    pub created_at: Timespec,
+}
+
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct EncodableCrateOwnerInvitation {
+    pub invited_user: String,
+    pub invited_by: String,
+    pub crate_id: i32,
+    pub crate_name: String,
+    pub created_at: String,
+    pub expires_at: String,
+}
+
+impl CrateOwnerInvitation {
+    /// Invites `invitee` to become an owner of `krate`. Inviting someone who
+    /// has already been invited starts their invitation over.
+    pub fn create(conn: &GenericConnection, inviter: &User, krate: &Crate,
+                  invitee: &User) -> CargoResult<CrateOwnerInvitation> {
+        try!(conn.execute("DELETE FROM crate_owner_invitations
+                            WHERE invited_user_id = $1 AND crate_id = $2",
+                          &[&invitee.id, &krate.id]));
+        let stmt = try!(conn.prepare("INSERT INTO crate_owner_invitations
+                                      (invited_user_id, invited_by_user_id,
+                                       crate_id, created_at)
+                                      VALUES ($1, $2, $3, $4)
+                                      RETURNING *"));
+        let rows = try!(stmt.query(&[&invitee.id, &inviter.id, &krate.id,
+                                     &::now()]));
+        let row = try!(rows.iter().next().chain_error(|| {
+            internal("no invitation was returned after inserting one")
+        }));
+        Ok(Model::from_row(&row))
+    }
+
+    /// The invitation of `user` to own the crate, whether or not it expired.
+    pub fn find(conn: &GenericConnection, user_id: i32,
+                crate_id: i32) -> CargoResult<Option<CrateOwnerInvitation>> {
+        let stmt = try!(conn.prepare("SELECT * FROM crate_owner_invitations
+                                      WHERE invited_user_id = $1
+                                        AND crate_id = $2"));
+        let rows = try!(stmt.query(&[&user_id, &crate_id]));
+        Ok(rows.iter().next().map(|r| Model::from_row(&r)))
+    }
+
+    /// The invitations to `user` which can still be accepted.
+    pub fn pending_for_user(conn: &GenericConnection, user: &User)
+                            -> CargoResult<Vec<CrateOwnerInvitation>> {
+        let stmt = try!(conn.prepare("SELECT * FROM crate_owner_invitations
+                                      WHERE invited_user_id = $1
+                                        AND created_at > $2
+                                      ORDER BY created_at DESC"));
+        let rows = try!(stmt.query(&[&user.id, &expired_before()]));
+        Ok(rows.iter().map(|r| Model::from_row(&r)).collect())
+    }
+
+    /// The invitations to own `krate` which can still be accepted.
+    pub fn pending_for_crate(conn: &GenericConnection, krate: &Crate)
+                             -> CargoResult<Vec<CrateOwnerInvitation>> {
+        let stmt = try!(conn.prepare("SELECT * FROM crate_owner_invitations
+                                      WHERE crate_id = $1
+                                        AND created_at > $2
+                                      ORDER BY created_at DESC"));
+        let rows = try!(stmt.query(&[&krate.id, &expired_before()]));
+        Ok(rows.iter().map(|r| Model::from_row(&r)).collect())
+    }
+
+    pub fn delete(&self, conn: &GenericConnection) -> CargoResult<()> {
+        try!(conn.execute("DELETE FROM crate_owner_invitations
+                            WHERE invited_user_id = $1 AND crate_id = $2",
+                          &[&self.invited_user_id, &self.crate_id]));
+        Ok(())
+    }
+
+    /// Withdraws the invitations to own `krate` which `inviter` sent.
+    pub fn delete_sent_by(conn: &GenericConnection, inviter: &User,
+                          krate: &Crate) -> CargoResult<()> {
+        try!(conn.execute("DELETE FROM crate_owner_invitations
+                            WHERE invited_by_user_id = $1 AND crate_id = $2",
+                          &[&inviter.id, &krate.id]));
+        Ok(())
+    }
+
+    pub fn expires_at(&self) -> Timespec {
+        self.created_at + Duration::days(EXPIRY_DAYS)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at() <= ::now()
+    }
+
+    pub fn encodable(self, conn: &GenericConnection)
+                     -> CargoResult<EncodableCrateOwnerInvitation> {
+        let invited_user: User = try!(Model::find(conn, self.invited_user_id));
+        let invited_by: User = try!(Model::find(conn, self.invited_by_user_id));
+        let krate: Crate = try!(Model::find(conn, self.crate_id));
+        let expires_at = self.expires_at();
+        Ok(EncodableCrateOwnerInvitation {
+            invited_user: invited_user.gh_login,
+            invited_by: invited_by.gh_login,
+            crate_id: krate.id,
+            crate_name: krate.name,
+            created_at: ::encode_time(self.created_at),
+            expires_at: ::encode_time(expires_at),
+        })
+    }
+}
+
+impl Model for CrateOwnerInvitation {
+    fn from_row(row: &Row) -> CrateOwnerInvitation {
+        CrateOwnerInvitation {
+            invited_user_id: row.get("invited_user_id"),
+            invited_by_user_id: row.get("invited_by_user_id"),
+            crate_id: row.get("crate_id"),
+            created_at: row.get("created_at"),
+        }
+    }
+
+    fn table_name(_: Option<CrateOwnerInvitation>) -> &'static str {
+        "crate_owner_invitations"
+    }
+}
+
+/// Invitations created before this have expired.
+fn expired_before() -> Timespec {
+    ::now() - Duration::days(EXPIRY_DAYS)
+}
+
+/// Handles the `GET /me/crate_owner_invitations` route.
+pub fn list(req: &mut Request) -> CargoResult<Response> {
+    let user = try!(req.user());
+    let tx = try!(req.tx());
+    let invitations = try!(CrateOwnerInvitation::pending_for_user(tx, user));
+    let mut encodable = Vec::new();
+    for invitation in invitations {
+        encodable.push(try!(invitation.encodable(tx)));
+    }
+
+    #[derive(RustcEncodable)]
+    struct R { crate_owner_invitations: Vec<EncodableCrateOwnerInvitation> }
+    Ok(req.json(&R { crate_owner_invitations: encodable }))
+}
+
+/// Handles the `PUT /me/crate_owner_invitations/:crate_id/accept` route.
+pub fn accept(req: &mut Request) -> CargoResult<Response> {
+    // Accepting an invitation grants full rights to the crate, which a token
+    // restricted to some crates or actions mustn't be able to do.
+    try!(req.check_not_restricted("accept invitations"));
+    let user = try!(req.user()).clone();
+    let tx = try!(req.tx());
+    let (krate, invitation) = try!(find_invitation(req, &user));
+    if invitation.is_expired() {
+        return Err(human(format!("the invitation to become an owner of `{}` \
+                                  has expired", krate.name)))
+    }
+
+    // Whoever sent the invitation may have lost the right to add owners
+    // since then, e.g. by having been removed as an owner themselves.
+    let inviter: User = try!(Model::find(tx, invitation.invited_by_user_id));
+    let owners = try!(krate.owners(tx));
+    if try!(rights(req, tx, &owners, &inviter, Scope::Owners,
+                   &krate.name)) != Rights::Full {
+        return Err(human(format!("`{}` is no longer an owner of `{}`, so \
+                                  the invitation can't be accepted",
+                                 inviter.gh_login, krate.name)))
+    }
+    try!(invitation.delete(tx));
+    try!(krate.owner_add(req.app(), tx, &inviter, &user.gh_login));
+
+    #[derive(RustcEncodable)]
+    struct R { ok: bool }
+    Ok(req.json(&R { ok: true }))
+}
+
+/// Handles the `PUT /me/crate_owner_invitations/:crate_id/decline` route.
+pub fn decline(req: &mut Request) -> CargoResult<Response> {
+    let user = try!(req.user()).clone();
+    let tx = try!(req.tx());
+    let (_, invitation) = try!(find_invitation(req, &user));
+    try!(invitation.delete(tx));
+
+    #[derive(RustcEncodable)]
+    struct R { ok: bool }
+    Ok(req.json(&R { ok: true }))
+}
+
+fn find_invitation(req: &Request, user: &User)
+                   -> CargoResult<(Crate, CrateOwnerInvitation)> {
+    let tx = try!(req.tx());
+    let name = &req.params()["crate_id"];
+    let krate = try!(Crate::find_by_name(tx, name));
+    match try!(CrateOwnerInvitation::find(tx, user.id, krate.id)) {
+        Some(invitation) => Ok((krate, invitation)),
+        None => Err(human(format!("you haven't been invited to become an \
+                                   owner of `{}`", name))),
+    }
+}
@@ -1,4 +1,4 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::error::Error;
 use std::mem;
 use std::sync::Arc;
@@ -33,6 +33,9 @@ pub struct Transaction {
     tx: LazyCell<pg::Transaction<'static>>,
     slot: LazyCell<PooledConnnection>,
     commit: Cell<bool>,
+    // Undoes changes made outside of the database if the transaction ends up
+    // not being committed.
+    undo: RefCell<Vec<Box<Fn()>>>,
 
     // Keep a handle to the app which keeps a handle to the database to ensure
     // that this `'static` is indeed at least a little more accurate (in that
@@ -47,6 +50,7 @@ impl Transaction {
             slot: LazyCell::new(),
             tx: LazyCell::new(),
             commit: Cell::new(false),
+            undo: RefCell::new(Vec::new()),
         }
     }
 
@@ -81,6 +85,34 @@ impl Transaction {
 
     pub fn rollback(&self) { self.commit.set(false); }
     pub fn commit(&self) { self.commit.set(true); }
+
+    /// Registers `undo` to be called if this transaction isn't committed.
+    pub fn on_rollback(&self, undo: Box<Fn()>) {
+        self.undo.borrow_mut().push(undo);
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        let committed = match self.tx.take() {
+            Some(tx) => {
+                let will_commit = tx.will_commit();
+                match tx.finish() {
+                    Ok(()) => will_commit,
+                    Err(e) => {
+                        error!("failed to finish the request's transaction: {}", e);
+                        false
+                    }
+                }
+            }
+            None => false,
+        };
+        if !committed {
+            for undo in self.undo.borrow_mut().drain(..) {
+                undo();
+            }
+        }
+    }
 }
 
 impl Middleware for TransactionMiddleware {
@@ -158,6 +190,10 @@ pub trait RequestTransaction {
     fn rollback(&self);
     /// Flag this transaction to be committed
     fn commit(&self);
+
+    /// Undo a change made outside of the database, such as an upload to
+    /// storage, if the transaction for this request isn't committed.
+    fn on_rollback(&self, undo: Box<Fn()>);
 }
 
 impl<'a> RequestTransaction for Request + 'a {
@@ -190,4 +226,10 @@ impl<'a> RequestTransaction for Request + 'a {
             .expect("Transaction not present in request")
             .commit()
     }
+
+    fn on_rollback(&self, undo: Box<Fn()>) {
+        self.extensions().find::<Transaction>()
+            .expect("Transaction not present in request")
+            .on_rollback(undo)
+    }
 }
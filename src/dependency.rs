@@ -10,6 +10,9 @@ pub struct Dependency {
     pub id: i32,
     pub version_id: i32,
     pub crate_id: i32,
+    /// The name the crate depended on had when this dependency was
+    /// published, which is the one its index entry uses.
+    pub name: String,
     pub req: semver::VersionReq,
     pub optional: bool,
     pub default_features: bool,
@@ -44,29 +47,31 @@ pub enum Kind {
 
 impl Dependency {
     pub fn insert(conn: &GenericConnection, version_id: i32, crate_id: i32,
-                  req: &semver::VersionReq, kind: Kind,
+                  name: &str, req: &semver::VersionReq, kind: Kind,
                   optional: bool, default_features: bool,
                   features: &[String], target: &Option<String>)
                   -> CargoResult<Dependency> {
         let req = req.to_string();
         let features = features.join(",");
         let stmt = try!(conn.prepare("INSERT INTO dependencies
-                                      (version_id, crate_id, req, optional,
-                                       default_features, features, target, kind)
-                                      VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+                                      (version_id, crate_id, name, req,
+                                       optional, default_features, features,
+                                       target, kind)
+                                      VALUES ($1, $2, $3, $4, $5, $6, $7, $8,
+                                              $9)
                                       RETURNING *"));
-        let rows = try!(stmt.query(&[&version_id, &crate_id, &req,
+        let rows = try!(stmt.query(&[&version_id, &crate_id, &name, &req,
                                       &optional, &default_features,
                                       &features, target, &(kind as i32)]));
         Ok(Model::from_row(&rows.iter().next().unwrap()))
     }
 
-    pub fn git_encode(&self, crate_name: &str) -> git::Dependency {
-        let Dependency { id: _, version_id: _, crate_id: _, ref req,
+    pub fn git_encode(&self) -> git::Dependency {
+        let Dependency { id: _, version_id: _, crate_id: _, ref name, ref req,
                          optional, default_features, ref features,
                          ref target, kind } = *self;
         git::Dependency {
-            name: crate_name.to_string(),
+            name: name.clone(),
             req: req.to_string(),
             features: features.clone(),
             optional: optional,
@@ -77,7 +82,7 @@ impl Dependency {
     }
 
     pub fn encodable(self, crate_name: &str) -> EncodableDependency {
-        let Dependency { id, version_id, crate_id: _, req, optional,
+        let Dependency { id, version_id, crate_id: _, name: _, req, optional,
                          default_features, features, target, kind } = self;
         EncodableDependency {
             id: id,
@@ -102,6 +107,7 @@ impl Model for Dependency {
             id: row.get("id"),
             version_id: row.get("version_id"),
             crate_id: row.get("crate_id"),
+            name: row.get("name"),
             req: semver::VersionReq::parse(&req).unwrap(),
             optional: row.get("optional"),
             default_features: row.get("default_features"),
@@ -1,55 +1,30 @@
+use std::collections::HashMap;
+
+use openssl::crypto::hash::{hash, Type};
+use pg::GenericConnection;
+use pg::error::{Error as PgError, SqlState};
 use pg::rows::Row;
-use time::Timespec;
+use pg::types::Slice;
+use rustc_serialize::hex::ToHex;
+use time::{self, Duration, Timespec};
 
 use Model;
-
-pub struct VersionDownload {
-    pub id: i32,
-    pub version_id: i32,
-    pub downloads: i32,
-    pub counted: i32,
-    pub date: Timespec,
-}
+use util::{CargoResult, ChainError, human};
 
 #[derive(RustcEncodable, RustcDecodable)]
 pub struct EncodableVersionDownload {
     pub id: i32,
     pub version: i32,
     pub downloads: i32,
+    pub unique_downloads: i32,
     pub date: String,
 }
 
-impl VersionDownload {
-    pub fn encodable(self) -> EncodableVersionDownload {
-        let VersionDownload { id, version_id, downloads, counted: _,
-                              date } = self;
-        EncodableVersionDownload {
-            id: id,
-            version: version_id,
-            downloads: downloads,
-            date: ::encode_time(date),
-        }
-    }
-}
-
-impl Model for VersionDownload {
-    fn from_row(row: &Row) -> VersionDownload {
-        VersionDownload {
-            id: row.get("id"),
-            version_id: row.get("version_id"),
-            downloads: row.get("downloads"),
-            counted: row.get("counted"),
-            date: row.get("date"),
-        }
-    }
-
-    fn table_name(_: Option<VersionDownload>) -> &'static str { "version_downloads" }
-}
-
 pub struct CrateDownload {
     pub id: i32,
     pub crate_id: i32,
     pub downloads: i32,
+    pub unique_downloads: i32,
     pub date: Timespec,
 }
 
@@ -59,9 +34,233 @@ impl Model for CrateDownload {
             id: row.get("id"),
             crate_id: row.get("crate_id"),
             downloads: row.get("downloads"),
+            unique_downloads: row.get("unique_downloads"),
             date: row.get("date"),
         }
     }
 
     fn table_name(_: Option<CrateDownload>) -> &'static str { "crate_downloads" }
 }
+
+/// Downloads summed over a period, for things other than a single version.
+#[derive(RustcEncodable, RustcDecodable)]
+pub struct DownloadTotal {
+    pub date: String,
+    pub downloads: i64,
+    pub unique_downloads: i64,
+}
+
+/// Classifies the client making a download by its `User-Agent`, as one of
+/// `cargo`, `browser` or `other`.
+pub fn client_class(user_agent: &str) -> &'static str {
+    if user_agent.starts_with("cargo") {
+        "cargo"
+    } else if user_agent.starts_with("Mozilla/") {
+        "browser"
+    } else {
+        "other"
+    }
+}
+
+/// Identifies a client for the purposes of counting unique downloads without
+/// recording who it is.
+///
+/// The address and `User-Agent` are hashed along with a server secret and the
+/// date, so the fingerprint can't be reversed by trying every address and
+/// can't be used to follow a client from one day to the next.
+pub fn fingerprint(secret: &str, date: &str, addr: &str,
+                   user_agent: &str) -> String {
+    let data = [secret, date, addr, user_agent].join("\0");
+    hash(Type::SHA256, data.as_bytes())[..16].to_hex()
+}
+
+/// Counts a download of a version today.
+///
+/// Each version has one row per day, created by the first download of the
+/// day. If two requests race to create it, the insert of one of them fails
+/// on the unique `(version_id, date)` constraint and it tries again, this time
+/// finding the row to update.
+pub fn increment(conn: &GenericConnection, version_id: i32) -> CargoResult<()> {
+    loop {
+        let tx = try!(conn.transaction());
+        let result = tx.execute("\
+            WITH updated AS (
+                UPDATE version_downloads SET downloads = downloads + 1
+                 WHERE version_id = $1 AND date = current_date
+             RETURNING id
+            )
+            INSERT INTO version_downloads
+                        (version_id, downloads, counted, date, processed)
+                 SELECT $1, 1, 0, current_date, FALSE
+                  WHERE NOT EXISTS (SELECT 1 FROM updated)", &[&version_id]);
+        match result {
+            Ok(..) => {
+                tx.set_commit();
+                return Ok(try!(tx.finish()))
+            }
+            Err(PgError::Db(ref e)) if *e.code() == SqlState::UniqueViolation => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Records a download of a version today by the client with `fingerprint`.
+pub fn record_client(conn: &GenericConnection, version_id: i32, client: &str,
+                     fingerprint: &str) -> CargoResult<()> {
+    loop {
+        let tx = try!(conn.transaction());
+        let result = tx.execute("\
+            WITH updated AS (
+                UPDATE version_download_clients SET downloads = downloads + 1
+                 WHERE version_id = $1 AND date = current_date
+                   AND fingerprint = $2
+             RETURNING 1
+            )
+            INSERT INTO version_download_clients
+                        (version_id, date, fingerprint, client, downloads)
+                 SELECT $1, current_date, $2, $3, 1
+                  WHERE NOT EXISTS (SELECT 1 FROM updated)",
+            &[&version_id, &fingerprint, &client]);
+        match result {
+            Ok(..) => {
+                tx.set_commit();
+                return Ok(try!(tx.finish()))
+            }
+            Err(PgError::Db(ref e)) if *e.code() == SqlState::UniqueViolation => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// The dates and granularity of requested download statistics.
+///
+/// These come from the `from` and `to` query parameters, which are inclusive
+/// dates like `2015-12-31` defaulting to the last 90 days, and `granularity`,
+/// which is one of `day` (the default), `week` or `month`. Each period is
+/// labelled with the date it starts on, so the first and last periods of a
+/// range may only be partially covered.
+pub struct DownloadRange {
+    pub from: Timespec,
+    pub to: Timespec,
+    pub granularity: &'static str,
+}
+
+impl DownloadRange {
+    pub fn from_query(query: &HashMap<String, String>)
+                      -> CargoResult<DownloadRange> {
+        let date = |name: &str| -> CargoResult<Option<Timespec>> {
+            match query.get(name) {
+                Some(s) => {
+                    let tm = try!(time::strptime(s, "%Y-%m-%d").ok().chain_error(|| {
+                        human(format!("invalid `{}`, expected a date like \
+                                       `2015-12-31`", name))
+                    }));
+                    Ok(Some(tm.to_timespec()))
+                }
+                None => Ok(None),
+            }
+        };
+        let today = time::now_utc().to_timespec();
+        let today = Timespec::new(today.sec - today.sec % 86400, 0);
+        let to = try!(date("to")).unwrap_or(today);
+        let from = try!(date("from")).unwrap_or(to + Duration::days(-89));
+        if from > to {
+            return Err(human("`from` must not be after `to`"))
+        }
+        let granularity = match query.get("granularity").map(|s| &s[..]) {
+            None | Some("day") => "day",
+            Some("week") => "week",
+            Some("month") => "month",
+            Some(..) => {
+                return Err(human("invalid `granularity`, expected `day`, \
+                                  `week` or `month`"))
+            }
+        };
+        Ok(DownloadRange { from: from, to: to, granularity: granularity })
+    }
+
+    /// Sums the downloads of each of `versions` over each period of the range.
+    pub fn version_downloads(&self, conn: &GenericConnection, versions: &[i32])
+                             -> CargoResult<Vec<EncodableVersionDownload>> {
+        let stmt = try!(conn.prepare("\
+              SELECT MIN(id) AS id, version_id,
+                     SUM(downloads)::int AS downloads,
+                     SUM(unique_downloads)::int AS unique_downloads,
+                     date_trunc($1, date::timestamp) AS period
+                FROM version_downloads
+               WHERE date >= $2::timestamp AND date < $3::timestamp
+                 AND version_id = ANY($4)
+            GROUP BY version_id, period
+            ORDER BY period ASC, version_id ASC"));
+        let rows = try!(stmt.query(&[&self.granularity, &self.from,
+                                     &self.end(), &Slice(versions)]));
+        let downloads = rows.iter().map(|row| {
+            EncodableVersionDownload {
+                id: row.get("id"),
+                version: row.get("version_id"),
+                downloads: row.get("downloads"),
+                unique_downloads: row.get("unique_downloads"),
+                date: ::encode_time(row.get("period")),
+            }
+        }).collect();
+        Ok(downloads)
+    }
+
+    /// Sums the downloads of all versions of a crate except for `versions`
+    /// over each period of the range.
+    pub fn other_downloads(&self, conn: &GenericConnection, crate_id: i32,
+                           versions: &[i32]) -> CargoResult<Vec<DownloadTotal>> {
+        let stmt = try!(conn.prepare("\
+              SELECT to_char(date_trunc($1, version_downloads.date::timestamp),
+                             'YYYY-MM-DD') AS period,
+                     SUM(version_downloads.downloads) AS downloads,
+                     SUM(version_downloads.unique_downloads) AS unique_downloads
+                FROM version_downloads
+               INNER JOIN versions ON
+                     version_id = versions.id
+               WHERE version_downloads.date >= $2::timestamp
+                 AND version_downloads.date < $3::timestamp
+                 AND versions.crate_id = $4
+                 AND NOT (versions.id = ANY($5))
+            GROUP BY period
+            ORDER BY period ASC"));
+        let rows = try!(stmt.query(&[&self.granularity, &self.from,
+                                     &self.end(), &crate_id,
+                                     &Slice(versions)]));
+        Ok(rows.iter().map(|row| {
+            DownloadTotal {
+                date: row.get("period"),
+                downloads: row.get("downloads"),
+                unique_downloads: row.get("unique_downloads"),
+            }
+        }).collect())
+    }
+
+    /// Sums the counted downloads of a crate over each period of the range.
+    pub fn crate_downloads(&self, conn: &GenericConnection, crate_id: i32)
+                           -> CargoResult<Vec<DownloadTotal>> {
+        let stmt = try!(conn.prepare("\
+              SELECT to_char(date_trunc($1, date), 'YYYY-MM-DD') AS period,
+                     SUM(downloads) AS downloads,
+                     SUM(unique_downloads) AS unique_downloads
+                FROM crate_downloads
+               WHERE date >= $2 AND date < $3
+                 AND crate_id = $4
+            GROUP BY period
+            ORDER BY period ASC"));
+        let rows = try!(stmt.query(&[&self.granularity, &self.from,
+                                     &self.end(), &crate_id]));
+        Ok(rows.iter().map(|row| {
+            DownloadTotal {
+                date: row.get("period"),
+                downloads: row.get("downloads"),
+                unique_downloads: row.get("unique_downloads"),
+            }
+        }).collect())
+    }
+
+    /// The start of the day after `to`.
+    fn end(&self) -> Timespec {
+        self.to + Duration::days(1)
+    }
+}
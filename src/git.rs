@@ -1,14 +1,13 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::fs::{self, File};
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
-use semver;
 use git2;
-use rustc_serialize::json;
+use rustc_serialize::json::{self, Json};
 
-use app::App;
+use Config;
 use dependency::Kind;
 use util::{CargoResult, internal};
 
@@ -33,7 +32,9 @@ pub struct Dependency {
     pub kind: Option<Kind>,
 }
 
-fn index_file(base: &Path, name: &str) -> PathBuf {
+/// Returns the path of the file describing the crate `name` in the index
+/// checked out at `base`.
+pub fn index_file(base: &Path, name: &str) -> PathBuf {
     let name = name.chars().flat_map(|c| c.to_lowercase()).collect::<String>();
     match name.len() {
         1 => base.join("1").join(&name),
@@ -45,9 +46,7 @@ fn index_file(base: &Path, name: &str) -> PathBuf {
     }
 }
 
-pub fn add_crate(app: &App, krate: &Crate) -> CargoResult<()> {
-    let repo = app.git_repo.lock().unwrap();
-    let repo = &*repo;
+pub fn add_crate(repo: &git2::Repository, krate: &Crate) -> CargoResult<()> {
     let repo_path = repo.workdir().unwrap();
     let dst = index_file(&repo_path, &krate.name);
 
@@ -58,21 +57,25 @@ pub fn add_crate(app: &App, krate: &Crate) -> CargoResult<()> {
         if fs::metadata(&dst).is_ok() {
             try!(File::open(&dst).and_then(|mut f| f.read_to_string(&mut prev)));
         }
-        let s = json::encode(krate).unwrap();
-        let new = prev + &s;
-        let mut f = try!(File::create(&dst));
-        try!(f.write_all(new.as_bytes()));
-        try!(f.write_all(b"\n"));
+
+        // A job which is retried may have added this version already
+        let exists = prev.lines().filter_map(|l| json::decode::<Crate>(l).ok())
+                         .any(|c| c.vers == krate.vers);
+        if !exists {
+            let s = json::encode(krate).unwrap();
+            let new = prev + &s;
+            let mut f = try!(File::create(&dst));
+            try!(f.write_all(new.as_bytes()));
+            try!(f.write_all(b"\n"));
+        }
 
         Ok((format!("Updating crate `{}#{}`", krate.name, krate.vers),
             dst.clone()))
     })
 }
 
-pub fn yank(app: &App, krate: &str, version: &semver::Version,
+pub fn yank(repo: &git2::Repository, krate: &str, version: &str,
             yanked: bool) -> CargoResult<()> {
-    let repo = app.git_repo.lock().unwrap();
-    let repo = &*repo;
     let repo_path = repo.workdir().unwrap();
     let dst = index_file(&repo_path, krate);
 
@@ -83,8 +86,7 @@ pub fn yank(app: &App, krate: &str, version: &semver::Version,
             let mut git_crate = try!(json::decode::<Crate>(line).map_err(|_| {
                 internal(format!("couldn't decode: `{}`", line))
             }));
-            if git_crate.name != krate ||
-               git_crate.vers.to_string() != version.to_string() {
+            if git_crate.name != krate || git_crate.vers != version {
                 return Ok(line.to_string())
             }
             git_crate.yanked = Some(yanked);
@@ -102,6 +104,59 @@ pub fn yank(app: &App, krate: &str, version: &semver::Version,
     })
 }
 
+/// Creates the index file for the new name `new` of the crate `old`. The
+/// versions published before the rename stay in the old file, as their
+/// tarballs contain the old name, so the new file starts out empty unless the
+/// crate is going back to a name it had before.
+pub fn rename_crate(repo: &git2::Repository, old: &str, new: &str) -> CargoResult<()> {
+    let repo_path = repo.workdir().unwrap();
+    let dst = index_file(&repo_path, new);
+
+    commit_and_push(repo, || {
+        if fs::metadata(&dst).is_err() {
+            try!(fs::create_dir_all(dst.parent().unwrap()));
+            try!(File::create(&dst));
+        }
+        Ok((format!("Renaming crate `{}` to `{}`", old, new), dst.clone()))
+    })
+}
+
+/// Returns the contents of the index's `config.json` which points cargo at
+/// the registry described by `config`.
+pub fn config_json(config: &Config) -> String {
+    let mut obj = BTreeMap::new();
+    obj.insert("dl".to_string(), Json::String(config.dl_url.clone()));
+    obj.insert("api".to_string(), Json::String(config.api_url.clone()));
+    if config.auth_required {
+        obj.insert("auth-required".to_string(), Json::Boolean(true));
+    }
+    format!("{}\n", Json::Object(obj).pretty())
+}
+
+/// Returns whether the `config.json` in the index checked out at `base` has
+/// the same settings as `contents`.
+pub fn config_is_current(base: &Path, contents: &str) -> bool {
+    let mut prev = String::new();
+    let _ = File::open(base.join("config.json")).and_then(|mut f| {
+        f.read_to_string(&mut prev)
+    });
+    Json::from_str(&prev).ok() == Json::from_str(contents).ok()
+}
+
+pub fn update_config(repo: &git2::Repository, contents: &str) -> CargoResult<()> {
+    let repo_path = repo.workdir().unwrap();
+    let dst = repo_path.join("config.json");
+    if config_is_current(&repo_path, contents) {
+        return Ok(())
+    }
+
+    commit_and_push(repo, || {
+        let mut f = try!(File::create(&dst));
+        try!(f.write_all(contents.as_bytes()));
+        Ok(("Updating config.json".to_string(), dst.clone()))
+    })
+}
+
 fn commit_and_push<F>(repo: &git2::Repository, mut f: F) -> CargoResult<()>
     where F: FnMut() -> CargoResult<(String, PathBuf)>
 {
@@ -123,10 +178,14 @@ fn commit_and_push<F>(repo: &git2::Repository, mut f: F) -> CargoResult<()>
         try!(index.write());
         let tree_id = try!(index.write_tree());
         let tree = try!(repo.find_tree(tree_id));
-
-        // git commit -m "..."
         let head = try!(repo.head());
         let parent = try!(repo.find_commit(head.target().unwrap()));
+        if parent.tree_id() == tree_id {
+            // Nothing changed, so there's nothing to commit or push
+            return Ok(())
+        }
+
+        // git commit -m "..."
         let sig = try!(repo.signature());
         try!(repo.commit(Some("HEAD"), &sig, &sig, &msg, &tree, &[&parent]));
 
@@ -0,0 +1,166 @@
+//! Serves the registry index over plain HTTP.
+//!
+//! Each index file is available under `/index/` at the same path it has in
+//! the git index, so clients can fetch just the files of the crates they need
+//! instead of cloning the whole repository. The files are generated from the
+//! database, so they're up to date even while the background worker still has
+//! changes to push to the git index.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::Cursor;
+use std::path::Path;
+
+use conduit::{Request, Response};
+use conduit_router::RequestParams;
+use openssl::crypto::hash::{hash, Type};
+use pg::GenericConnection;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{self, Json};
+use time::{self, Timespec};
+
+use {Model, Crate, Version};
+use app::RequestApp;
+use db::RequestTransaction;
+use git;
+use user::RequestUser;
+use util::{CargoResult, ChainError};
+use util::errors::NotFound;
+
+/// Handles the `GET /index/config.json` route.
+pub fn config(req: &mut Request) -> CargoResult<Response> {
+    let body = git::config_json(&req.app().config).into_bytes();
+    Ok(response(body, "application/json; charset=utf-8", None))
+}
+
+/// Handles the `GET /index/*path` route.
+pub fn file(req: &mut Request) -> CargoResult<Response> {
+    if req.app().config.auth_required {
+        try!(req.user());
+    }
+    let path = req.params()["path"].trim_left_matches('/').to_string();
+    let name = try!(path.rsplit('/').next().chain_error(|| NotFound)).to_string();
+    if git::index_file(Path::new(""), &name) != Path::new(&path) {
+        return Err(Box::new(NotFound))
+    }
+
+    let checkout = req.app().git_repo_checkout.clone();
+    // The file of a crate which hasn't published anything under its new name
+    // yet is empty, as it is in the git index.
+    let (lines, last_modified) = try!(lines(try!(req.tx()), &checkout, &name));
+    let mut body = String::new();
+    for line in lines.iter() {
+        body.push_str(&json::encode(line).unwrap());
+        body.push('\n');
+    }
+    Ok(response(body.into_bytes(), "text/plain; charset=utf-8",
+                Some(last_modified)))
+}
+
+/// Generates the lines of the index file for `name`, along with when they
+/// last changed.
+///
+/// This mirrors what the background worker pushes to the git index: the file
+/// belongs to the crate currently or previously called `name`, and lists the
+/// versions it published under that name.
+fn lines(conn: &GenericConnection, checkout: &Path,
+         name: &str) -> CargoResult<(Vec<git::Crate>, Timespec)> {
+    let stmt = try!(conn.prepare("SELECT * FROM crates
+                                  WHERE canon_crate_name(name) =
+                                        canon_crate_name($1) LIMIT 1"));
+    let krate: Option<Crate> = try!(stmt.query(&[&name])).iter().next()
+                                        .map(|r| Model::from_row(&r));
+    let krate = match krate {
+        Some(krate) => krate,
+        None => {
+            let stmt = try!(conn.prepare("SELECT * FROM crate_aliases
+                                          WHERE canon_crate_name(name) =
+                                                canon_crate_name($1)
+                                          ORDER BY id DESC LIMIT 1"));
+            let rows = try!(stmt.query(&[&name]));
+            let row = try!(rows.iter().next().chain_error(|| NotFound));
+            try!(Crate::find(conn, row.get("crate_id")))
+        }
+    };
+
+    let stmt = try!(conn.prepare("SELECT * FROM versions WHERE crate_id = $1
+                                  ORDER BY id ASC"));
+    let versions = try!(stmt.query(&[&krate.id])).iter().map(|r| {
+        let v: Version = Model::from_row(&r);
+        v
+    }).collect::<Vec<_>>();
+    let aliases = try!(krate.aliases(conn));
+
+    // Names which differ in more than case, such as `foo-bar` and `foo_bar`,
+    // have different files, and only the ones of names the crate had exist.
+    let exists = aliases.iter().map(|&(ref alias, _)| alias)
+                        .chain(Some(&krate.name))
+                        .any(|n| n.to_lowercase() == name.to_lowercase());
+    if !exists {
+        return Err(Box::new(NotFound))
+    }
+
+    let mut last_modified = Timespec::new(0, 0);
+    let mut lines = Vec::new();
+    for version in versions.iter() {
+        let published_name = krate.published_name(&aliases, version);
+        if published_name.to_lowercase() != name.to_lowercase() { continue }
+        lines.push(try!(version.git_encode(conn, published_name)));
+        if version.updated_at > last_modified {
+            last_modified = version.updated_at;
+        }
+    }
+
+    if lines.is_empty() {
+        last_modified = krate.updated_at;
+    }
+
+    // Versions published before we started recording checksums only have one
+    // in the git index.
+    if lines.iter().any(|l| l.cksum.is_empty()) {
+        let checksums = checkout_checksums(checkout, name);
+        for line in lines.iter_mut().filter(|l| l.cksum.is_empty()) {
+            if let Some(cksum) = checksums.get(&line.vers) {
+                line.cksum = cksum.clone();
+            }
+        }
+    }
+    Ok((lines, last_modified))
+}
+
+fn checkout_checksums(checkout: &Path, name: &str) -> HashMap<String, String> {
+    let mut contents = String::new();
+    if let Ok(mut f) = File::open(git::index_file(checkout, name)) {
+        let _ = f.read_to_string(&mut contents);
+    }
+    contents.lines().filter_map(|l| Json::from_str(l).ok()).filter_map(|line| {
+        let vers = line.find("vers").and_then(|v| v.as_string());
+        let cksum = line.find("cksum").and_then(|c| c.as_string());
+        match (vers, cksum) {
+            (Some(vers), Some(cksum)) => {
+                Some((vers.to_string(), cksum.to_string()))
+            }
+            _ => None,
+        }
+    }).collect()
+}
+
+fn response(body: Vec<u8>, content_type: &str,
+            last_modified: Option<Timespec>) -> Response {
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(), vec![content_type.to_string()]);
+    headers.insert("Content-Length".to_string(), vec![body.len().to_string()]);
+    headers.insert("ETag".to_string(),
+                   vec![format!("\"{}\"", hash(Type::SHA256, &body).to_hex())]);
+    if let Some(t) = last_modified {
+        let t = time::at_utc(t);
+        headers.insert("Last-Modified".to_string(),
+                       vec![time::strftime("%a, %d %b %Y %T GMT", &t).unwrap()]);
+    }
+    Response {
+        status: (200, "OK"),
+        headers: headers,
+        body: Box::new(Cursor::new(body)),
+    }
+}
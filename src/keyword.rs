@@ -6,11 +6,11 @@ use conduit::{Request, Response};
 use conduit_router::RequestParams;
 use pg::GenericConnection;
 use pg::rows::Row;
-use pg::types::Slice;
+use pg::types::{Slice, ToSql};
 
 use {Model, Crate};
 use db::RequestTransaction;
-use util::{RequestUtils, CargoResult, ChainError, internal};
+use util::{RequestUtils, CargoResult, ChainError, internal, encode_seek};
 use util::errors::NotFound;
 
 #[derive(Clone)]
@@ -144,33 +144,65 @@ pub fn index(req: &mut Request) -> CargoResult<Response> {
     let query = req.query();
     let sort = query.get("sort").map(|s| &s[..]).unwrap_or("alpha");
     let sort_sql = match sort {
-        "crates" => "ORDER BY crates_cnt DESC",
+        "crates" => "ORDER BY crates_cnt DESC, id DESC",
         _ => "ORDER BY keyword ASC",
     };
 
+    // Later pages can start after the last keyword seen
+    let (seek_cnt, seek_keyword);
+    let mut args = vec![&limit as &ToSql, &offset];
+    let seek_sql = if sort == "crates" {
+        match try!(req.seek::<(i32, i32)>()) {
+            Some((cnt, id)) => {
+                seek_cnt = (cnt, id);
+                args.push(&seek_cnt.0);
+                args.push(&seek_cnt.1);
+                "WHERE (crates_cnt, id) < ($3, $4)"
+            }
+            None => "",
+        }
+    } else {
+        match try!(req.seek::<String>()) {
+            Some(keyword) => {
+                seek_keyword = keyword;
+                args.push(&seek_keyword);
+                "WHERE keyword > $3"
+            }
+            None => "",
+        }
+    };
+
     // Collect all the keywords
-    let stmt = try!(conn.prepare(&format!("SELECT * FROM keywords {}
+    let stmt = try!(conn.prepare(&format!("SELECT * FROM keywords {} {}
                                            LIMIT $1 OFFSET $2",
-                                          sort_sql)));
-    let mut keywords = Vec::new();
-    for row in try!(stmt.query(&[&limit, &offset])) {
-        let keyword: Keyword = Model::from_row(&row);
-        keywords.push(keyword.encodable());
-    }
+                                          seek_sql, sort_sql)));
+    let keywords = try!(stmt.query(&args)).iter().map(|row| {
+        Model::from_row(&row)
+    }).collect::<Vec<Keyword>>();
 
     // Query for the total count of keywords
     let stmt = try!(conn.prepare("SELECT COUNT(*) FROM keywords"));
     let row = try!(stmt.query(&[])).into_iter().next().unwrap();
     let total = row.get(0);
 
+    let next_page = match keywords.last() {
+        Some(..) if (keywords.len() as i64) < limit => None,
+        Some(last) if sort == "crates" => {
+            Some(req.next_page(Some(encode_seek(&(last.crates_cnt, last.id)))))
+        }
+        Some(last) => Some(req.next_page(Some(encode_seek(&last.keyword)))),
+        None => None,
+    };
+    let keywords = keywords.into_iter().map(|k| k.encodable()).collect();
+
     #[derive(RustcEncodable)]
     struct R { keywords: Vec<EncodableKeyword>, meta: Meta }
     #[derive(RustcEncodable)]
-    struct Meta { total: i64 }
+    struct Meta { total: i64, next_page: Option<String> }
 
     Ok(req.json(&R {
         keywords: keywords,
-        meta: Meta { total: total },
+        meta: Meta { total: total, next_page: next_page },
     }))
 }
 
@@ -1,39 +1,46 @@
 use std::ascii::AsciiExt;
 use std::cmp;
 use std::collections::HashMap;
+use std::fs::{self, File};
 use std::io::prelude::*;
-use std::io;
+use std::io::{self, SeekFrom};
 use std::iter::repeat;
 use std::mem;
-use std::sync::Arc;
+use std::path::Path;
 
 use conduit::{Request, Response};
 use conduit_router::RequestParams;
-use curl::http;
 use license_exprs;
 use pg::GenericConnection;
+use pg::error::{Error as PgError, SqlState};
 use pg::rows::Row;
 use pg::types::{ToSql, Slice};
-use rustc_serialize::hex::ToHex;
 use rustc_serialize::json;
 use semver;
-use time::{Timespec, Duration};
+use time::{self, Timespec};
 use url::{self, Url};
 use yaqb::*;
 
 use {Model, User, Keyword, Version};
 use app::{App, RequestApp};
+use background::Job;
+use crate_owner_invitation::{CrateOwnerInvitation, EncodableCrateOwnerInvitation};
 use db::RequestTransaction;
 use dependency::{Dependency, EncodableDependency};
-use download::{VersionDownload, EncodableVersionDownload};
+use download::{self, DownloadRange, DownloadTotal, EncodableVersionDownload};
 use git;
 use keyword::EncodableKeyword;
+use storage::Download;
+use tarball;
+use token::Scope;
 use upload;
 use user::RequestUser;
+use publish::Publish;
 use owner::{EncodableOwner, Owner, Rights, OwnerKind, Team, rights};
 use util::errors::{NotFound, CargoError};
-use util::{LimitErrorReader, HashingReader};
-use util::{RequestUtils, CargoResult, internal, ChainError, human};
+use util::LimitErrorReader;
+use util::{RequestUtils, CargoResult, internal, ChainError, human, encode_seek};
+use util::{client_ip, csv_response};
 use version::{EncodableVersion, versions};
 
 #[derive(Clone)]
@@ -44,6 +51,7 @@ pub struct Crate {
     pub updated_at: Timespec,
     pub created_at: Timespec,
     pub downloads: i32,
+    pub unique_downloads: i32,
     pub max_version: semver::Version,
     pub description: Option<String>,
     pub homepage: Option<String>,
@@ -70,17 +78,7 @@ table! {
         keywords -> Nullable<VarChar>,
         license -> Nullable<VarChar>,
         repository -> Nullable<VarChar>,
-    }
-}
-
-table! {
-    version_downloads {
-        id -> Serial,
-        version_id -> Integer,
-        downloads -> Integer,
-        counted -> Integer,
-        date -> BigInt,
-        processed -> Bool,
+        unique_downloads -> Integer,
     }
 }
 
@@ -103,10 +101,10 @@ fn parse_time(t: i64) -> Timespec {
 }
 
 impl Queriable<crates::SqlType> for Crate {
-    type Row = (i32, String, i32, i64, i64, i32, String, Option<String>, Option<String>, Option<String>, Option<String>, Option<String>, Option<String>, Option<String>);
+    type Row = (i32, String, i32, i64, i64, i32, String, Option<String>, Option<String>, Option<String>, Option<String>, Option<String>, Option<String>, Option<String>, i32);
 
     fn build(row: Self::Row) -> Self {
-        let (id, name, user_id, raw_updated, raw_created, downloads, max_version, description, homepage, documentation, readme, keywords, license, repository) = row;
+        let (id, name, user_id, raw_updated, raw_created, downloads, max_version, description, homepage, documentation, readme, keywords, license, repository, unique_downloads) = row;
         let created_at = parse_time(raw_created);
         let updated_at = parse_time(raw_updated);
         let keywords = keywords.unwrap_or(String::new()).split(',')
@@ -121,6 +119,7 @@ impl Queriable<crates::SqlType> for Crate {
             updated_at: updated_at,
             created_at: created_at,
             downloads: downloads,
+            unique_downloads: unique_downloads,
             max_version: max_version,
             description: description,
             homepage: homepage,
@@ -147,6 +146,7 @@ pub struct EncodableCrate {
     pub versions: Option<Vec<i32>>,
     pub created_at: String,
     pub downloads: i32,
+    pub unique_downloads: i32,
     pub max_version: String,
     pub description: Option<String>,
     pub homepage: Option<String>,
@@ -165,15 +165,39 @@ pub struct CrateLinks {
     pub reverse_dependencies: String,
 }
 
+// Blacklist the current set of crates in the rust distribution
+const RESERVED: &'static str = include_str!("reserved_crates.txt");
+
+/// Crate names are compared case insensitively and without regard to `-`
+/// versus `_`, this is the Rust version of the `canon_crate_name` SQL
+/// function.
+pub fn canonical_name(name: &str) -> String {
+    name.to_lowercase().replace("-", "_")
+}
+
 impl Crate {
     pub fn find_by_name(conn: &GenericConnection,
                         name: &str) -> CargoResult<Crate> {
         let stmt = try!(conn.prepare("SELECT * FROM crates \
                                       WHERE canon_crate_name(name) =
                                             canon_crate_name($1) LIMIT 1"));
+        if let Some(row) = try!(stmt.query(&[&name])).into_iter().next() {
+            return Ok(Model::from_row(&row))
+        }
+        let krate = try!(Crate::find_by_alias(conn, name));
+        krate.chain_error(|| NotFound)
+    }
+
+    /// Finds the crate which was previously known as `name`, if any.
+    pub fn find_by_alias(conn: &GenericConnection,
+                         name: &str) -> CargoResult<Option<Crate>> {
+        let stmt = try!(conn.prepare("SELECT crates.* FROM crates
+                                      INNER JOIN crate_aliases
+                                         ON crate_aliases.crate_id = crates.id
+                                      WHERE canon_crate_name(crate_aliases.name) =
+                                            canon_crate_name($1) LIMIT 1"));
         let row = try!(stmt.query(&[&name])).into_iter().next();
-        let row = try!(row.chain_error(|| NotFound));
-        Ok(Model::from_row(&row))
+        Ok(row.map(|row| Model::from_row(&row)))
     }
 
     pub fn find_or_insert(conn: &GenericConnection,
@@ -235,13 +259,16 @@ impl Crate {
             None => {}
         }
 
-        // Blacklist the current set of crates in the rust distribution
-        const RESERVED: &'static str = include_str!("reserved_crates.txt");
-
         if RESERVED.lines().any(|krate| name == krate) {
             return Err(human("cannot upload a crate with a reserved name"))
         }
 
+        // Old names of renamed crates stay reserved for that crate
+        if let Some(krate) = try!(Crate::find_by_alias(conn, name)) {
+            return Err(human(format!("crate `{}` has been renamed to `{}`",
+                                     name, krate.name)))
+        }
+
         let stmt = try!(conn.prepare("INSERT INTO crates
                                       (name, user_id, created_at,
                                        updated_at, downloads, max_version,
@@ -308,6 +335,69 @@ impl Crate {
             name.chars().all(|c| c.is_ascii())
     }
 
+    /// Renames this crate to `new_name`, keeping the old name reserved as
+    /// an alias which resolves to this crate.
+    pub fn rename(&mut self, conn: &GenericConnection,
+                  new_name: &str) -> CargoResult<()> {
+        if !Crate::valid_name(new_name) {
+            return Err(human(format!("invalid crate name: `{}`", new_name)))
+        }
+        if RESERVED.lines().any(|krate| new_name == krate) {
+            return Err(human("cannot rename a crate to a reserved name"))
+        }
+
+        if new_name == self.name { return Ok(()) }
+        if canonical_name(new_name) != canonical_name(&self.name) {
+            // The new name may only be taken by one of our own old names.
+            let stmt = try!(conn.prepare("SELECT 1 FROM crates
+                                          WHERE canon_crate_name(name) =
+                                                canon_crate_name($1)"));
+            let taken = try!(stmt.query(&[&new_name])).iter().next().is_some();
+            let taken = taken || match try!(Crate::find_by_alias(conn, new_name)) {
+                Some(krate) => krate.id != self.id,
+                None => false,
+            };
+            if taken {
+                return Err(human(format!("crate name `{}` is already taken",
+                                         new_name)))
+            }
+        }
+
+        // Every rename is recorded, even if only the case changed, as the
+        // versions published before it are stored under the old name.
+        try!(conn.execute("INSERT INTO crate_renames (crate_id, name, created_at)
+                           VALUES ($1, $2, $3)",
+                          &[&self.id, &self.name, &::now()]));
+
+        // The old name stays reserved as an alias, unless it already is one
+        // from an earlier rename. A concurrent rename of another crate can
+        // still take the same name, which the unique indexes on
+        // `crate_aliases` and `crates` catch.
+        let taken = || human(format!("crate name `{}` is already taken", new_name));
+        let reserved = canonical_name(new_name) == canonical_name(&self.name) ||
+                       try!(Crate::find_by_alias(conn, &self.name)).is_some();
+        if !reserved {
+            match conn.execute("INSERT INTO crate_aliases (crate_id, name, created_at)
+                                VALUES ($1, $2, $3)",
+                               &[&self.id, &self.name, &::now()]) {
+                Err(PgError::Db(ref e)) if *e.code() == SqlState::UniqueViolation => {
+                    return Err(taken())
+                }
+                r => { try!(r); }
+            }
+        }
+        match conn.execute("UPDATE crates SET name = $1, updated_at = $2
+                            WHERE id = $3",
+                           &[&new_name, &::now(), &self.id]) {
+            Err(PgError::Db(ref e)) if *e.code() == SqlState::UniqueViolation => {
+                return Err(taken())
+            }
+            r => { try!(r); }
+        }
+        self.name = new_name.to_string();
+        Ok(())
+    }
+
     pub fn valid_feature_name(name: &str) -> bool {
         let mut parts = name.split('/');
         match parts.next() {
@@ -324,7 +414,8 @@ impl Crate {
 
     pub fn encodable(self, versions: Option<Vec<i32>>) -> EncodableCrate {
         let Crate {
-            name, created_at, updated_at, downloads, max_version, description,
+            name, created_at, updated_at, downloads, unique_downloads,
+            max_version, description,
             homepage, documentation, keywords, license, repository,
             readme: _, id: _, user_id: _,
         } = self;
@@ -338,6 +429,7 @@ impl Crate {
             updated_at: ::encode_time(updated_at),
             created_at: ::encode_time(created_at),
             downloads: downloads,
+            unique_downloads: unique_downloads,
             versions: versions,
             max_version: max_version.to_string(),
             documentation: documentation,
@@ -393,7 +485,7 @@ impl Crate {
                      login: &str) -> CargoResult<()> {
         let owner = match Owner::find_by_login(conn, login) {
             Ok(owner @ Owner::User(_)) => { owner }
-            Ok(Owner::Team(team)) => if try!(team.contains_user(app, req_user)) {
+            Ok(Owner::Team(team)) => if try!(team.contains_user(app, conn, req_user)) {
                 Owner::Team(team)
             } else {
                 return Err(human(format!("only members of {} can add it as \
@@ -442,10 +534,41 @@ impl Crate {
         Ok(())
     }
 
-    pub fn s3_path(&self, version: &str) -> String {
+    pub fn storage_path(&self, version: &str) -> String {
         format!("/crates/{}/{}-{}.crate", self.name, self.name, version)
     }
 
+    /// The path in storage of the `.crate` file for `version`, which is
+    /// stored under the name this crate had when the version was published.
+    pub fn version_storage_path(&self, conn: &GenericConnection,
+                                version: &Version) -> CargoResult<String> {
+        let aliases = try!(self.aliases(conn));
+        let name = self.published_name(&aliases, version);
+        Ok(format!("/crates/{}/{}-{}.crate", name, name, version.num))
+    }
+
+    /// The names this crate was renamed away from, oldest first, along with
+    /// when each rename happened. A name appears more than once if the crate
+    /// was renamed back to it.
+    pub fn aliases(&self, conn: &GenericConnection)
+                   -> CargoResult<Vec<(String, Timespec)>> {
+        let stmt = try!(conn.prepare("SELECT name, created_at FROM crate_renames
+                                      WHERE crate_id = $1
+                                      ORDER BY created_at ASC, id ASC"));
+        let rows = try!(stmt.query(&[&self.id]));
+        Ok(rows.iter().map(|r| (r.get("name"), r.get("created_at"))).collect())
+    }
+
+    /// The name this crate had when `version` was published, given the
+    /// crate's `aliases`. The version's tarball and index entry use this
+    /// name, so they stay under it after the crate is renamed.
+    pub fn published_name<'a>(&'a self, aliases: &'a [(String, Timespec)],
+                              version: &Version) -> &'a str {
+        aliases.iter().find(|&&(_, renamed_at)| renamed_at > version.created_at)
+               .map(|&(ref name, _)| &name[..])
+               .unwrap_or(&self.name)
+    }
+
     pub fn add_version(&mut self,
                        conn: &GenericConnection,
                        ver: &semver::Version,
@@ -481,10 +604,14 @@ impl Crate {
     }
 
     /// Returns (dependency, dependent crate name)
+    /// Returns a page of the crates depending on this one, ordered by name,
+    /// starting after the crate named `after` if given, along with the total
+    /// number of such crates.
     pub fn reverse_dependencies(&self,
                                 conn: &GenericConnection,
                                 offset: i64,
-                                limit: i64)
+                                limit: i64,
+                                after: Option<&str>)
                                 -> CargoResult<(Vec<(Dependency, String)>, i64)> {
         let select_sql = "
               FROM dependencies
@@ -495,18 +622,23 @@ impl Crate {
               WHERE dependencies.crate_id = $1
                 AND versions.num = crates.max_version
         ";
+        let mut args = vec![&self.id as &ToSql, &offset, &limit];
+        let seek_sql = match after {
+            Some(ref name) => { args.push(name); "AND crates.name > $4" }
+            None => "",
+        };
         let fetch_sql = format!("SELECT DISTINCT ON (crate_name)
                                         dependencies.*,
                                         crates.name AS crate_name
-                                        {}
+                                        {} {}
                                ORDER BY crate_name ASC
                                  OFFSET $2
-                                  LIMIT $3", select_sql);
+                                  LIMIT $3", select_sql, seek_sql);
         let count_sql = format!("SELECT COUNT(DISTINCT(crates.id)) {}",
                                 select_sql);
 
         let stmt = try!(conn.prepare(&fetch_sql));
-        let vec: Vec<_> = try!(stmt.query(&[&self.id, &offset, &limit]))
+        let vec: Vec<_> = try!(stmt.query(&args))
                                    .iter().map(|r| {
             (Model::from_row(&r), r.get("crate_name"))
         }).collect();
@@ -528,6 +660,7 @@ impl Model for Crate {
             updated_at: row.get("updated_at"),
             created_at: row.get("created_at"),
             downloads: row.get("downloads"),
+            unique_downloads: row.get("unique_downloads"),
             description: row.get("description"),
             documentation: row.get("documentation"),
             homepage: row.get("homepage"),
@@ -549,123 +682,224 @@ pub fn index(req: &mut Request) -> CargoResult<Response> {
     let conn = try!(req.tx());
     let (offset, limit) = try!(req.pagination(10, 100));
     let query = req.query();
-    let sort = query.get("sort").map(|s| &s[..]).unwrap_or("alpha");
-    let sort_sql = match sort {
-        "downloads" => "ORDER BY crates.downloads DESC",
-        _ => "ORDER BY crates.name ASC",
+    let sort = query.get("sort").map(|s| &s[..]);
+    let mut sort_sql = match sort {
+        Some("downloads") => "crates.downloads DESC, crates.id DESC".to_string(),
+        _ => "crates.name ASC".to_string(),
     };
 
-    // Different queries for different parameters.
-    //
-    // Sure wish we had an arel-like thing here...
-    let mut pattern = String::new();
-    let mut id = -1;
-    let (mut needs_id, mut needs_pattern) = (false, false);
-    let mut args = vec![&limit as &ToSql, &offset];
-    let (q, cnt) = query.get("q").map(|query| {
-        args.insert(0, query);
-        ("SELECT crates.* FROM crates,
-                               plainto_tsquery($1) q,
-                               ts_rank_cd(textsearchable_index_col, q) rank
-          WHERE q @@ textsearchable_index_col
-          ORDER BY rank DESC, crates.name ASC
-          LIMIT $2 OFFSET $3".to_string(),
-         "SELECT COUNT(crates.*) FROM crates,
-                                      plainto_tsquery($1) q
-          WHERE q @@ textsearchable_index_col".to_string())
-    }).or_else(|| {
-        query.get("letter").map(|letter| {
-            pattern = format!("{}%", letter.chars().next().unwrap()
-                                           .to_lowercase().collect::<String>());
-            needs_pattern = true;
-            (format!("SELECT * FROM crates WHERE canon_crate_name(name) \
-                      LIKE $1 {} LIMIT $2 OFFSET $3", sort_sql),
-             "SELECT COUNT(*) FROM crates WHERE canon_crate_name(name) \
-              LIKE $1".to_string())
-        })
-    }).or_else(|| {
-        query.get("keyword").map(|kw| {
-            args.insert(0, kw);
-            let base = "FROM crates
-                        INNER JOIN crates_keywords
-                                ON crates.id = crates_keywords.crate_id
-                        INNER JOIN keywords
-                                ON crates_keywords.keyword_id = keywords.id
-                        WHERE lower(keywords.keyword) = lower($1)";
-            (format!("SELECT crates.* {} {} LIMIT $2 OFFSET $3", base, sort_sql),
-             format!("SELECT COUNT(crates.*) {}", base))
-        })
-    }).or_else(|| {
-        query.get("user_id").and_then(|s| s.parse::<i32>().ok()).map(|user_id| {
-            id = user_id;
-            needs_id = true;
-            (format!("SELECT crates.* FROM crates
-                       INNER JOIN crate_owners
-                          ON crate_owners.crate_id = crates.id
-                       WHERE crate_owners.owner_id = $1
-                       AND crate_owners.owner_kind = {} {}
-                      LIMIT $2 OFFSET $3",
-                     OwnerKind::User as i32, sort_sql),
-             format!("SELECT COUNT(crates.*) FROM crates
-               INNER JOIN crate_owners
-                  ON crate_owners.crate_id = crates.id
-               WHERE crate_owners.owner_id = $1 \
-                 AND crate_owners.owner_kind = {}",
-                 OwnerKind::User as i32))
-        })
-    }).or_else(|| {
-        query.get("following").map(|_| {
-            needs_id = true;
-            (format!("SELECT crates.* FROM crates
-                      INNER JOIN follows
-                         ON follows.crate_id = crates.id AND
-                            follows.user_id = $1
-                      {} LIMIT $2 OFFSET $3", sort_sql),
-             "SELECT COUNT(crates.*) FROM crates
-              INNER JOIN follows
-                 ON follows.crate_id = crates.id AND
-                    follows.user_id = $1".to_string())
-        })
-    }).unwrap_or_else(|| {
-        (format!("SELECT * FROM crates {} LIMIT $1 OFFSET $2",
-                 sort_sql),
-         "SELECT COUNT(*) FROM crates".to_string())
+    // Every filter which is given must match. An empty search matches
+    // everything, the same as not searching.
+    let mut filter = CrateFilter::new();
+    let mut ranking_join = "";
+    let search = query.get("q").and_then(|q| {
+        if q.trim().is_empty() { None } else { Some(q) }
     });
+    if let Some(search) = search {
+        let q = filter.arg(search.clone());
+        filter.join(format!("CROSS JOIN plainto_tsquery({}) q", q));
+        // A pattern rather than comparing the start of the name, so that the
+        // `text_pattern_ops` index on canonical names can be used.
+        let prefix = filter.arg(format!("{}%", like_escape(&canonical_name(search))));
+        let name_prefix = format!("canon_crate_name(crates.name) LIKE {}",
+                                  prefix);
+        filter.filter(format!("(q @@ textsearchable_index_col OR {})",
+                              name_prefix));
+
+        // Crates named exactly what was searched for come first, followed by
+        // those whose names start with it. The rest are ordered by `sort`,
+        // which by default is how well the text matches, scaled up by the
+        // crate's downloads over the last 90 days and by how recently it was
+        // updated.
+        if sort.is_none() {
+            ranking_join = "LEFT JOIN (SELECT crate_id, SUM(downloads) AS downloads
+                                         FROM crate_downloads
+                                        WHERE date > now() - interval '90 days'
+                                        GROUP BY crate_id) recent
+                                   ON recent.crate_id = crates.id";
+            sort_sql = "ts_rank_cd(textsearchable_index_col, q)
+                          * ln(2 + COALESCE(recent.downloads, 0)::float)
+                          * (1 + 1 / (1 + EXTRACT(EPOCH FROM now() - crates.updated_at)
+                                          / (365 * 24 * 60 * 60))) DESC,
+                        crates.name ASC".to_string();
+        }
+        sort_sql = format!("canon_crate_name(crates.name) = canon_crate_name({}) DESC,
+                            {} DESC, {}", q, name_prefix, sort_sql);
+    }
+    if let Some(letter) = query.get("letter") {
+        let pattern = format!("{}%", letter.chars().next().unwrap_or('%')
+                                           .to_lowercase().collect::<String>());
+        let pattern = filter.arg(pattern);
+        filter.filter(format!("canon_crate_name(crates.name) LIKE {}", pattern));
+    }
+    if let Some(kw) = query.get("keyword") {
+        let kw = filter.arg(kw.clone());
+        filter.filter(format!("EXISTS (SELECT 1 FROM crates_keywords
+                                        INNER JOIN keywords
+                                                ON crates_keywords.keyword_id = keywords.id
+                                        WHERE crates_keywords.crate_id = crates.id
+                                          AND lower(keywords.keyword) = lower({}))",
+                              kw));
+    }
+    if let Some(user_id) = query.get("user_id").and_then(|s| s.parse::<i32>().ok()) {
+        let user_id = filter.arg(user_id);
+        let kind = filter.arg(OwnerKind::User as i32);
+        filter.filter(format!("EXISTS (SELECT 1 FROM crate_owners
+                                        WHERE crate_owners.crate_id = crates.id
+                                          AND crate_owners.owner_id = {}
+                                          AND crate_owners.owner_kind = {})",
+                              user_id, kind));
+    }
+    if query.contains_key("following") {
+        let user_id = filter.arg(try!(req.user()).id);
+        filter.filter(format!("EXISTS (SELECT 1 FROM follows
+                                        WHERE follows.crate_id = crates.id
+                                          AND follows.user_id = {})", user_id));
+    }
+    if let Some(license) = query.get("license") {
+        // Matches either the whole license or one of the alternatives in a
+        // license like `MIT/Apache-2.0` or `MIT OR Apache-2.0`.
+        let license = filter.arg(license.to_lowercase());
+        filter.filter(format!("(lower(crates.license) = {l} OR
+                                {l} = ANY(regexp_split_to_array(lower(crates.license),
+                                                                '\\s*/\\s*|\\s+or\\s+')))",
+                              l = license));
+    }
+    if let Some(since) = query.get("updated_since") {
+        let since = try!(time::strptime(since, "%Y-%m-%dT%H:%M:%SZ").or_else(|_| {
+            time::strptime(since, "%Y-%m-%d")
+        }).map_err(|_| {
+            human("invalid `updated_since`, expected a date like `2015-12-31`")
+        }));
+        let since = filter.arg(since.to_timespec());
+        filter.filter(format!("crates.updated_at >= {}", since));
+    }
+    if let Some(has) = query.get("has_repository") {
+        filter.filter(match &has[..] {
+            "true" | "1" => "crates.repository IS NOT NULL".to_string(),
+            "false" | "0" => "crates.repository IS NULL".to_string(),
+            _ => return Err(human("invalid `has_repository`, expected `true` \
+                                   or `false`")),
+        });
+    }
 
-    if needs_id {
-        if id == -1 {
-            id = try!(req.user()).id;
+    // Later pages can start after the last crate seen instead of skipping
+    // over the earlier pages, unless searching, as search results are ranked
+    // by more than the crates themselves.
+    let searching = search.is_some();
+    let num_filter_args = filter.args.len();
+    let mut seek_sql = String::new();
+    if searching {
+        if query.contains_key("seek") {
+            return Err(human("cannot use `seek` when searching, use `page` \
+                              instead"))
+        }
+    } else if sort == Some("downloads") {
+        if let Some((downloads, id)) = try!(req.seek::<(i32, i32)>()) {
+            seek_sql = format!("(crates.downloads, crates.id) < ({}, {})",
+                               filter.arg(downloads), filter.arg(id));
         }
-        args.insert(0, &id);
-    } else if needs_pattern {
-        args.insert(0, &pattern);
+    } else if let Some(name) = try!(req.seek::<String>()) {
+        seek_sql = format!("crates.name > {}", filter.arg(name));
     }
 
     // Collect all the crates
+    let mut args = filter.args();
+    let q = format!("SELECT crates.* {} ORDER BY {} LIMIT ${} OFFSET ${}",
+                    filter.from_sql(ranking_join, &seek_sql), sort_sql,
+                    args.len() + 1, args.len() + 2);
+    args.push(&limit);
+    args.push(&offset);
     let stmt = try!(conn.prepare(&q));
-    let mut crates = Vec::new();
-    for row in try!(stmt.query(&args)) {
-        let krate: Crate = Model::from_row(&row);
-        crates.push(krate.encodable(None));
-    }
+    let crates = try!(stmt.query(&args)).iter().map(|row| {
+        Model::from_row(&row)
+    }).collect::<Vec<Crate>>();
 
     // Query for the total count of crates
+    let cnt = format!("SELECT COUNT(crates.*) {}", filter.from_sql("", ""));
     let stmt = try!(conn.prepare(&cnt));
-    let args = if args.len() > 2 {&args[..1]} else {&args[..0]};
-    let row = try!(stmt.query(args)).into_iter().next().unwrap();
+    let row = try!(stmt.query(&filter.args()[..num_filter_args]))
+                  .into_iter().next().unwrap();
     let total = row.get(0);
 
+    let next_page = match crates.last() {
+        Some(..) if (crates.len() as i64) < limit => None,
+        None => None,
+        Some(..) if searching => Some(req.next_page(None)),
+        Some(last) if sort == Some("downloads") => {
+            Some(req.next_page(Some(encode_seek(&(last.downloads, last.id)))))
+        }
+        Some(last) => Some(req.next_page(Some(encode_seek(&last.name)))),
+    };
+    let crates = crates.into_iter().map(|c| c.encodable(None)).collect();
+
     #[derive(RustcEncodable)]
     struct R { crates: Vec<EncodableCrate>, meta: Meta }
     #[derive(RustcEncodable)]
-    struct Meta { total: i64 }
+    struct Meta { total: i64, next_page: Option<String> }
 
     Ok(req.json(&R {
         crates: crates,
-        meta: Meta { total: total },
+        meta: Meta { total: total, next_page: next_page },
     }))
 }
 
+/// Escapes the characters which have a special meaning in a `LIKE` pattern.
+fn like_escape(s: &str) -> String {
+    s.replace("\\", "\\\\").replace("%", "\\%").replace("_", "\\_")
+}
+
+/// The conditions on `GET /crates`, which are combined into a single query.
+///
+/// Which conditions apply is only known at runtime, and each one changes the
+/// type of a yaqb query, so the SQL is put together here instead.
+struct CrateFilter {
+    joins: Vec<String>,
+    conditions: Vec<String>,
+    args: Vec<Box<ToSql>>,
+}
+
+impl CrateFilter {
+    fn new() -> CrateFilter {
+        CrateFilter { joins: Vec::new(), conditions: Vec::new(), args: Vec::new() }
+    }
+
+    /// Adds a parameter to the query, returning the placeholder for it.
+    fn arg<T: ToSql + 'static>(&mut self, arg: T) -> String {
+        self.args.push(Box::new(arg));
+        format!("${}", self.args.len())
+    }
+
+    fn join(&mut self, join: String) {
+        self.joins.push(join);
+    }
+
+    fn filter(&mut self, condition: String) {
+        self.conditions.push(condition);
+    }
+
+    fn args(&self) -> Vec<&ToSql> {
+        self.args.iter().map(|a| &**a).collect()
+    }
+
+    /// The `FROM` and `WHERE` clauses of the query, with `extra_join` added
+    /// to the joins and `extra_condition`, if not empty, to the conditions.
+    fn from_sql(&self, extra_join: &str, extra_condition: &str) -> String {
+        let mut conditions = self.conditions.iter().map(|c| &c[..])
+                                 .collect::<Vec<_>>();
+        if !extra_condition.is_empty() {
+            conditions.push(extra_condition);
+        }
+        let mut sql = format!("FROM crates {} {}", self.joins.join(" "),
+                              extra_join);
+        if !conditions.is_empty() {
+            sql.push_str(" WHERE ");
+            sql.push_str(&conditions.join(" AND "));
+        }
+        sql
+    }
+}
+
 /// Handles the `GET /summary` route.
 pub fn summary(req: &mut Request) -> CargoResult<Response> {
     use self::crates::dsl::*;
@@ -711,6 +945,9 @@ pub fn show(req: &mut Request) -> CargoResult<Response> {
     let name = &req.params()["crate_id"];
     let conn = try!(req.tx());
     let krate = try!(Crate::find_by_name(conn, &name));
+    if canonical_name(&krate.name) != canonical_name(name) {
+        return Ok(req.redirect(format!("/api/v1/crates/{}", krate.name)))
+    }
     let versions = try!(krate.versions(conn));
     let ids = versions.iter().map(|v| v.id).collect();
     let kws = try!(krate.keywords(conn));
@@ -744,8 +981,27 @@ pub fn new(req: &mut Request) -> CargoResult<Response> {
                                      .unwrap_or(&[]);
     let keywords = keywords.iter().map(|k| k[..].to_string()).collect::<Vec<_>>();
 
+    // Read the tarball and make sure that it contains what it claims to
+    let tarball = {
+        let length = try!(read_le_u32(req.body()));
+        let mut body = LimitErrorReader::new(req.body(),
+                                             app.config.max_upload_size);
+        let mut tarball = Vec::new();
+        try!(body.read_to_end(&mut tarball));
+        if tarball.len() as u64 != length as u64 {
+            return Err(human(format!("expected a tarball of {} bytes but \
+                                      received {} bytes", length,
+                                     tarball.len())))
+        }
+        tarball
+    };
+    let contents = try!(tarball::verify(&tarball, name, vers));
+
+    // Everything from here on is undone if publishing fails at any point
+    let mut publish = try!(Publish::begin(&app, try!(req.tx())));
+
     // Persist the new crate, if it doesn't already exist
-    let mut krate = try!(Crate::find_or_insert(try!(req.tx()), name, user.id,
+    let mut krate = try!(Crate::find_or_insert(publish.conn(), name, user.id,
                                                &new_crate.description,
                                                &new_crate.homepage,
                                                &new_crate.documentation,
@@ -755,83 +1011,55 @@ pub fn new(req: &mut Request) -> CargoResult<Response> {
                                                &new_crate.license,
                                                &new_crate.license_file));
 
-    let owners = try!(krate.owners(try!(req.tx())));
-    if try!(rights(req.app(), &owners, &user)) < Rights::Publish {
+    let owners = try!(krate.owners(publish.conn()));
+    if try!(rights(req, publish.conn(), &owners, &user, Scope::Publish,
+                   name)) < Rights::Publish {
         return Err(human("crate name has already been claimed by \
                           another user"))
     }
 
     if krate.name != name {
-        return Err(human(format!("crate was previously named `{}`", krate.name)))
+        return Err(human(format!("crate was previously named `{}`, it must \
+                                  be renamed before publishing under a \
+                                  new name", krate.name)))
     }
 
     // Persist the new version of this crate
-    let mut version = try!(krate.add_version(try!(req.tx()), vers, &features,
+    let mut version = try!(krate.add_version(publish.conn(), vers, &features,
                                              &new_crate.authors));
 
     // Link this new version to all dependencies
     let mut deps = Vec::new();
     for dep in new_crate.deps.iter() {
-        let (dep, krate) = try!(version.add_dependency(try!(req.tx()), dep));
-        deps.push(dep.git_encode(&krate.name));
+        let (dep, _) = try!(version.add_dependency(publish.conn(), dep));
+        deps.push(dep.git_encode());
     }
 
     // Update all keywords for this crate
-    try!(Keyword::update_crate(try!(req.tx()), &krate, &keywords));
+    try!(Keyword::update_crate(publish.conn(), &krate, &keywords));
 
-    // Upload the crate to S3
-    let mut handle = req.app().handle();
-    let path = krate.s3_path(&vers.to_string());
-    let (resp, cksum) = {
-        let length = try!(read_le_u32(req.body()));
-        let body = LimitErrorReader::new(req.body(), app.config.max_upload_size);
-        let mut body = HashingReader::new(body);
-        let resp = {
-            let s3req = app.bucket.put(&mut handle, &path, &mut body,
-                                       "application/x-tar")
-                                  .content_length(length as usize);
-            try!(s3req.exec().chain_error(|| {
-                internal(format!("failed to upload to S3: `{}`", path))
-            }))
-        };
-        (resp, body.finalize())
-    };
-    if resp.get_code() != 200 {
-        return Err(internal(format!("failed to get a 200 response from S3: {}",
-                                    resp)))
-    }
-
-    // If the git commands fail below, we shouldn't keep the crate on the
-    // server.
-    struct Bomb { app: Arc<App>, path: Option<String>, handle: http::Handle }
-    impl Drop for Bomb {
-        fn drop(&mut self) {
-            match self.path {
-                Some(ref path) => {
-                    let _ = self.app.bucket.delete(&mut self.handle, &path)
-                                .exec();
-                }
-                None => {}
-            }
-        }
+    if let Some(ref readme) = contents.readme {
+        try!(version.set_readme(publish.conn(), readme));
     }
-    let mut bomb = Bomb { app: app.clone(), path: Some(path), handle: handle };
+
+    // Upload the crate to storage
+    let path = krate.storage_path(&vers.to_string());
+    let cksum = try!(publish.upload(&path, &tarball));
+    try!(version.set_checksum(publish.conn(), &cksum));
 
     // Register this crate in our local git repo.
     let git_crate = git::Crate {
         name: name.to_string(),
         vers: vers.to_string(),
-        cksum: cksum.to_hex(),
+        cksum: cksum,
         features: features,
         deps: deps,
         yanked: Some(false),
     };
-    try!(git::add_crate(&**req.app(), &git_crate).chain_error(|| {
-        internal(format!("could not add crate `{}` to the git repo", name))
-    }));
+    try!(publish.add_to_index(git_crate));
 
     // Now that we've come this far, we're committed!
-    bomb.path = None;
+    try!(publish.finish(req));
 
     #[derive(RustcEncodable)]
     struct R { krate: EncodableCrate }
@@ -910,10 +1138,22 @@ sql_function!(canon_crate_name, (a: VarChar) -> VarChar);
 /// Handles the `GET /crates/:crate_id/:version/download` route.
 pub fn download(req: &mut Request) -> CargoResult<Response> {
     use yaqb::expression::dsl::*;
-    use self::version_downloads::downloads;
 
+    if req.app().config.auth_required {
+        try!(req.user());
+    }
     let crate_name = &req.params()["crate_id"];
     let version = &req.params()["version"];
+
+    // Downloads of a renamed crate's old name are sent to the new name.
+    let krate = try!(Crate::find_by_name(try!(req.tx()), crate_name).map_err(|_| {
+        human("crate or version not found")
+    }));
+    if canonical_name(&krate.name) != canonical_name(crate_name) {
+        return Ok(req.redirect(format!("/api/v1/crates/{}/{}/download",
+                                       krate.name, version)))
+    }
+
     let conn = req.new_conn();
 
     let version_id = try!(crates::table.inner_join(versions)
@@ -922,89 +1162,235 @@ pub fn download(req: &mut Request) -> CargoResult<Response> {
         .first(&conn))
         .chain_error(|| human("crate or version not found"));
 
-    // Bump download counts.
-    //
-    // Note that this is *not* an atomic update, and that's somewhat
-    // intentional. It doesn't appear that postgres supports an atomic update of
-    // a counter, so we just do the hopefully "least racy" thing. This is
-    // largely ok because these download counters are just that, counters. No
-    // need to have super high-fidelity counter.
-    //
-    // Also, we only update the counter for *today*, nothing else. We have lots
-    // of other counters, but they're all updated later on via the
-    // update-downloads script.
-    let target = version_downloads::table.filter(version_id.eq(version_id))
-        .filter(date(now()).eq(date(version_downloads::date)));
-    let updated_rows = try!(conn.update_returning_count(&target, downloads.eq(downloads + 1)));
-
-    if updated_rows == 0 {
-        let new_download = NewVersionDownload::new(version_id);
-        try!(conn.insert_returning_count(&version_downloads::table, &[new_download]));
-    }
-
-    // Now that we've done our business, redirect to the actual data.
-    let redirect_url = format!("https://{}/crates/{}/{}-{}.crate",
-                               req.app().bucket.host(),
-                               crate_name, crate_name, version);
-
-    if req.wants_json() {
-        #[derive(RustcEncodable)]
-        struct R { url: String }
-        Ok(req.json(&R{ url: redirect_url }))
+    // Redirect to the actual data, or serve it ourselves if it's stored
+    // locally.
+    let num = try!(semver::Version::parse(version).ok().chain_error(|| NotFound));
+    let version = try!(Version::find_by_num(try!(req.tx()), krate.id, &num));
+    let version = try!(version.chain_error(|| NotFound));
+    let path = try!(krate.version_storage_path(try!(req.tx()), &version));
+    let resp = match req.app().storage.download(&path) {
+        Download::Redirect(url) => {
+            if req.wants_json() {
+                #[derive(RustcEncodable)]
+                struct R { url: String }
+                req.json(&R{ url: url })
+            } else {
+                req.redirect(url)
+            }
+        }
+        Download::File(path) => {
+            try!(serve_file(req, &path, etag(&version.checksum, Some(&*path))))
+        }
+    };
+
+    // Only whole downloads are counted. Ranges are parts of a download, and
+    // a conditional request which will get a 304 from the `ConditionalGet`
+    // middleware already has the file.
+    let partial = resp.status.0 == 206 || resp.status.0 == 416;
+    let etag = resp.headers.get("ETag").and_then(|h| h.first().cloned());
+    if partial || is_not_modified(req, &etag) {
+        return Ok(resp)
+    }
+
+    // Bump the download count for today. The other counters are all updated
+    // later on by the update-downloads script.
+    try!(download::increment(try!(req.tx()), version_id));
+
+    // Also record which client this was, so update-downloads can tell how
+    // many distinct clients downloaded the version today.
+    let user_agent = req.headers().find("User-Agent")
+                        .and_then(|h| h.first().map(|s| s.to_string()))
+                        .unwrap_or(String::new());
+    let today = time::strftime("%Y-%m-%d", &time::now_utc()).unwrap();
+    let fingerprint = download::fingerprint(&req.app().config.fingerprint_secret,
+                                            &today, &client_ip(req), &user_agent);
+    try!(download::record_client(try!(req.tx()), version_id,
+                                 download::client_class(&user_agent),
+                                 &fingerprint));
+    Ok(resp)
+}
+
+/// Whether the client already has the version with `checksum`, according to
+/// its `If-None-Match` header.
+fn is_not_modified(req: &Request, etag: &Option<String>) -> bool {
+    let etag = match *etag {
+        Some(ref etag) => etag,
+        None => return false,
+    };
+    req.headers().find("If-None-Match").map(|values| {
+        values.iter().flat_map(|v| v.split(',')).any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag == etag
+        })
+    }).unwrap_or(false)
+}
+
+/// The `ETag` of a `.crate` file, which is its checksum. Versions published
+/// before checksums were recorded get a weak one made from the size and
+/// modification time of the file instead, if it's stored locally.
+fn etag(checksum: &Option<String>, path: Option<&Path>) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+
+    match (checksum.as_ref(), path) {
+        (Some(checksum), _) => Some(format!("\"{}\"", checksum)),
+        (None, Some(path)) => fs::metadata(path).ok().map(|metadata| {
+            format!("W/\"{}-{}\"", metadata.len(), metadata.mtime())
+        }),
+        (None, None) => None,
+    }
+}
+
+/// The portion of a file requested through a `Range` header.
+#[derive(PartialEq, Debug)]
+enum ByteRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses the value of a `Range` header for a file of `length` bytes.
+///
+/// Only a single range of bytes is supported, anything else (or a header we
+/// don't understand) results in the entire file being served.
+fn parse_range(header: &str, length: u64) -> ByteRange {
+    let mut parts = header.trim().splitn(2, '=');
+    let spec = match (parts.next(), parts.next()) {
+        (Some(unit), Some(spec)) if unit.trim() == "bytes" => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    if spec.contains(',') {
+        return ByteRange::Full
+    }
+    let mut parts = spec.splitn(2, '-');
+    let (start, end) = match (parts.next(), parts.next()) {
+        (Some(start), Some(end)) => (start.trim(), end.trim()),
+        _ => return ByteRange::Full,
+    };
+    let (start, end) = if start.is_empty() {
+        // A suffix range, `bytes=-N`, asks for the last N bytes.
+        match end.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => (length.saturating_sub(n), length.saturating_sub(1)),
+            Err(..) => return ByteRange::Full,
+        }
+    } else {
+        let start = match start.parse::<u64>() {
+            Ok(n) => n,
+            Err(..) => return ByteRange::Full,
+        };
+        let end = if end.is_empty() {
+            length.saturating_sub(1)
+        } else {
+            match end.parse::<u64>() {
+                Ok(n) if n >= start => cmp::min(n, length.saturating_sub(1)),
+                _ => return ByteRange::Full,
+            }
+        };
+        (start, end)
+    };
+    if start >= length {
+        ByteRange::Unsatisfiable
     } else {
-        Ok(req.redirect(redirect_url))
+        ByteRange::Partial(start, end)
     }
 }
 
+/// Responds with the contents of a locally stored `.crate` file, honoring
+/// any `Range` requested. The checksum, if known, is used as the `ETag`.
+fn serve_file(req: &Request, path: &Path, etag: Option<String>)
+              -> CargoResult<Response> {
+    let mut file = try!(File::open(path).ok().chain_error(|| NotFound));
+    let length = try!(file.metadata()).len();
+    let range = req.headers().find("Range").and_then(|h| h.first().cloned())
+                   .map(|h| parse_range(h, length))
+                   .unwrap_or(ByteRange::Full);
+
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(),
+                   vec!["application/x-tar".to_string()]);
+    headers.insert("Accept-Ranges".to_string(), vec!["bytes".to_string()]);
+    if let Some(etag) = etag {
+        headers.insert("ETag".to_string(), vec![etag]);
+    }
+
+    let (status, body): (_, Box<Read + Send>) = match range {
+        ByteRange::Full => {
+            headers.insert("Content-Length".to_string(),
+                           vec![length.to_string()]);
+            ((200, "OK"), Box::new(file))
+        }
+        ByteRange::Partial(start, end) => {
+            try!(file.seek(SeekFrom::Start(start)));
+            headers.insert("Content-Length".to_string(),
+                           vec![(end - start + 1).to_string()]);
+            headers.insert("Content-Range".to_string(),
+                           vec![format!("bytes {}-{}/{}", start, end, length)]);
+            ((206, "Partial Content"), Box::new(file.take(end - start + 1)))
+        }
+        ByteRange::Unsatisfiable => {
+            headers.insert("Content-Length".to_string(), vec!["0".to_string()]);
+            headers.insert("Content-Range".to_string(),
+                           vec![format!("bytes */{}", length)]);
+            ((416, "Requested Range Not Satisfiable"), Box::new(io::empty()))
+        }
+    };
+    Ok(Response {
+        status: status,
+        headers: headers,
+        body: body,
+    })
+}
+
 /// Handles the `GET /crates/:crate_id/downloads` route.
 pub fn downloads(req: &mut Request) -> CargoResult<Response> {
     let crate_name = &req.params()["crate_id"];
+    let query = req.query();
+    let range = try!(DownloadRange::from_query(&query));
     let tx = try!(req.tx());
     let krate = try!(Crate::find_by_name(tx, crate_name));
     let mut versions = try!(krate.versions(tx));
     versions.sort_by(|a, b| b.num.cmp(&a.num));
 
-
-    let to_show = &versions[..cmp::min(5, versions.len())];
+    // Only the five latest versions are shown individually unless all of them
+    // are asked for, with the rest summed up as `extra_downloads`.
+    let to_show = match query.get("versions").map(|s| &s[..]) {
+        None => &versions[..cmp::min(5, versions.len())],
+        Some("all") => &versions[..],
+        Some(..) => return Err(human("invalid `versions`, expected `all`")),
+    };
     let ids = to_show.iter().map(|i| i.id).collect::<Vec<_>>();
 
-    let cutoff_date = ::now() + Duration::days(-90);
-    let stmt = try!(tx.prepare("SELECT * FROM version_downloads
-                                 WHERE date > $1
-                                   AND version_id = ANY($2)
-                                 ORDER BY date ASC"));
-    let mut downloads = Vec::new();
-    for row in try!(stmt.query(&[&cutoff_date, &Slice(&ids)])) {
-        let download: VersionDownload = Model::from_row(&row);
-        downloads.push(download.encodable());
-    }
-
-    let stmt = try!(tx.prepare("\
-          SELECT COALESCE(to_char(DATE(version_downloads.date), 'YYYY-MM-DD'), '') AS date,
-                 SUM(version_downloads.downloads) AS downloads
-            FROM version_downloads
-           INNER JOIN versions ON
-                 version_id = versions.id
-           WHERE version_downloads.date > $1
-             AND versions.crate_id = $2
-             AND NOT (versions.id = ANY($3))
-        GROUP BY DATE(version_downloads.date)
-        ORDER BY DATE(version_downloads.date) ASC"));
-    let mut extra = Vec::new();
-    for row in try!(stmt.query(&[&cutoff_date, &krate.id, &Slice(&ids)])) {
-        extra.push(ExtraDownload {
-            downloads: row.get("downloads"),
-            date: row.get("date")
-        });
+    let downloads = try!(range.version_downloads(tx, &ids));
+    let extra = try!(range.other_downloads(tx, krate.id, &ids));
+    let total = try!(range.crate_downloads(tx, krate.id));
+
+    if query.get("format").map(|s| &s[..]) == Some("csv") {
+        let nums = to_show.iter().map(|v| (v.id, v.num.to_string()))
+                          .collect::<HashMap<_, _>>();
+        let mut rows = vec![vec!["date".to_string(), "version".to_string(),
+                                 "downloads".to_string(),
+                                 "unique_downloads".to_string()]];
+        rows.extend(downloads.iter().map(|d| {
+            vec![d.date[..10].to_string(), nums[&d.version].clone(),
+                 d.downloads.to_string(), d.unique_downloads.to_string()]
+        }));
+        // Downloads of versions which weren't shown individually have no
+        // version.
+        rows.extend(extra.iter().map(|d| {
+            vec![d.date.clone(), String::new(), d.downloads.to_string(),
+                 d.unique_downloads.to_string()]
+        }));
+        return Ok(csv_response(&rows))
     }
 
-    #[derive(RustcEncodable)]
-    struct ExtraDownload { date: String, downloads: i64 }
     #[derive(RustcEncodable)]
     struct R { version_downloads: Vec<EncodableVersionDownload>, meta: Meta }
     #[derive(RustcEncodable)]
-    struct Meta { extra_downloads: Vec<ExtraDownload> }
-    let meta = Meta { extra_downloads: extra };
+    struct Meta {
+        extra_downloads: Vec<DownloadTotal>,
+        total_downloads: Vec<DownloadTotal>,
+    }
+    let meta = Meta { extra_downloads: extra, total_downloads: total };
     Ok(req.json(&R{ version_downloads: downloads, meta: meta }))
 }
 
@@ -1077,10 +1463,17 @@ pub fn owners(req: &mut Request) -> CargoResult<Response> {
     let krate = try!(Crate::find_by_name(tx, crate_name));
     let owners = try!(krate.owners(tx));
     let owners = owners.into_iter().map(|o| o.encodable()).collect();
+    let mut invitations = Vec::new();
+    for invitation in try!(CrateOwnerInvitation::pending_for_crate(tx, &krate)) {
+        invitations.push(try!(invitation.encodable(tx)));
+    }
 
     #[derive(RustcEncodable)]
-    struct R { users: Vec<EncodableOwner> }
-    Ok(req.json(&R{ users: owners }))
+    struct R {
+        users: Vec<EncodableOwner>,
+        pending_invitations: Vec<EncodableCrateOwnerInvitation>,
+    }
+    Ok(req.json(&R{ users: owners, pending_invitations: invitations }))
 }
 
 /// Handles the `PUT /crates/:crate_id/owners` route.
@@ -1093,6 +1486,43 @@ pub fn remove_owners(req: &mut Request) -> CargoResult<Response> {
     modify_owners(req, false)
 }
 
+/// Handles the `PUT /crates/:crate_id/rename` route.
+pub fn rename(req: &mut Request) -> CargoResult<Response> {
+    let mut body = String::new();
+    try!(req.body().read_to_string(&mut body));
+    let (user, mut krate) = try!(user_and_crate(req));
+    let tx = try!(req.tx());
+    let owners = try!(krate.owners(tx));
+
+    match try!(rights(req, tx, &owners, &user, Scope::Owners, &krate.name)) {
+        Rights::Full => {}
+        Rights::Publish => {
+            return Err(human("team members don't have permission to rename \
+                              crates"));
+        }
+        Rights::None => {
+            return Err(human("only owners have permission to rename crates"));
+        }
+    }
+
+    #[derive(RustcDecodable)]
+    struct Request { name: String }
+
+    let request: Request = try!(json::decode(&body).map_err(|_| {
+        human("invalid json request")
+    }));
+
+    // Versions published before the rename stay in the index file of the old
+    // name, which their tarballs use, and the new name gets its own file.
+    let old_name = krate.name.clone();
+    try!(krate.rename(tx, &request.name));
+    try!(Job::Rename { old: old_name, new: krate.name.clone() }.enqueue(tx));
+
+    #[derive(RustcEncodable)]
+    struct R { krate: EncodableCrate }
+    Ok(req.json(&R { krate: krate.encodable(None) }))
+}
+
 fn modify_owners(req: &mut Request, add: bool) -> CargoResult<Response> {
     let mut body = String::new();
     try!(req.body().read_to_string(&mut body));
@@ -1100,7 +1530,7 @@ fn modify_owners(req: &mut Request, add: bool) -> CargoResult<Response> {
     let tx = try!(req.tx());
     let owners = try!(krate.owners(tx));
 
-    match try!(rights(req.app(), &owners, &user)) {
+    match try!(rights(req, tx, &owners, &user, Scope::Owners, &krate.name)) {
         Rights::Full => {} // Yes!
         Rights::Publish => {
             return Err(human("team members don't have permission to modify owners"));
@@ -1125,25 +1555,52 @@ fn modify_owners(req: &mut Request, add: bool) -> CargoResult<Response> {
         human("invalid json request")
     }));
 
+    let mut msgs = Vec::new();
     for login in &logins {
         if add {
             if owners.iter().any(|owner| owner.login() == *login) {
                 return Err(human(format!("`{}` is already an owner", login)))
             }
-            try!(krate.owner_add(req.app(), tx, &user, &login));
+            // Users have to accept before becoming owners, while teams can
+            // only be added by their own members.
+            if login.contains(":") {
+                try!(krate.owner_add(req.app(), tx, &user, &login));
+                msgs.push(format!("team `{}` has been added as an owner of \
+                                   crate `{}`", login, krate.name));
+            } else {
+                let invitee = try!(User::find_by_login(tx, login).map_err(|_| {
+                    human(format!("could not find user with login `{}`", login))
+                }));
+                try!(CrateOwnerInvitation::create(tx, &user, &krate, &invitee));
+                msgs.push(format!("user `{}` has been invited to be an owner \
+                                   of crate `{}`", login, krate.name));
+            }
         } else {
             // Removing the team that gives you rights is prevented because
             // team members only have Rights::Publish
             if *login == user.gh_login {
                 return Err(human("cannot remove yourself as an owner"))
             }
+            // Also withdraw the invitation of someone who hasn't accepted yet,
+            // and the ones sent by an owner who is being removed.
+            if let Ok(invitee) = User::find_by_login(tx, login) {
+                try!(CrateOwnerInvitation::delete_sent_by(tx, &invitee, &krate));
+                let invitation = try!(CrateOwnerInvitation::find(tx, invitee.id,
+                                                                 krate.id));
+                if let Some(invitation) = invitation {
+                    try!(invitation.delete(tx));
+                    if !owners.iter().any(|owner| owner.login() == *login) {
+                        continue
+                    }
+                }
+            }
             try!(krate.owner_remove(tx, &user, &login));
         }
     }
 
     #[derive(RustcEncodable)]
-    struct R { ok: bool }
-    Ok(req.json(&R{ ok: true }))
+    struct R { ok: bool, msg: String }
+    Ok(req.json(&R{ ok: true, msg: msgs.join(", ") }))
 }
 
 /// Handles the `GET /crates/:crate_id/reverse_dependencies` route.
@@ -1153,7 +1610,16 @@ pub fn reverse_dependencies(req: &mut Request) -> CargoResult<Response> {
     let krate = try!(Crate::find_by_name(conn, &name));
     let tx = try!(req.tx());
     let (offset, limit) = try!(req.pagination(10, 100));
-    let (rev_deps, total) = try!(krate.reverse_dependencies(tx, offset, limit));
+    let after = try!(req.seek::<String>());
+    let after = after.as_ref().map(|s| &s[..]);
+    let (rev_deps, total) = try!(krate.reverse_dependencies(tx, offset, limit,
+                                                            after));
+    let next_page = match rev_deps.last() {
+        Some(&(_, ref name)) if rev_deps.len() as i64 == limit => {
+            Some(req.next_page(Some(encode_seek(name))))
+        }
+        _ => None,
+    };
     let rev_deps = rev_deps.into_iter().map(|(dep, crate_name)| {
         dep.encodable(&crate_name)
     }).collect();
@@ -1161,6 +1627,9 @@ pub fn reverse_dependencies(req: &mut Request) -> CargoResult<Response> {
     #[derive(RustcEncodable)]
     struct R { dependencies: Vec<EncodableDependency>, meta: Meta }
     #[derive(RustcEncodable)]
-    struct Meta { total: i64 }
-    Ok(req.json(&R{ dependencies: rev_deps, meta: Meta { total: total } }))
+    struct Meta { total: i64, next_page: Option<String> }
+    Ok(req.json(&R{
+        dependencies: rev_deps,
+        meta: Meta { total: total, next_page: next_page },
+    }))
 }
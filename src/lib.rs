@@ -13,12 +13,15 @@ extern crate git2;
 extern crate license_exprs;
 extern crate oauth2;
 extern crate openssl;
+extern crate pulldown_cmark;
 extern crate r2d2;
 extern crate r2d2_postgres;
 extern crate rand;
 extern crate s3;
 extern crate semver;
+extern crate tar;
 extern crate time;
+extern crate toml;
 extern crate url;
 #[macro_use] extern crate yaqb;
 
@@ -35,7 +38,7 @@ extern crate conduit_static;
 pub use app::App;
 pub use config::Config;
 pub use self::dependency::Dependency;
-pub use self::download::{CrateDownload, VersionDownload};
+pub use self::download::CrateDownload;
 pub use self::keyword::Keyword;
 pub use self::krate::Crate;
 pub use self::model::Model;
@@ -48,21 +51,31 @@ use std::error::Error;
 use conduit_router::RouteBuilder;
 use conduit_middleware::MiddlewareBuilder;
 
+use rate_limit::RateLimited;
 use util::{C, R, R404};
 
 pub mod app;
+pub mod background;
 pub mod config;
+pub mod crate_owner_invitation;
 pub mod db;
 pub mod dependency;
 pub mod dist;
 pub mod download;
 pub mod git;
+pub mod index;
 pub mod keyword;
 pub mod krate;
 pub mod model;
 pub mod upload;
 pub mod user;
 pub mod owner;
+pub mod publish;
+pub mod rate_limit;
+pub mod render;
+pub mod storage;
+pub mod tarball;
+pub mod token;
 pub mod util;
 pub mod version;
 pub mod http;
@@ -79,12 +92,15 @@ pub fn middleware(app: Arc<App>) -> MiddlewareBuilder {
 
     api_router.get("/crates", C(krate::index));
     api_router.get("/crates/:crate_id", C(krate::show));
-    api_router.put("/crates/new", C(krate::new));
+    // Publishing is also charged to the "api" group which wraps every route
+    api_router.put("/crates/new", RateLimited { group: "publish",
+                                                handler: C(krate::new) });
     api_router.get("/crates/:crate_id/:version", C(version::show));
     api_router.get("/crates/:crate_id/:version/download", C(krate::download));
     api_router.get("/crates/:crate_id/:version/dependencies", C(version::dependencies));
     api_router.get("/crates/:crate_id/:version/downloads", C(version::downloads));
     api_router.get("/crates/:crate_id/:version/authors", C(version::authors));
+    api_router.get("/crates/:crate_id/:version/readme", C(version::readme));
     api_router.get("/crates/:crate_id/downloads", C(krate::downloads));
     api_router.get("/crates/:crate_id/versions", C(krate::versions));
     api_router.put("/crates/:crate_id/follow", C(krate::follow));
@@ -93,6 +109,7 @@ pub fn middleware(app: Arc<App>) -> MiddlewareBuilder {
     api_router.get("/crates/:crate_id/owners", C(krate::owners));
     api_router.put("/crates/:crate_id/owners", C(krate::add_owners));
     api_router.delete("/crates/:crate_id/owners", C(krate::remove_owners));
+    api_router.put("/crates/:crate_id/rename", C(krate::rename));
     api_router.delete("/crates/:crate_id/:version/yank", C(version::yank));
     api_router.put("/crates/:crate_id/:version/unyank", C(version::unyank));
     api_router.get("/crates/:crate_id/reverse_dependencies", C(krate::reverse_dependencies));
@@ -100,7 +117,12 @@ pub fn middleware(app: Arc<App>) -> MiddlewareBuilder {
     api_router.get("/versions/:version_id", C(version::show));
     api_router.get("/keywords", C(keyword::index));
     api_router.get("/keywords/:keyword_id", C(keyword::show));
-    let api_router = Arc::new(R404(api_router));
+    api_router.get("/teams/:team_id/members", C(owner::members));
+    api_router.put("/teams/:team_id/members", C(owner::add_members));
+    api_router.delete("/teams/:team_id/members", C(owner::remove_members));
+    api_router.put("/teams/:team_id/refresh", C(owner::refresh_membership));
+    let api_router = Arc::new(RateLimited { group: "api",
+                                            handler: R404(api_router) });
 
     let mut router = RouteBuilder::new();
 
@@ -117,8 +139,18 @@ pub fn middleware(app: Arc<App>) -> MiddlewareBuilder {
     router.get("/logout", C(user::logout));
     router.get("/me", C(user::me));
     router.put("/me/reset_token", C(user::reset_token));
+    router.get("/me/tokens", C(token::list));
+    router.put("/me/tokens", C(token::new));
+    router.delete("/me/tokens/:id", C(token::revoke));
     router.get("/me/updates", C(user::updates));
+    router.get("/me/crate_owner_invitations", C(crate_owner_invitation::list));
+    router.put("/me/crate_owner_invitations/:crate_id/accept",
+               C(crate_owner_invitation::accept));
+    router.put("/me/crate_owner_invitations/:crate_id/decline",
+               C(crate_owner_invitation::decline));
     router.get("/summary", C(krate::summary));
+    router.get("/index/config.json", C(index::config));
+    router.get("/index/*path", C(index::file));
 
     let env = app.config.env;
     if env == Env::Development {
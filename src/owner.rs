@@ -1,11 +1,21 @@
+use std::io::prelude::*;
+
+use conduit::{Request, Response};
+use conduit_router::RequestParams;
 use pg::GenericConnection;
+use pg::error::{Error as PgError, SqlState};
 use pg::rows::Row;
+use rustc_serialize::json;
+use time::{Duration, Timespec};
 
 use {Model, User};
-use util::{RequestUtils, CargoResult, ChainError, human};
+use app::{App, RequestApp};
+use db::RequestTransaction;
+use token::Scope;
+use user::RequestUser;
+use util::{RequestUtils, CargoResult, ChainError, human, internal};
 use util::errors::NotFound;
 use http;
-use app::App;
 
 #[repr(u32)]
 pub enum OwnerKind {
@@ -19,16 +29,17 @@ pub enum Owner {
     Team(Team),
 }
 
-/// For now, just a Github Team. Can be upgraded to other teams
-/// later if desirable.
+/// A team which can own crates, whose members are decided by its provider.
 pub struct Team {
-    /// We're assuming these are stable
-    pub github_id: i32,
+    /// The id of the team on Github, for Github teams. We're assuming these
+    /// are stable.
+    pub github_id: Option<i32>,
     /// Unique table id
     pub id: i32,
-    /// "github:org:team"
-    /// An opaque unique ID, that was at one point parsed out to query Github.
-    /// We only query membership with github using the github_id, though.
+    /// "github:org:team" or "local:team"
+    /// An opaque unique ID, whose prefix is the name of the team's provider.
+    /// Github teams were at one point parsed out to query Github, but we only
+    /// query membership with github using the github_id.
     /// This is the only name we should ever talk to Cargo about.
     pub login: String,
     /// Sugary goodness
@@ -48,6 +59,48 @@ pub struct EncodableOwner {
     pub avatar: Option<String>,
 }
 
+/// A source of teams, which decides who their members are.
+///
+/// The provider of a team is picked by the prefix of its login, see
+/// `team_provider`.
+pub trait TeamProvider: Sync {
+    /// Creates the team called `login` in the DB on behalf of `req_user`, who
+    /// has to be one of its members.
+    fn create(&self, app: &App, conn: &GenericConnection, login: &str,
+              req_user: &User) -> CargoResult<Team>;
+
+    /// Whether `user` is currently a member of `team`.
+    fn contains_user(&self, app: &App, conn: &GenericConnection, team: &Team,
+                     user: &User) -> CargoResult<bool>;
+
+    /// Where people can find out more about `team`, if anywhere.
+    fn url(&self, team: &Team) -> Option<String>;
+
+    /// Whether the members of this provider's teams are managed through the
+    /// registry rather than elsewhere.
+    fn manages_members(&self) -> bool;
+}
+
+/// Teams on Github, named `github:org:team`.
+pub struct Github;
+
+/// Teams kept by the registry itself, named `local:team`. Their members are
+/// stored in the `team_members` table and managed through the API by the
+/// members themselves.
+pub struct Native;
+
+static GITHUB: Github = Github;
+static NATIVE: Native = Native;
+
+/// Finds the provider of the teams whose logins start with `prefix:`.
+pub fn team_provider(prefix: &str) -> Option<&'static TeamProvider> {
+    match prefix {
+        "github" => Some(&GITHUB as &TeamProvider),
+        "local" => Some(&NATIVE as &TeamProvider),
+        _ => None,
+    }
+}
+
 /// Access rights to the crate (publishing and ownership management)
 /// NOTE: The order of these variants matters!
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
@@ -77,21 +130,11 @@ impl Team {
                   req_user: &User)
                   -> CargoResult<Self> {
         // must look like system:xxxxxxx
-        let mut chunks = login.split(":");
-        match chunks.next().unwrap() {
-            // github:rust-lang:owners
-            "github" => {
-                // Ok to unwrap since we know one ":" is contained
-                let org = chunks.next().unwrap();
-                let team = try!(chunks.next().ok_or_else(||
-                    human("missing github team argument; \
-                            format is github:org:team")
-                ));
-                Team::create_github_team(app, conn, login, org, team, req_user)
-            }
-            _ => {
-                Err(human("unknown organization handler, \
-                            only 'github:org:team' is supported"))
+        match team_provider(login.split(":").next().unwrap()) {
+            Some(provider) => provider.create(app, conn, login, req_user),
+            None => {
+                Err(human("unknown organization handler, only \
+                           'github:org:team' and 'local:team' are supported"))
             }
         }
     }
@@ -152,12 +195,12 @@ impl Team {
         let resp = try!(http::github(app, &url, &token));
         let org: Org = try!(http::parse_github_response(resp));
 
-        Team::insert(conn, login, team.id, team.name, org.avatar_url)
+        Team::insert(conn, login, Some(team.id), team.name, org.avatar_url)
     }
 
     pub fn insert(conn: &GenericConnection,
                   login: &str,
-                  github_id: i32,
+                  github_id: Option<i32>,
                   name: Option<String>,
                   avatar: Option<String>)
                   -> CargoResult<Self> {
@@ -172,13 +215,149 @@ impl Team {
         Ok(Model::from_row(&row))
     }
 
-    /// Phones home to Github to ask if this User is a member of the given team.
+    /// The provider of this team.
+    pub fn provider(&self) -> &'static TeamProvider {
+        // Teams are only ever created through a provider
+        team_provider(self.login.split(":").next().unwrap()).unwrap()
+    }
+
+    /// Asks the team's provider if this User is a member of the team, which
+    /// for Github teams means phoning home to Github.
     /// Note that we're assuming that the given user is the one interested in
     /// the answer. If this is not the case, then we could accidentally leak
     /// private membership information here.
-    pub fn contains_user(&self, app: &App, user: &User) -> CargoResult<bool> {
-        team_with_gh_id_contains_user(app, self.github_id, user)
+    pub fn contains_user(&self, app: &App, conn: &GenericConnection,
+                         user: &User) -> CargoResult<bool> {
+        self.provider().contains_user(app, conn, self, user)
+    }
+
+    /// The members of a team whose members are managed by the registry.
+    pub fn members(&self, conn: &GenericConnection) -> CargoResult<Vec<User>> {
+        let stmt = try!(conn.prepare("SELECT users.* FROM users
+                                      INNER JOIN team_members
+                                         ON team_members.user_id = users.id
+                                      WHERE team_members.team_id = $1
+                                      ORDER BY users.gh_login ASC"));
+        let rows = try!(stmt.query(&[&self.id]));
+        Ok(rows.iter().map(|r| Model::from_row(&r)).collect())
+    }
+
+    pub fn add_member(&self, conn: &GenericConnection,
+                      user: &User) -> CargoResult<()> {
+        try!(conn.execute("INSERT INTO team_members (team_id, user_id)
+                           SELECT $1, $2
+                            WHERE NOT EXISTS (SELECT 1 FROM team_members
+                                               WHERE team_id = $1
+                                                 AND user_id = $2)",
+                          &[&self.id, &user.id]));
+        Ok(())
+    }
+
+    pub fn remove_member(&self, conn: &GenericConnection,
+                         user: &User) -> CargoResult<()> {
+        try!(conn.execute("DELETE FROM team_members
+                            WHERE team_id = $1 AND user_id = $2",
+                          &[&self.id, &user.id]));
+        Ok(())
+    }
+}
+
+impl TeamProvider for Github {
+    fn create(&self, app: &App, conn: &GenericConnection, login: &str,
+              req_user: &User) -> CargoResult<Team> {
+        // github:rust-lang:owners
+        let mut chunks = login.split(":").skip(1);
+        // Ok to unwrap since we know one ":" is contained
+        let org = chunks.next().unwrap();
+        let team = try!(chunks.next().ok_or_else(||
+            human("missing github team argument; \
+                    format is github:org:team")
+        ));
+        Team::create_github_team(app, conn, login, org, team, req_user)
+    }
+
+    /// Answers from the `team_memberships` cache while its entry is fresh,
+    /// and asks Github otherwise.
+    ///
+    /// Entries which are used after they're halfway to expiring are flagged
+    /// so that `refresh_memberships` checks them again in the background. If
+    /// Github can't be asked, an expired entry is only trusted if the config
+    /// allows stale memberships, and even then an entry saying the user is a
+    /// member stops being trusted once it's twice the TTL old.
+    fn contains_user(&self, app: &App, conn: &GenericConnection, team: &Team,
+                     user: &User) -> CargoResult<bool> {
+        let github_id = match team.github_id {
+            Some(id) => id,
+            None => return Ok(false),
+        };
+        let cached = try!(Membership::find(conn, team.id, user.id));
+        let ttl = Duration::seconds(app.config.team_membership_ttl);
+        let now = ::now();
+        if let Some(ref membership) = cached {
+            if membership.checked_at + ttl > now {
+                if membership.checked_at + ttl / 2 <= now {
+                    try!(Membership::request_refresh(conn, team.id, user.id));
+                }
+                return Ok(membership.is_member)
+            }
+        }
+
+        match team_with_gh_id_contains_user(app, github_id, user) {
+            Ok(is_member) => {
+                try!(Membership::store(conn, team.id, user.id, is_member));
+                Ok(is_member)
+            }
+            Err(e) => match cached {
+                Some(ref membership) if app.config.allow_stale_team_memberships &&
+                                        (!membership.is_member ||
+                                         membership.checked_at + ttl * 2 > now) => {
+                    try!(Membership::request_refresh(conn, team.id, user.id));
+                    Ok(membership.is_member)
+                }
+                _ => Err(e),
+            },
+        }
+    }
+
+    fn url(&self, team: &Team) -> Option<String> {
+        let mut parts = team.login.split(":");
+        parts.next(); // discard github
+        Some(format!("https://github.com/orgs/{}/teams/{}",
+                     parts.next().unwrap(), parts.next().unwrap()))
+    }
+
+    fn manages_members(&self) -> bool { false }
+}
+
+impl TeamProvider for Native {
+    fn create(&self, _app: &App, conn: &GenericConnection, login: &str,
+              req_user: &User) -> CargoResult<Team> {
+        // local:release-team
+        let name = &login["local:".len()..];
+        let valid = |c: char| c.is_alphanumeric() || c == '-' || c == '_';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(human("team names may only contain letters, numbers, \
+                              `-` and `_`; format is local:team"))
+        }
+
+        // Whoever creates the team is its first member, and can then add
+        // everyone else.
+        let team = try!(Team::insert(conn, login, None, None, None));
+        try!(team.add_member(conn, req_user));
+        Ok(team)
+    }
+
+    fn contains_user(&self, _app: &App, conn: &GenericConnection, team: &Team,
+                     user: &User) -> CargoResult<bool> {
+        let stmt = try!(conn.prepare("SELECT 1 FROM team_members
+                                      WHERE team_id = $1 AND user_id = $2"));
+        let rows = try!(stmt.query(&[&team.id, &user.id]));
+        Ok(rows.iter().next().is_some())
     }
+
+    fn url(&self, _team: &Team) -> Option<String> { None }
+
+    fn manages_members(&self) -> bool { true }
 }
 
 fn team_with_gh_id_contains_user(app: &App, github_id: i32, user: &User)
@@ -206,6 +385,134 @@ fn team_with_gh_id_contains_user(app: &App, github_id: i32, user: &User)
     Ok(membership.state == "active")
 }
 
+/// Github's last answer on whether a user is a member of a team, kept in the
+/// `team_memberships` table.
+pub struct Membership {
+    pub team_id: i32,
+    pub user_id: i32,
+    pub is_member: bool,
+    pub checked_at: Timespec,
+    pub refresh_requested: bool,
+}
+
+impl Membership {
+    pub fn find(conn: &GenericConnection, team_id: i32,
+                user_id: i32) -> CargoResult<Option<Membership>> {
+        let stmt = try!(conn.prepare("SELECT * FROM team_memberships
+                                      WHERE team_id = $1 AND user_id = $2"));
+        let rows = try!(stmt.query(&[&team_id, &user_id]));
+        Ok(rows.iter().next().map(|r| Model::from_row(&r)))
+    }
+
+    /// Records Github's answer as of now, replacing any previous one.
+    ///
+    /// If two requests race to insert the first answer for a user, the insert
+    /// of one of them fails on the primary key and it tries again, this time
+    /// finding the row to update.
+    pub fn store(conn: &GenericConnection, team_id: i32, user_id: i32,
+                 is_member: bool) -> CargoResult<()> {
+        loop {
+            let tx = try!(conn.transaction());
+            let result = tx.execute("\
+                WITH updated AS (
+                    UPDATE team_memberships
+                       SET is_member = $3, checked_at = $4,
+                           refresh_requested = FALSE
+                     WHERE team_id = $1 AND user_id = $2
+                 RETURNING 1
+                )
+                INSERT INTO team_memberships
+                            (team_id, user_id, is_member, checked_at)
+                     SELECT $1, $2, $3, $4
+                      WHERE NOT EXISTS (SELECT 1 FROM updated)",
+                &[&team_id, &user_id, &is_member, &::now()]);
+            match result {
+                Ok(..) => {
+                    tx.set_commit();
+                    return Ok(try!(tx.finish()))
+                }
+                Err(PgError::Db(ref e)) if *e.code() == SqlState::UniqueViolation => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Flags the membership to be checked again by `refresh_memberships`.
+    pub fn request_refresh(conn: &GenericConnection, team_id: i32,
+                           user_id: i32) -> CargoResult<()> {
+        try!(conn.execute("UPDATE team_memberships SET refresh_requested = TRUE
+                            WHERE team_id = $1 AND user_id = $2
+                              AND NOT refresh_requested",
+                          &[&team_id, &user_id]));
+        Ok(())
+    }
+}
+
+impl Model for Membership {
+    fn from_row(row: &Row) -> Membership {
+        Membership {
+            team_id: row.get("team_id"),
+            user_id: row.get("user_id"),
+            is_member: row.get("is_member"),
+            checked_at: row.get("checked_at"),
+            refresh_requested: row.get("refresh_requested"),
+        }
+    }
+
+    fn table_name(_: Option<Membership>) -> &'static str { "team_memberships" }
+}
+
+/// Asks Github again about every cached membership which has been flagged by
+/// `Github::contains_user`, returning how many were refreshed.
+///
+/// A membership which can't be checked stays flagged, so that it's tried
+/// again the next time around. No database connection is held while waiting
+/// on Github, so that requests aren't kept waiting for one.
+pub fn refresh_memberships(app: &App) -> CargoResult<usize> {
+    let get_conn = || {
+        app.database.get().map_err(|e| {
+            internal(format!("failed to get a database connection: {}", e))
+        })
+    };
+
+    let flagged = {
+        let conn = try!(get_conn());
+        let stmt = try!(conn.prepare("SELECT * FROM team_memberships
+                                      WHERE refresh_requested"));
+        let rows = try!(stmt.query(&[]));
+        let memberships: Vec<Membership> = rows.iter().map(|r| {
+            Model::from_row(&r)
+        }).collect();
+        let mut flagged = Vec::new();
+        for membership in memberships {
+            let team: Team = try!(Model::find(&*conn, membership.team_id));
+            let user: User = try!(Model::find(&*conn, membership.user_id));
+            flagged.push((team, user));
+        }
+        flagged
+    };
+
+    let mut refreshed = 0;
+    for (team, user) in flagged {
+        let github_id = match team.github_id {
+            Some(id) => id,
+            None => continue,
+        };
+        match team_with_gh_id_contains_user(app, github_id, &user) {
+            Ok(is_member) => {
+                let conn = try!(get_conn());
+                try!(Membership::store(&*conn, team.id, user.id, is_member));
+                refreshed += 1;
+            }
+            Err(e) => {
+                info!("couldn't refresh the membership of {} in {}: {}",
+                      user.gh_login, team.login, e);
+            }
+        }
+    }
+    Ok(refreshed)
+}
+
 impl Model for Team {
     fn from_row(row: &Row) -> Self {
         Team {
@@ -273,18 +580,14 @@ impl Owner {
                     kind: String::from("user"),
                 }
             }
-            Owner::Team(Team { id, name, login, avatar, .. }) => {
-                let url = {
-                    let mut parts = login.split(":");
-                    parts.next(); // discard github
-                    format!("https://github.com/orgs/{}/teams/{}",
-                            parts.next().unwrap(), parts.next().unwrap())
-                };
+            Owner::Team(team) => {
+                let url = team.provider().url(&team);
+                let Team { id, name, login, avatar, .. } = team;
                 EncodableOwner {
                     id: id,
                     login: login,
                     email: None,
-                    url: Some(url),
+                    url: url,
                     avatar: avatar,
                     name: name,
                     kind: String::from("team"),
@@ -302,18 +605,129 @@ impl Owner {
 /// `Publish` as well, but this is a non-obvious invariant so we don't bother.
 /// Sweet free optimization if teams are proving burdensome to check.
 /// More than one team isn't really expected, though.
-pub fn rights(app: &App, owners: &[Owner], user: &User) -> CargoResult<Rights> {
+///
+/// Users who have only been invited to own the crate aren't among its owners
+/// until they accept, so they get no rights from their invitation.
+///
+/// The rights are being used for `scope` on the crate named `krate`, which
+/// fails if the API token the request was authenticated with doesn't allow
+/// that.
+pub fn rights(req: &Request, conn: &GenericConnection, owners: &[Owner],
+              user: &User, scope: Scope, krate: &str) -> CargoResult<Rights> {
     let mut best = Rights::None;
     for owner in owners {
         match *owner {
             Owner::User(ref other_user) => if other_user.id == user.id {
-                return Ok(Rights::Full);
-            },
-            Owner::Team(ref team) => if try!(team.contains_user(app, user)) {
-                best = Rights::Publish;
+                best = Rights::Full;
+                break
             },
+            Owner::Team(ref team) => {
+                if try!(team.contains_user(req.app(), conn, user)) {
+                    best = Rights::Publish;
+                }
+            }
         }
     }
+    if best > Rights::None {
+        try!(req.check_scope(scope, krate));
+    }
     Ok(best)
 }
 
+
+/// Handles the `GET /teams/:team_id/members` route.
+pub fn members(req: &mut Request) -> CargoResult<Response> {
+    let tx = try!(req.tx());
+    let team = try!(find_native_team(tx, &req.params()["team_id"]));
+    let users = try!(team.members(tx)).into_iter().map(|u| {
+        Owner::User(u).encodable()
+    }).collect();
+
+    #[derive(RustcEncodable)]
+    struct R { users: Vec<EncodableOwner> }
+    Ok(req.json(&R{ users: users }))
+}
+
+/// Handles the `PUT /teams/:team_id/members` route.
+pub fn add_members(req: &mut Request) -> CargoResult<Response> {
+    modify_members(req, true)
+}
+
+/// Handles the `DELETE /teams/:team_id/members` route.
+pub fn remove_members(req: &mut Request) -> CargoResult<Response> {
+    modify_members(req, false)
+}
+
+fn modify_members(req: &mut Request, add: bool) -> CargoResult<Response> {
+    let mut body = String::new();
+    try!(req.body().read_to_string(&mut body));
+    let user = try!(req.user()).clone();
+    // Members can publish every crate the team owns, so a token restricted to
+    // some crates or actions mustn't be able to make anyone a member.
+    try!(req.check_not_restricted("manage team members"));
+    let tx = try!(req.tx());
+    let team = try!(find_native_team(tx, &req.params()["team_id"]));
+    if !try!(team.contains_user(req.app(), tx, &user)) {
+        return Err(human(format!("only members of {} can change its members",
+                                 team.login)))
+    }
+
+    #[derive(RustcDecodable)]
+    struct Request { users: Vec<String> }
+    let request: Request = try!(json::decode(&body).map_err(|_| {
+        human("invalid json request")
+    }));
+
+    for login in request.users.iter() {
+        let member = try!(User::find_by_login(tx, login).map_err(|_| {
+            human(format!("could not find user with login `{}`", login))
+        }));
+        if add {
+            try!(team.add_member(tx, &member));
+        } else {
+            try!(team.remove_member(tx, &member));
+        }
+    }
+    if try!(team.members(tx)).is_empty() {
+        return Err(human("cannot remove every member of a team"))
+    }
+
+    #[derive(RustcEncodable)]
+    struct R { ok: bool }
+    Ok(req.json(&R{ ok: true }))
+}
+
+/// Handles the `PUT /teams/:team_id/refresh` route, which asks Github again
+/// whether the current user is a member of the team instead of waiting for
+/// their cached membership to expire.
+pub fn refresh_membership(req: &mut Request) -> CargoResult<Response> {
+    let user = try!(req.user()).clone();
+    let tx = try!(req.tx());
+    let login = &req.params()["team_id"];
+    let team = try!(Team::find_by_login(tx, login).map_err(|_| {
+        human(format!("could not find team with name {}", login))
+    }));
+    let member = match team.github_id {
+        Some(id) => {
+            let member = try!(team_with_gh_id_contains_user(req.app(), id, &user));
+            try!(Membership::store(tx, team.id, user.id, member));
+            member
+        }
+        None => try!(team.contains_user(req.app(), tx, &user)),
+    };
+
+    #[derive(RustcEncodable)]
+    struct R { member: bool }
+    Ok(req.json(&R{ member: member }))
+}
+
+fn find_native_team(conn: &GenericConnection, login: &str) -> CargoResult<Team> {
+    let team = try!(Team::find_by_login(conn, login).map_err(|_| {
+        human(format!("could not find team with name {}", login))
+    }));
+    if !team.provider().manages_members() {
+        return Err(human(format!("the members of {} aren't managed by this \
+                                  registry", login)))
+    }
+    Ok(team)
+}
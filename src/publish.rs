@@ -0,0 +1,91 @@
+//! Publishing a new version of a crate has side effects in three places: rows
+//! in the database, the `.crate` file in storage, and an entry in the git
+//! index. A `Publish` stages each of these in turn and, unless it is
+//! explicitly finished, undoes every stage that was started when it is
+//! dropped. This way a failure at any point leaves no trace of the version.
+//!
+//! Finishing only commits a savepoint of the request's transaction, so the
+//! upload is still deleted if that transaction isn't committed in the end.
+//!
+//! The index is updated by a background job which is enqueued along with
+//! the rest of the database changes, so it is undone along with them.
+
+use conduit::Request;
+use pg::{self, GenericConnection};
+use rustc_serialize::hex::ToHex;
+
+use app::{App, RequestApp};
+use background::Job;
+use db::RequestTransaction;
+use git;
+use util::{CargoResult, HashingReader};
+
+pub struct Publish<'a> {
+    app: &'a App,
+    // A savepoint within the request's transaction, this is rolled back when
+    // dropped unless `finish` marked it to be committed.
+    tx: Option<pg::Transaction<'a>>,
+    uploaded: Option<String>,
+}
+
+impl<'a> Publish<'a> {
+    /// Starts publishing, with all database changes made through `conn()`
+    /// staged in a savepoint of `conn`.
+    pub fn begin(app: &'a App,
+                 conn: &'a GenericConnection) -> CargoResult<Publish<'a>> {
+        Ok(Publish {
+            app: app,
+            tx: Some(try!(conn.transaction())),
+            uploaded: None,
+        })
+    }
+
+    /// The connection which database changes for this publish must be made
+    /// through.
+    pub fn conn(&self) -> &GenericConnection {
+        self.tx.as_ref().unwrap()
+    }
+
+    /// Uploads `tarball` to storage at `path`, returning its checksum.
+    pub fn upload(&mut self, path: &str, tarball: &[u8]) -> CargoResult<String> {
+        // Even a failed upload may have left something behind.
+        self.uploaded = Some(path.to_string());
+        let mut body = HashingReader::new(tarball);
+        try!(self.app.storage.upload(path, &mut body, tarball.len() as u64,
+                                     "application/x-tar"));
+        Ok(body.finalize().to_hex())
+    }
+
+    /// Enqueues a job adding `krate` to the git index.
+    pub fn add_to_index(&mut self, krate: git::Crate) -> CargoResult<()> {
+        Job::AddCrate(krate).enqueue(self.conn())
+    }
+
+    /// Commits the savepoint. From then on the upload is only undone if the
+    /// transaction of `req`, which the savepoint belongs to, isn't committed.
+    pub fn finish(mut self, req: &Request) -> CargoResult<()> {
+        let tx = self.tx.take().unwrap();
+        tx.set_commit();
+        try!(tx.finish());
+        if let Some(path) = self.uploaded.take() {
+            let app = req.app().clone();
+            req.on_rollback(Box::new(move || delete(&app, &path)));
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Publish<'a> {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.uploaded {
+            delete(self.app, path);
+        }
+        // Dropping the savepoint rolls it back.
+    }
+}
+
+fn delete(app: &App, path: &str) {
+    if let Err(e) = app.storage.delete(path) {
+        error!("failed to delete `{}` from storage: {}", path, e);
+    }
+}
@@ -0,0 +1,132 @@
+//! Limits how often a client may make requests to a group of routes.
+//!
+//! Each client has a token bucket per route group, stored in the
+//! `rate_limit_buckets` table so that the limit holds across all server
+//! processes. Clients are identified by their user id when logged in and by
+//! their IP address otherwise.
+//!
+//! Route groups may be nested, in which case a request is charged to each of
+//! them. Publishing is in both the `publish` and `api` groups, so the `api`
+//! limit caps publishes too.
+
+use std::error::Error;
+
+use conduit::{Request, Response, Handler};
+use pg::GenericConnection;
+use pg::error::{Error as PgError, SqlState};
+use time::Timespec;
+
+use User;
+use app::RequestApp;
+use util::{CargoResult, client_ip, internal, std_error};
+use util::errors::{CargoError, TooManyRequests};
+
+/// Allows bursts of up to `burst` requests, refilling at `per_second`
+/// requests per second.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub burst: f64,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    /// Parses a limit like `10/60`, meaning 10 requests per 60 seconds.
+    pub fn parse(s: &str) -> Option<RateLimit> {
+        let mut parts = s.splitn(2, '/');
+        let requests = parts.next().and_then(|s| s.trim().parse::<f64>().ok());
+        let seconds = parts.next().and_then(|s| s.trim().parse::<f64>().ok());
+        match (requests, seconds) {
+            (Some(r), Some(s)) if r >= 1.0 && s > 0.0 => {
+                Some(RateLimit { burst: r, per_second: r / s })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Wraps a handler so requests to it are limited by the limit configured for
+/// `group`, if any.
+pub struct RateLimited<H> {
+    pub group: &'static str,
+    pub handler: H,
+}
+
+impl<H: Handler> Handler for RateLimited<H> {
+    fn call(&self, req: &mut Request) -> Result<Response, Box<Error+Send>> {
+        let limit = req.app().config.rate_limits.get(self.group).map(|l| *l);
+        if let Some(limit) = limit {
+            if let Err(e) = check(req, self.group, &limit) {
+                return match e.response() {
+                    Some(response) => Ok(response),
+                    None => Err(std_error(e)),
+                }
+            }
+        }
+        self.handler.call(req)
+    }
+}
+
+fn check(req: &mut Request, group: &str, limit: &RateLimit) -> CargoResult<()> {
+    let key = match req.extensions().find::<User>() {
+        Some(user) => format!("user:{}", user.id),
+        None => format!("ip:{}", client_ip(req)),
+    };
+
+    // The request's own transaction is rolled back when the request fails, but
+    // failed requests count too, so the bucket is updated separately. The
+    // request may already hold its connection, so this comes from another pool.
+    let conn = try!(req.app().rate_limit_database.get().map_err(|e| {
+        internal(format!("failed to get a database connection: {}", e))
+    }));
+    take(&*conn, group, &key, limit, ::now())
+}
+
+/// Takes a token from the bucket of `key` in `group`, failing if there isn't
+/// one left.
+pub fn take(conn: &GenericConnection, group: &str, key: &str,
+            limit: &RateLimit, now: Timespec) -> CargoResult<()> {
+    loop {
+        let tx = try!(conn.transaction());
+        let inserted = tx.execute("INSERT INTO rate_limit_buckets
+                                        (route_group, key, tokens, last_refill)
+                                   SELECT $1, $2, $3, $4
+                                    WHERE NOT EXISTS (SELECT 1 FROM rate_limit_buckets
+                                                       WHERE route_group = $1
+                                                         AND key = $2)",
+                                  &[&group, &key, &limit.burst, &now]);
+        match inserted {
+            Ok(..) => {}
+            // Another request created the bucket at the same time, so
+            // start over and use theirs.
+            Err(PgError::Db(ref e)) if *e.code() == SqlState::UniqueViolation => {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let (tokens, last_refill): (f64, Timespec) = {
+            let stmt = try!(tx.prepare("SELECT tokens, last_refill
+                                          FROM rate_limit_buckets
+                                         WHERE route_group = $1 AND key = $2
+                                           FOR UPDATE"));
+            let rows = try!(stmt.query(&[&group, &key]));
+            let row = rows.iter().next().unwrap();
+            (row.get("tokens"), row.get("last_refill"))
+        };
+
+        let elapsed = (now - last_refill).num_milliseconds() as f64 / 1000.0;
+        let tokens = (tokens + elapsed.max(0.0) * limit.per_second).min(limit.burst);
+        let (tokens, result) = if tokens >= 1.0 {
+            (tokens - 1.0, Ok(()))
+        } else {
+            let wait = ((1.0 - tokens) / limit.per_second).ceil() as i64;
+            (tokens, Err(Box::new(TooManyRequests { retry_after: wait }) as Box<CargoError>))
+        };
+        try!(tx.execute("UPDATE rate_limit_buckets
+                            SET tokens = $1, last_refill = $2
+                          WHERE route_group = $3 AND key = $4",
+                        &[&tokens, &now, &group, &key]));
+        tx.set_commit();
+        try!(tx.finish());
+        return result
+    }
+}
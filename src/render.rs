@@ -0,0 +1,50 @@
+//! Rendering of README files to HTML that is safe to show on the website.
+
+use std::borrow::Cow;
+
+use pulldown_cmark::{html, Event, Parser, Tag};
+
+/// Renders Markdown `text` to HTML.
+///
+/// Any raw HTML in the source is escaped and shown as text, and links or
+/// images using a scheme other than `http`, `https` or `mailto` are dropped.
+pub fn markdown_to_html(text: &str) -> String {
+    let events = Parser::new(text).map(|event| {
+        match event {
+            Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+            Event::Start(tag) => Event::Start(sanitize_tag(tag)),
+            Event::End(tag) => Event::End(sanitize_tag(tag)),
+            event => event,
+        }
+    });
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, events);
+    rendered
+}
+
+fn sanitize_tag(tag: Tag) -> Tag {
+    match tag {
+        Tag::Link(url, title) => Tag::Link(sanitize_url(url), title),
+        Tag::Image(url, title) => Tag::Image(sanitize_url(url), title),
+        tag => tag,
+    }
+}
+
+fn sanitize_url(url: Cow<str>) -> Cow<str> {
+    // Browsers ignore whitespace and control characters inside of a scheme,
+    // so they need to be ignored here as well.
+    let cleaned = url.chars().filter(|c| !c.is_whitespace() && !c.is_control())
+                     .collect::<String>();
+    let scheme_end = cleaned.find(|c: char| {
+        c == ':' || c == '/' || c == '?' || c == '#'
+    });
+    let safe = match scheme_end {
+        Some(i) if cleaned[i..].starts_with(':') => {
+            let scheme = cleaned[..i].to_lowercase();
+            scheme == "http" || scheme == "https" || scheme == "mailto"
+        }
+        // No scheme at all, so this is a relative link.
+        _ => true,
+    };
+    if safe { url } else { Cow::Borrowed("") }
+}
@@ -51,6 +51,19 @@ impl Bucket {
               .content_type(content_type)
     }
 
+    pub fn get<'a, 'b>(&self, handle: &'a mut http::Handle, path: &str)
+                       -> http::Request<'a, 'b> {
+        let path = if path.starts_with("/") {&path[1..]} else {path};
+        let host = self.host();
+        let date = time::now().rfc822z().to_string();
+        let auth = self.auth("GET", &date, path, "", "");
+        let url = format!("{}://{}/{}", self.proto, host, path);
+        handle.get(&url[..])
+              .header("Host", &host)
+              .header("Date", &date)
+              .header("Authorization", &auth)
+    }
+
     pub fn delete<'a, 'b>(&self, handle: &'a mut http::Handle, path: &str)
                           -> http::Request<'a, 'b> {
         let path = if path.starts_with("/") {&path[1..]} else {path};
@@ -72,6 +85,8 @@ impl Bucket {
                 })
     }
 
+    pub fn proto(&self) -> &str { &self.proto }
+
     fn auth(&self, verb: &str, date: &str, path: &str,
             md5: &str, content_type: &str) -> String {
         let string = format!("{verb}\n{md5}\n{ty}\n{date}\n{headers}{resource}",
@@ -0,0 +1,213 @@
+//! Backends for storing the `.crate` files uploaded to the registry.
+//!
+//! crates.io itself keeps everything in S3, but a private registry (or the
+//! test suite) can instead keep the files on the local filesystem and have
+//! the registry serve them itself.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io;
+use std::path::PathBuf;
+
+use curl::http;
+use s3;
+
+use Config;
+use util::{CargoResult, ChainError, internal};
+
+/// Where the contents of a stored file can be fetched from.
+pub enum Download {
+    /// The file is publicly reachable at this URL.
+    Redirect(String),
+    /// The file lives on this machine and must be served by the registry.
+    File(PathBuf),
+}
+
+pub trait Storage: Send + Sync {
+    /// Stores `length` bytes read from `body` at `path`.
+    fn upload(&self, path: &str, body: &mut Read, length: u64,
+              content_type: &str) -> CargoResult<()>;
+
+    /// Removes the file at `path`, if there is one.
+    fn delete(&self, path: &str) -> CargoResult<()>;
+
+    /// Returns where the file at `path` can be downloaded from.
+    fn download(&self, path: &str) -> Download;
+
+    /// Reads the contents of the file at `path`, or `None` if there isn't
+    /// one.
+    fn read(&self, path: &str) -> CargoResult<Option<Vec<u8>>>;
+}
+
+/// Where the `.crate` files are kept.
+#[derive(Clone)]
+pub enum Location {
+    /// In a directory on the local filesystem.
+    Local(PathBuf),
+    /// In an S3 bucket.
+    S3 {
+        bucket: String,
+        region: Option<String>,
+        access_key: String,
+        secret_key: String,
+    },
+}
+
+/// Reads where the `.crate` files are kept from the environment. A directory
+/// in `LOCAL_STORAGE` takes precedence over the S3 bucket described by
+/// `S3_BUCKET`, `S3_ACCESS_KEY`, `S3_SECRET_KEY` and `S3_REGION`.
+pub fn from_env() -> Location {
+    fn var(name: &str) -> String {
+        env::var(name).ok().expect(&format!("must have `{}` defined", name))
+    }
+
+    match env::var("LOCAL_STORAGE") {
+        Ok(root) => Location::Local(PathBuf::from(root)),
+        Err(..) => Location::S3 {
+            bucket: var("S3_BUCKET"),
+            region: env::var("S3_REGION").ok(),
+            access_key: var("S3_ACCESS_KEY"),
+            secret_key: var("S3_SECRET_KEY"),
+        },
+    }
+}
+
+/// Creates the storage backend described by `config`.
+pub fn new(config: &Config) -> Box<Storage> {
+    open(&config.storage, config.s3_proxy.clone(), config.api_protocol())
+}
+
+/// Creates the storage backend for `location`, talking to S3 through `proxy`
+/// over `protocol` if the files are kept there.
+pub fn open(location: &Location, proxy: Option<String>,
+            protocol: &str) -> Box<Storage> {
+    match *location {
+        Location::Local(ref root) => Box::new(FileSystem::new(root.clone())),
+        Location::S3 { ref bucket, ref region, ref access_key, ref secret_key } => {
+            let bucket = s3::Bucket::new(bucket.clone(), region.clone(),
+                                         access_key.clone(), secret_key.clone(),
+                                         protocol);
+            Box::new(S3::new(bucket, proxy))
+        }
+    }
+}
+
+pub struct S3 {
+    bucket: s3::Bucket,
+    proxy: Option<String>,
+}
+
+impl S3 {
+    pub fn new(bucket: s3::Bucket, proxy: Option<String>) -> S3 {
+        S3 { bucket: bucket, proxy: proxy }
+    }
+
+    fn handle(&self) -> http::Handle {
+        let handle = http::handle();
+        match self.proxy {
+            Some(ref proxy) => handle.proxy(&proxy[..]),
+            None => handle,
+        }
+    }
+}
+
+impl Storage for S3 {
+    fn upload(&self, path: &str, mut body: &mut Read, length: u64,
+              content_type: &str) -> CargoResult<()> {
+        let mut handle = self.handle();
+        let resp = {
+            let s3req = self.bucket.put(&mut handle, path, &mut body,
+                                        content_type)
+                                   .content_length(length as usize);
+            try!(s3req.exec().chain_error(|| {
+                internal(format!("failed to upload to S3: `{}`", path))
+            }))
+        };
+        if resp.get_code() != 200 {
+            return Err(internal(format!("failed to get a 200 response from \
+                                         S3: {}", resp)))
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> CargoResult<()> {
+        let mut handle = self.handle();
+        try!(self.bucket.delete(&mut handle, path).exec());
+        Ok(())
+    }
+
+    fn download(&self, path: &str) -> Download {
+        Download::Redirect(format!("{}://{}{}", self.bucket.proto(),
+                                   self.bucket.host(), path))
+    }
+
+    fn read(&self, path: &str) -> CargoResult<Option<Vec<u8>>> {
+        let mut handle = self.handle();
+        let resp = try!(self.bucket.get(&mut handle, path).exec().chain_error(|| {
+            internal(format!("failed to read from S3: `{}`", path))
+        }));
+        match resp.get_code() {
+            200 => Ok(Some(resp.get_body().to_vec())),
+            404 => Ok(None),
+            _ => Err(internal(format!("failed to get a 200 response from \
+                                       S3: {}", resp))),
+        }
+    }
+}
+
+pub struct FileSystem {
+    root: PathBuf,
+}
+
+impl FileSystem {
+    pub fn new(root: PathBuf) -> FileSystem {
+        FileSystem { root: root }
+    }
+
+    /// The location on disk of the file stored at `path`.
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path.trim_left_matches('/'))
+    }
+}
+
+impl Storage for FileSystem {
+    fn upload(&self, path: &str, body: &mut Read, length: u64,
+              _content_type: &str) -> CargoResult<()> {
+        let dst = self.path(path);
+        try!(fs::create_dir_all(dst.parent().unwrap()));
+        let amt = {
+            let mut file = try!(File::create(&dst));
+            try!(io::copy(body, &mut file))
+        };
+        if amt != length {
+            let _ = fs::remove_file(&dst);
+            return Err(internal(format!("expected {} bytes for `{}` but only \
+                                         {} were uploaded", length, path, amt)))
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &str) -> CargoResult<()> {
+        match fs::remove_file(self.path(path)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => Ok(try!(res)),
+        }
+    }
+
+    fn download(&self, path: &str) -> Download {
+        Download::File(self.path(path))
+    }
+
+    fn read(&self, path: &str) -> CargoResult<Option<Vec<u8>>> {
+        let mut contents = Vec::new();
+        match File::open(self.path(path)) {
+            Ok(mut file) => try!(file.read_to_end(&mut contents)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Some(contents))
+    }
+}
@@ -0,0 +1,185 @@
+//! Verification of the `.crate` archives uploaded by `cargo publish`.
+//!
+//! A `.crate` file is a gzipped tarball whose entries all live underneath a
+//! `{name}-{vers}/` directory, and which contains the crate's `Cargo.toml` at
+//! the top of that directory.
+
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use semver;
+use tar::Archive;
+use toml;
+
+use util::{CargoResult, ChainError, human};
+
+/// README file names we look for if the manifest doesn't name one.
+const READMES: &'static [&'static str] = &["README.md", "README.markdown",
+                                            "README"];
+
+/// The interesting bits of a `.crate` file.
+pub struct Contents {
+    /// The contents of the crate's README, if it has one.
+    pub readme: Option<String>,
+}
+
+/// Checks that `tarball` is a well-formed `.crate` file for version `vers` of
+/// the crate `name`, returning what we found inside of it.
+pub fn verify(tarball: &[u8], name: &str, vers: &semver::Version)
+              -> CargoResult<Contents> {
+    let prefix = PathBuf::from(format!("{}-{}", name, vers));
+    let decoder = try!(GzDecoder::new(tarball).map_err(|_| {
+        human("uploaded crate is not a valid gzip file")
+    }));
+    let mut archive = Archive::new(decoder);
+    let mut manifest = None;
+    let mut files = HashSet::new();
+
+    let entries = try!(archive.entries().map_err(|_| {
+        human("uploaded crate is not a valid tarball")
+    }));
+    for entry in entries {
+        let mut entry = try!(entry.map_err(|_| {
+            human("uploaded crate is not a valid tarball")
+        }));
+        let path = try!(entry.path().map_err(|_| {
+            human("uploaded crate contains an invalid path")
+        })).into_owned();
+        let path = match normalize(&path) {
+            Some(ref p) if p.starts_with(&prefix) && *p != prefix => p.clone(),
+            _ => return Err(human(format!("invalid path in uploaded crate, \
+                                           all files must be within `{}/`: \
+                                           `{}`", prefix.display(),
+                                          path.display()))),
+        };
+
+        let kind = entry.header().entry_type();
+        if kind.is_symlink() || kind.is_hard_link() {
+            let target = try!(entry.link_name().map_err(|_| {
+                human("uploaded crate contains an invalid link")
+            }));
+            let target = try!(target.chain_error(|| {
+                human("uploaded crate contains a link without a target")
+            })).into_owned();
+            // Symlinks are relative to the directory containing them, while
+            // hard links are relative to the root of the archive.
+            let target = if kind.is_symlink() {
+                path.parent().unwrap().join(&target)
+            } else {
+                target
+            };
+            match normalize(&target) {
+                Some(ref t) if t.starts_with(&prefix) => {}
+                _ => return Err(human(format!("link in uploaded crate points \
+                                               outside of `{}/`: `{}`",
+                                              prefix.display(),
+                                              path.display()))),
+            }
+        }
+
+        if kind.is_file() {
+            files.insert(path.clone());
+        }
+        if path == prefix.join("Cargo.toml") && kind.is_file() {
+            let mut contents = String::new();
+            try!(entry.read_to_string(&mut contents).map_err(|_| {
+                human("uploaded crate has a Cargo.toml which is not utf-8")
+            }));
+            manifest = Some(contents);
+        }
+    }
+
+    let manifest = try!(manifest.chain_error(|| {
+        human(format!("uploaded crate is missing `{}/Cargo.toml`",
+                      prefix.display()))
+    }));
+    let package = try!(verify_manifest(&manifest, name, vers));
+
+    let readme = package.get("readme").and_then(|r| r.as_str())
+                        .and_then(|r| normalize(&prefix.join(r)))
+                        .into_iter()
+                        .chain(READMES.iter().map(|r| prefix.join(r)))
+                        .find(|r| files.contains(r));
+    let readme = match readme {
+        Some(path) => read_file(tarball, &path),
+        None => None,
+    };
+    Ok(Contents { readme: readme })
+}
+
+/// Reads the file at `path` out of `tarball`, returning `None` if it can't be
+/// read as utf-8.
+fn read_file(tarball: &[u8], path: &Path) -> Option<String> {
+    let decoder = match GzDecoder::new(tarball) {
+        Ok(decoder) => decoder,
+        Err(..) => return None,
+    };
+    let mut archive = Archive::new(decoder);
+    let entries = match archive.entries() {
+        Ok(entries) => entries,
+        Err(..) => return None,
+    };
+    for entry in entries {
+        let mut entry = match entry {
+            Ok(entry) => entry,
+            Err(..) => return None,
+        };
+        let matches = entry.path().ok().and_then(|p| normalize(&p))
+                           .map_or(false, |p| p.as_path() == path);
+        if matches {
+            let mut contents = String::new();
+            return entry.read_to_string(&mut contents).ok().map(|_| contents)
+        }
+    }
+    None
+}
+
+/// Checks that the name and version in a `Cargo.toml` agree with those the
+/// crate is being published as, returning its `[package]` section.
+fn verify_manifest(manifest: &str, name: &str, vers: &semver::Version)
+                   -> CargoResult<toml::Table> {
+    let manifest = try!(toml::Parser::new(manifest).parse().chain_error(|| {
+        human("uploaded crate has an invalid Cargo.toml")
+    }));
+    let package = manifest.get("package").or(manifest.get("project"));
+    let package = try!(package.and_then(|p| p.as_table()).chain_error(|| {
+        human("uploaded crate's Cargo.toml has no `[package]` section")
+    })).clone();
+
+    {
+        let manifest_name = package.get("name").and_then(|n| n.as_str());
+        if manifest_name != Some(name) {
+            return Err(human(format!("crate name `{}` does not match the \
+                                      name in the uploaded Cargo.toml: `{}`",
+                                     name, manifest_name.unwrap_or(""))))
+        }
+
+        let manifest_vers = package.get("version").and_then(|v| v.as_str());
+        let parsed = manifest_vers.and_then(|v| semver::Version::parse(v).ok());
+        if parsed.as_ref() != Some(vers) {
+            return Err(human(format!("crate version `{}` does not match the \
+                                      version in the uploaded Cargo.toml: `{}`",
+                                     vers, manifest_vers.unwrap_or(""))))
+        }
+    }
+    Ok(package)
+}
+
+/// Lexically resolves `.` and `..` in a relative path, returning `None` if
+/// the path is absolute or climbs above its starting point.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let mut ret = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => ret.push(c),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !ret.pop() { return None }
+            }
+            Component::RootDir | Component::Prefix(..) => return None,
+        }
+    }
+    Some(ret)
+}
@@ -7,10 +7,13 @@ extern crate conduit;
 extern crate conduit_middleware;
 extern crate conduit_test;
 extern crate curl;
+extern crate flate2;
 extern crate git2;
+extern crate openssl;
 extern crate postgres;
 extern crate rustc_serialize;
 extern crate semver;
+extern crate tar;
 extern crate time;
 extern crate url;
 
@@ -23,11 +26,15 @@ use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 use rustc_serialize::json::{self, Json};
 
 use conduit::{Request, Method};
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use conduit_test::MockRequest;
 use cargo_registry::app::App;
+use cargo_registry::background;
 use cargo_registry::db::{self, RequestTransaction};
 use cargo_registry::dependency::Kind;
 use cargo_registry::{User, Crate, Version, Keyword, Dependency};
+use cargo_registry::storage::Location;
 use cargo_registry::upload as u;
 
 macro_rules! t {
@@ -64,6 +71,10 @@ struct Error { detail: String }
 #[derive(RustcDecodable)]
 struct Bad { errors: Vec<Error> }
 
+mod background;
+mod crate_owner_invitation;
+mod index;
+mod rate_limit;
 mod middleware;
 mod keyword;
 mod krate;
@@ -72,27 +83,40 @@ mod record;
 mod git;
 mod version;
 mod team;
+mod token;
 
 fn app() -> (record::Bomb, Arc<App>, conduit_middleware::MiddlewareBuilder) {
+    app_with_config(|_| {})
+}
+
+fn app_with_config<F>(f: F)
+                      -> (record::Bomb, Arc<App>, conduit_middleware::MiddlewareBuilder)
+    where F: FnOnce(&mut cargo_registry::Config)
+{
     struct NoCommit;
     static INIT: Once = ONCE_INIT;
     git::init();
 
     let (proxy, bomb) = record::proxy();
-    let config = cargo_registry::Config {
-        s3_bucket: env::var("S3_BUCKET").unwrap_or(String::new()),
-        s3_access_key: env::var("S3_ACCESS_KEY").unwrap_or(String::new()),
-        s3_secret_key: env::var("S3_SECRET_KEY").unwrap_or(String::new()),
-        s3_region: env::var("S3_REGION").ok(),
+    let mut config = cargo_registry::Config {
+        storage: Location::Local(git::storage()),
         s3_proxy: Some(proxy),
         session_key: "test".to_string(),
+        fingerprint_secret: "test".to_string(),
         git_repo_checkout: git::checkout(),
         gh_client_id: env::var("GH_CLIENT_ID").unwrap_or(String::new()),
         gh_client_secret: env::var("GH_CLIENT_SECRET").unwrap_or(String::new()),
         db_url: env("TEST_DATABASE_URL"),
         env: cargo_registry::Env::Test,
         max_upload_size: 1000,
+        api_url: "http://localhost".to_string(),
+        dl_url: "http://localhost/api/v1/crates".to_string(),
+        auth_required: false,
+        rate_limits: rate_limit::limits(),
+        team_membership_ttl: 3600,
+        allow_stale_team_memberships: false,
     };
+    f(&mut config);
     INIT.call_once(|| db_setup(&config.db_url));
     let app = App::new(&config);
     let app = Arc::new(app);
@@ -199,6 +223,7 @@ fn krate(name: &str) -> Crate {
         updated_at: time::now().to_timespec(),
         created_at: time::now().to_timespec(),
         downloads: 10,
+        unique_downloads: 10,
         max_version: semver::Version::parse("0.0.0").unwrap(),
         documentation: None,
         homepage: None,
@@ -210,6 +235,12 @@ fn krate(name: &str) -> Crate {
     }
 }
 
+/// Applies the index changes queued up by `req` to the local checkout.
+fn run_jobs(req: &mut Request) {
+    let repo = t!(git2::Repository::open(&git::checkout()));
+    t!(background::run_pending(req.tx().unwrap(), &repo));
+}
+
 fn mock_user(req: &mut Request, u: User) -> User {
     let u = User::find_or_insert(req.tx().unwrap(),
                                  &u.gh_login,
@@ -249,6 +280,7 @@ fn mock_dep(req: &mut Request, version: &Version, krate: &Crate,
     Dependency::insert(req.tx().unwrap(),
                        version.id,
                        krate.id,
+                       &krate.name,
                        &semver::VersionReq::parse(">= 0").unwrap(),
                        Kind::Normal,
                        false, true, &[],
@@ -296,6 +328,12 @@ fn new_req_body(krate: Crate, version: &str, deps: Vec<u::CrateDependency>)
 }
 
 fn new_crate_to_body(new_crate: &u::NewCrate) -> Vec<u8> {
+    let tarball = crate_tarball(&new_crate.name, &new_crate.vers.to_string());
+    new_crate_to_body_with_tarball(new_crate, &tarball)
+}
+
+fn new_crate_to_body_with_tarball(new_crate: &u::NewCrate, tarball: &[u8])
+                                  -> Vec<u8> {
     let json = json::encode(&new_crate).unwrap();
     let mut body = Vec::new();
     body.extend([
@@ -305,6 +343,33 @@ fn new_crate_to_body(new_crate: &u::NewCrate) -> Vec<u8> {
         (json.len() >> 24) as u8,
     ].iter().cloned());
     body.extend(json.as_bytes().iter().cloned());
-    body.extend([0, 0, 0, 0].iter().cloned());
+    body.extend([
+        (tarball.len() >>  0) as u8,
+        (tarball.len() >>  8) as u8,
+        (tarball.len() >> 16) as u8,
+        (tarball.len() >> 24) as u8,
+    ].iter().cloned());
+    body.extend(tarball.iter().cloned());
     body
 }
+
+/// A minimal `.crate` file containing just a `Cargo.toml`.
+fn crate_tarball(name: &str, version: &str) -> Vec<u8> {
+    let manifest = format!("[package]\nname = \"{}\"\nversion = \"{}\"\n",
+                           name, version);
+    let path = format!("{}-{}/Cargo.toml", name, version);
+    tarball(&[(&path[..], manifest.as_bytes())])
+}
+
+fn tarball(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut ar = tar::Builder::new(GzEncoder::new(Vec::new(),
+                                                  Compression::Default));
+    for &(path, data) in files {
+        let mut header = tar::Header::new_gnu();
+        t!(header.set_path(path));
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        t!(ar.append(&header, data));
+    }
+    t!(t!(ar.into_inner()).finish())
+}
@@ -0,0 +1,177 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+
+use conduit::{Handler, Request, Method};
+use git2;
+use rustc_serialize::json;
+
+use cargo_registry::Model;
+use cargo_registry::background::{self, BackgroundJob, Job};
+use cargo_registry::db::RequestTransaction;
+use cargo_registry::git;
+
+fn jobs(req: &mut Request) -> Vec<BackgroundJob> {
+    let tx = req.tx().unwrap();
+    let stmt = tx.prepare("SELECT * FROM background_jobs ORDER BY id").unwrap();
+    let rows = stmt.query(&[]).unwrap();
+    let jobs = rows.iter().map(|r| Model::from_row(&r)).collect();
+    jobs
+}
+
+#[test]
+fn publish_enqueues_job() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    assert_eq!(jobs(&mut req).len(), 1);
+
+    ::run_jobs(&mut req);
+    assert_eq!(jobs(&mut req).len(), 0);
+    assert!(::git::checkout().join("3/f/foo").exists());
+}
+
+#[test]
+fn jobs_run_in_order() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    ok_resp!(middle.call(req.with_method(Method::Delete)
+                            .with_path("/api/v1/crates/foo/1.0.0/yank")));
+    assert_eq!(jobs(&mut req).len(), 2);
+
+    ::run_jobs(&mut req);
+    let mut contents = String::new();
+    File::open(&::git::checkout().join("3/f/foo")).unwrap()
+        .read_to_string(&mut contents).unwrap();
+    assert!(contents.contains("\"yanked\":true"));
+}
+
+#[test]
+fn update_config() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app.clone(), Method::Get, "/");
+    let req: &mut Request = &mut req;
+    let contents = git::config_json(&app.config);
+    assert!(!git::config_is_current(&::git::checkout(), &contents));
+
+    Job::UpdateConfig(contents.clone()).enqueue(req.tx().unwrap()).unwrap();
+    ::run_jobs(req);
+    assert!(git::config_is_current(&::git::checkout(), &contents));
+    let mut written = String::new();
+    File::open(&::git::checkout().join("config.json")).unwrap()
+        .read_to_string(&mut written).unwrap();
+    assert!(written.contains("\"dl\": \"http://localhost/api/v1/crates\""));
+}
+
+#[test]
+fn failed_job_is_retried_later() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    let req: &mut Request = &mut req;
+    // A directory where the crate's index file should be
+    fs::create_dir_all(::git::checkout().join("3/f/foo")).unwrap();
+    let job = Job::Yank {
+        krate: "foo".to_string(),
+        version: "1.0.0".to_string(),
+        yanked: true,
+    };
+    job.enqueue(req.tx().unwrap()).unwrap();
+    let job = Job::Yank {
+        krate: "bar".to_string(),
+        version: "1.0.0".to_string(),
+        yanked: true,
+    };
+    job.enqueue(req.tx().unwrap()).unwrap();
+
+    let repo = git2::Repository::open(&::git::checkout()).unwrap();
+    assert!(background::run_pending(req.tx().unwrap(), &repo).is_err());
+    let queued = jobs(req);
+    assert_eq!(queued.len(), 2);
+    assert_eq!(queued[0].retries, 1);
+    assert!(queued[0].last_error.is_some());
+    assert!(queued[0].next_retry().unwrap() > queued[0].last_retry.unwrap());
+    assert_eq!(queued[1].retries, 0);
+
+    // The failed job isn't due yet, and nothing runs before it does
+    assert_eq!(background::run_pending(req.tx().unwrap(), &repo).unwrap(), 0);
+    assert_eq!(jobs(req).len(), 2);
+}
+
+#[test]
+fn failed_job_is_given_up_on() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app.clone(), Method::Get, "/");
+    let req: &mut Request = &mut req;
+    // A directory where the crate's index file should be
+    fs::create_dir_all(::git::checkout().join("3/f/foo")).unwrap();
+    let job = Job::Yank {
+        krate: "foo".to_string(),
+        version: "1.0.0".to_string(),
+        yanked: true,
+    };
+    job.enqueue(req.tx().unwrap()).unwrap();
+    req.tx().unwrap().execute("UPDATE background_jobs SET retries = $1",
+                              &[&(background::MAX_RETRIES - 1)]).unwrap();
+    let contents = git::config_json(&app.config);
+    Job::UpdateConfig(contents.clone()).enqueue(req.tx().unwrap()).unwrap();
+    let job = Job::Yank {
+        krate: "Foo".to_string(),
+        version: "2.0.0".to_string(),
+        yanked: true,
+    };
+    job.enqueue(req.tx().unwrap()).unwrap();
+
+    let repo = git2::Repository::open(&::git::checkout()).unwrap();
+    assert!(background::run_pending(req.tx().unwrap(), &repo).is_err());
+
+    // The jobs after it run unless they change the same file, and it's kept
+    // around to be looked into
+    assert_eq!(background::run_pending(req.tx().unwrap(), &repo).unwrap(), 1);
+    assert!(git::config_is_current(&::git::checkout(), &contents));
+    let queued = jobs(req);
+    assert_eq!(queued.len(), 2);
+    assert_eq!(queued[0].retries, background::MAX_RETRIES);
+    assert!(queued[0].last_error.is_some());
+    assert_eq!(queued[1].retries, 0);
+}
+
+#[test]
+fn add_crate_is_idempotent() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    // As if the job was retried after it had already been pushed
+    let path = ::git::checkout().join("3/f/foo");
+    let mut contents = String::new();
+    File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+    let krate = json::decode::<git::Crate>(contents.trim()).unwrap();
+    {
+        let req: &mut Request = &mut req;
+        Job::AddCrate(krate).enqueue(req.tx().unwrap()).unwrap();
+    }
+    ::run_jobs(&mut req);
+
+    let mut after = String::new();
+    File::open(&path).unwrap().read_to_string(&mut after).unwrap();
+    assert_eq!(after, contents);
+}
+
+#[test]
+fn is_pending() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    let req: &mut Request = &mut req;
+    let yank = |yanked| Job::Yank {
+        krate: "foo".to_string(),
+        version: "1.0.0".to_string(),
+        yanked: yanked,
+    };
+    yank(true).enqueue(req.tx().unwrap()).unwrap();
+    assert!(yank(true).is_pending(req.tx().unwrap()).unwrap());
+    assert!(!yank(false).is_pending(req.tx().unwrap()).unwrap());
+}
@@ -0,0 +1,180 @@
+use conduit::{Handler, Request, Method};
+use conduit_test::MockRequest;
+use time::{self, Duration};
+
+use cargo_registry::crate_owner_invitation::{EncodableCrateOwnerInvitation,
+                                             EXPIRY_DAYS};
+use cargo_registry::db::RequestTransaction;
+
+#[derive(RustcDecodable)]
+struct InvitationList { crate_owner_invitations: Vec<EncodableCrateOwnerInvitation> }
+#[derive(RustcDecodable)]
+struct O { ok: bool }
+
+fn invitations(middle: &Handler, req: &mut MockRequest) -> Vec<String> {
+    let mut response = ok_resp!(middle.call(req.with_path("/me/crate_owner_invitations")
+                                               .with_method(Method::Get)));
+    ::json::<InvitationList>(&mut response).crate_owner_invitations
+        .into_iter().map(|i| i.crate_name).collect()
+}
+
+fn invite(middle: &Handler, req: &mut MockRequest, krate: &str, login: &str) {
+    let path = format!("/api/v1/crates/{}/owners", krate);
+    let body = format!(r#"{{"users":["{}"]}}"#, login);
+    let mut response = ok_resp!(middle.call(req.with_path(&path)
+                                               .with_method(Method::Put)
+                                               .with_body(body.as_bytes())));
+    assert!(::json::<O>(&mut response).ok);
+}
+
+#[test]
+fn invitees_have_no_rights() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    let invitee = ::mock_user(&mut req, ::user("invitee"));
+    ::mock_user(&mut req, ::user("owner"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    invite(&middle, &mut req, "foo", "invitee");
+
+    ::mock_user(&mut req, invitee.clone());
+    assert_eq!(invitations(&middle, &mut req), ["foo"]);
+    let body = ::new_req_body(::krate("foo"), "2.0.0", vec![]);
+    let json = bad_resp!(middle.call(req.with_path("/api/v1/crates/new")
+                                        .with_body(&body)
+                                        .with_method(Method::Put)));
+    assert!(json.errors[0].detail.contains("another user"),
+            "{:?}", json.errors);
+
+    let mut response = ok_resp!(middle.call(req.with_path("/me/crate_owner_invitations/foo/accept")
+                                               .with_method(Method::Put)));
+    assert!(::json::<O>(&mut response).ok);
+    assert_eq!(invitations(&middle, &mut req), Vec::<String>::new());
+    ok_resp!(middle.call(req.with_path("/api/v1/crates/new")
+                            .with_body(&body)
+                            .with_method(Method::Put)));
+}
+
+#[test]
+fn decline() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    let invitee = ::mock_user(&mut req, ::user("invitee"));
+    ::mock_user(&mut req, ::user("owner"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    invite(&middle, &mut req, "foo", "invitee");
+
+    ::mock_user(&mut req, invitee.clone());
+    let mut response = ok_resp!(middle.call(req.with_path("/me/crate_owner_invitations/foo/decline")
+                                               .with_method(Method::Put)));
+    assert!(::json::<O>(&mut response).ok);
+    assert_eq!(invitations(&middle, &mut req), Vec::<String>::new());
+
+    let json = bad_resp!(middle.call(req.with_path("/me/crate_owner_invitations/foo/accept")
+                                        .with_method(Method::Put)));
+    assert!(json.errors[0].detail.contains("haven't been invited"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn withdraw() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    let invitee = ::mock_user(&mut req, ::user("invitee"));
+    ::mock_user(&mut req, ::user("owner"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    invite(&middle, &mut req, "foo", "invitee");
+
+    let body = r#"{"users":["invitee"]}"#;
+    ok_resp!(middle.call(req.with_path("/api/v1/crates/foo/owners")
+                            .with_method(Method::Delete)
+                            .with_body(body.as_bytes())));
+
+    ::mock_user(&mut req, invitee.clone());
+    assert_eq!(invitations(&middle, &mut req), Vec::<String>::new());
+}
+
+#[test]
+fn expired() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    let invitee = ::mock_user(&mut req, ::user("invitee"));
+    ::mock_user(&mut req, ::user("owner"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    invite(&middle, &mut req, "foo", "invitee");
+
+    let created_at = time::now_utc().to_timespec() - Duration::days(EXPIRY_DAYS + 1);
+    {
+        let req: &mut Request = &mut req;
+        req.tx().unwrap().execute("UPDATE crate_owner_invitations
+                                      SET created_at = $1
+                                    WHERE invited_user_id = $2",
+                                  &[&created_at, &invitee.id]).unwrap();
+    }
+
+    ::mock_user(&mut req, invitee.clone());
+    assert_eq!(invitations(&middle, &mut req), Vec::<String>::new());
+    let json = bad_resp!(middle.call(req.with_path("/me/crate_owner_invitations/foo/accept")
+                                        .with_method(Method::Put)));
+    assert!(json.errors[0].detail.contains("has expired"),
+            "{:?}", json.errors);
+
+    // Inviting them again starts over
+    ::mock_user(&mut req, ::user("owner"));
+    invite(&middle, &mut req, "foo", "invitee");
+    ::mock_user(&mut req, invitee.clone());
+    assert_eq!(invitations(&middle, &mut req), ["foo"]);
+}
+
+#[test]
+fn inviter_removed_before_accepting() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    let invitee = ::mock_user(&mut req, ::user("invitee"));
+    let other = ::mock_user(&mut req, ::user("other"));
+    ::mock_user(&mut req, ::user("owner"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    invite(&middle, &mut req, "foo", "other");
+    ::mock_user(&mut req, other.clone());
+    ok_resp!(middle.call(req.with_path("/me/crate_owner_invitations/foo/accept")
+                            .with_method(Method::Put)));
+
+    ::mock_user(&mut req, ::user("owner"));
+    invite(&middle, &mut req, "foo", "invitee");
+    ::mock_user(&mut req, other.clone());
+    let body = r#"{"users":["owner"]}"#;
+    ok_resp!(middle.call(req.with_path("/api/v1/crates/foo/owners")
+                            .with_method(Method::Delete)
+                            .with_body(body.as_bytes())));
+
+    // Removing the owner withdrew the invitations they sent
+    ::mock_user(&mut req, invitee.clone());
+    assert_eq!(invitations(&middle, &mut req), Vec::<String>::new());
+    let json = bad_resp!(middle.call(req.with_path("/me/crate_owner_invitations/foo/accept")
+                                        .with_method(Method::Put)));
+    assert!(json.errors[0].detail.contains("haven't been invited"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn inviter_without_rights_when_accepting() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    let invitee = ::mock_user(&mut req, ::user("invitee"));
+    let owner = ::mock_user(&mut req, ::user("owner"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    invite(&middle, &mut req, "foo", "invitee");
+
+    // The inviter lost their ownership without going through the API
+    {
+        let req: &mut Request = &mut req;
+        req.tx().unwrap().execute("UPDATE crate_owners SET deleted = TRUE
+                                    WHERE owner_id = $1",
+                                  &[&owner.id]).unwrap();
+    }
+
+    ::mock_user(&mut req, invitee.clone());
+    let json = bad_resp!(middle.call(req.with_path("/me/crate_owner_invitations/foo/accept")
+                                        .with_method(Method::Put)));
+    assert!(json.errors[0].detail.contains("no longer an owner"),
+            "{:?}", json.errors);
+}
@@ -13,11 +13,13 @@ fn root() -> PathBuf {
 
 pub fn checkout() -> PathBuf { root().join("checkout") }
 pub fn bare() -> PathBuf { root().join("bare") }
+pub fn storage() -> PathBuf { root().join("storage") }
 
 pub fn init() {
     static INIT: Once = ONCE_INIT;
     let _ = fs::remove_dir_all(&checkout());
     let _ = fs::remove_dir_all(&bare());
+    let _ = fs::remove_dir_all(&storage());
 
     INIT.call_once(|| {
         fs::create_dir_all(root().parent().unwrap()).unwrap();
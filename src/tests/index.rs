@@ -0,0 +1,116 @@
+use std::io::prelude::*;
+
+use conduit::{Handler, Method};
+use rustc_serialize::json::Json;
+
+fn body(resp: &mut ::conduit::Response) -> String {
+    let mut s = String::new();
+    resp.body.read_to_string(&mut s).unwrap();
+    s
+}
+
+#[test]
+fn config() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/index/config.json");
+    let mut resp = ok_resp!(middle.call(&mut req));
+    let config = Json::from_str(&body(&mut resp)).unwrap();
+    let dl = config.find("dl").and_then(|d| d.as_string()).unwrap();
+    assert!(dl.ends_with("/api/v1/crates"));
+}
+
+#[test]
+fn serves_index_file() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+
+    // Available straight away, before the git index has been updated
+    let mut resp = ok_resp!(middle.call(req.with_method(Method::Get)
+                                           .with_path("/index/3/f/foo")));
+    assert!(resp.headers.contains_key("ETag"));
+    assert!(resp.headers.contains_key("Last-Modified"));
+    let contents = body(&mut resp);
+    assert_eq!(contents.lines().count(), 1);
+    let line = Json::from_str(contents.lines().next().unwrap()).unwrap();
+    assert_eq!(line.find("vers").and_then(|v| v.as_string()), Some("1.0.0"));
+
+    ::run_jobs(&mut req);
+    let mut file = String::new();
+    ::std::fs::File::open(::git::checkout().join("3/f/foo")).unwrap()
+        .read_to_string(&mut file).unwrap();
+    let git_line = Json::from_str(file.trim()).unwrap();
+    assert_eq!(git_line.find("cksum"), line.find("cksum"));
+    assert_eq!(git_line.find("deps"), line.find("deps"));
+}
+
+#[test]
+fn unchanged_file_not_modified() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+
+    let resp = ok_resp!(middle.call(req.with_method(Method::Get)
+                                       .with_path("/index/3/f/foo")));
+    let etag = resp.headers["ETag"][0].clone();
+    let resp = t_resp!(middle.call(req.header("If-None-Match", &etag)));
+    assert_eq!(resp.status.0, 304);
+
+    // Yanking changes the file
+    ok_resp!(middle.call(req.with_method(Method::Delete)
+                            .with_path("/api/v1/crates/foo/1.0.0/yank")));
+    let mut resp = ok_resp!(middle.call(req.with_method(Method::Get)
+                                           .with_path("/index/3/f/foo")));
+    assert!(resp.headers["ETag"][0] != etag);
+    assert!(body(&mut resp).contains("\"yanked\":true"));
+}
+
+#[test]
+fn missing_file() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/index/3/f/foo");
+    let resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 404);
+
+    // Only the path computed from the crate's name is served
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    let resp = t_resp!(middle.call(req.with_path("/index/fo/o/foo")));
+    assert_eq!(resp.status.0, 404);
+    ok_resp!(middle.call(req.with_path("/index/3/f/foo")));
+}
+
+#[test]
+fn served_under_published_name() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/index/fo/o_/foo_bar");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("Foo_Bar"));
+    let mut resp = ok_resp!(middle.call(&mut req));
+    let contents = body(&mut resp);
+    let line = Json::from_str(contents.trim()).unwrap();
+    assert_eq!(line.find("name").and_then(|v| v.as_string()), Some("Foo_Bar"));
+
+    // The name matches the crate, but that isn't the file it's listed in
+    let resp = t_resp!(middle.call(req.with_path("/index/fo/o-/foo-bar")));
+    assert_eq!(resp.status.0, 404);
+}
+
+#[test]
+fn auth_required_rejects_anonymous_requests() {
+    let (_b, app, middle) = ::app_with_config(|c| c.auth_required = true);
+    let mut req = ::req(app, Method::Get, "/index/3/f/foo");
+    let user = ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    ::logout(&mut req);
+    let mut resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 403);
+    let json = ::json::<::Bad>(&mut resp);
+    assert!(json.errors[0].detail.contains("must be logged in"),
+            "{:?}", json.errors);
+
+    ::mock_user(&mut req, user);
+    ok_resp!(middle.call(&mut req));
+}
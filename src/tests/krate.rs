@@ -2,16 +2,26 @@ use std::collections::HashMap;
 use std::io::prelude::*;
 use std::fs::{self, File};
 use std::iter::repeat;
+use std::sync::Arc;
 
 use conduit::{Handler, Request, Method};
 use conduit_test::MockRequest;
+use flate2::Compression;
+use flate2::write::GzEncoder;
 use git2;
+use openssl::crypto::hash::{hash, Type};
+use rustc_serialize::hex::ToHex;
 use rustc_serialize::{json, Decoder};
 use semver;
+use tar;
 
+use cargo_registry::app::App;
+use cargo_registry::crate_owner_invitation::EncodableCrateOwnerInvitation;
+use cargo_registry::db::RequestTransaction;
 use cargo_registry::dependency::EncodableDependency;
-use cargo_registry::download::EncodableVersionDownload;
+use cargo_registry::download::{self, EncodableVersionDownload};
 use cargo_registry::krate::{Crate, EncodableCrate};
+use cargo_registry::publish::Publish;
 use cargo_registry::upload as u;
 use cargo_registry::user::EncodableUser;
 use cargo_registry::version::EncodableVersion;
@@ -21,9 +31,10 @@ struct CrateList { crates: Vec<EncodableCrate>, meta: CrateMeta }
 #[derive(RustcDecodable)]
 struct VersionsList { versions: Vec<EncodableVersion> }
 #[derive(RustcDecodable)]
-struct CrateMeta { total: i32 }
+struct CrateMeta { total: i32, next_page: Option<String> }
 #[derive(RustcDecodable)]
-struct GitCrate { name: String, vers: String, deps: Vec<String>, cksum: String }
+struct GitCrate { name: String, vers: String, deps: Vec<String>, cksum: String,
+                 yanked: Option<bool> }
 #[derive(RustcDecodable)]
 struct GoodCrate { krate: EncodableCrate }
 #[derive(RustcDecodable)]
@@ -106,6 +117,171 @@ fn index_queries() {
     assert_eq!(::json::<CrateList>(&mut response).crates.len(), 0);
 }
 
+#[test]
+fn index_combined_filters() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates");
+    let u = ::mock_user(&mut req, ::user("foo"));
+    let mut krate = ::krate("foo");
+    krate.keywords.push("kw1".to_string());
+    krate.license = Some("MIT/Apache-2.0".to_string());
+    krate.repository = Some("https://example.com".to_string());
+    ::mock_crate(&mut req, krate);
+    let mut krate = ::krate("bar");
+    krate.keywords.push("kw1".to_string());
+    krate.license = Some("MIT".to_string());
+    ::mock_crate(&mut req, krate);
+    let mut krate = ::krate("baz");
+    krate.license = Some("Apache-2.0 OR BSD-3-Clause".to_string());
+    ::mock_crate(&mut req, krate);
+
+    let mut list = |query: &str| {
+        let mut response = ok_resp!(middle.call(req.with_query(query)));
+        let json = ::json::<CrateList>(&mut response);
+        assert_eq!(json.meta.total as usize, json.crates.len());
+        let mut names = json.crates.into_iter().map(|c| c.name)
+                            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+
+    assert_eq!(list("keyword=kw1"), ["bar", "foo"]);
+    assert_eq!(list("keyword=kw1&has_repository=true"), ["foo"]);
+    assert_eq!(list("has_repository=false"), ["bar", "baz"]);
+    assert_eq!(list("q=foo&keyword=kw1"), ["foo"]);
+    assert_eq!(list("license=mit"), ["bar", "foo"]);
+    assert_eq!(list("license=Apache-2.0&keyword=kw1"), ["foo"]);
+    assert_eq!(list("license=apache-2.0"), ["baz", "foo"]);
+    assert_eq!(list("license=bsd-3-clause"), ["baz"]);
+    assert_eq!(list("updated_since=2000-01-01").len(), 3);
+    assert_eq!(list("updated_since=2999-01-01T00:00:00Z").len(), 0);
+    assert_eq!(list(&format!("user_id={}&letter=b", u.id)), ["bar", "baz"]);
+    assert_eq!(list("letter=b&keyword=kw1&license=mit"), ["bar"]);
+}
+
+#[test]
+fn index_seek() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    ::mock_crate(&mut req, ::krate("bar"));
+    ::mock_crate(&mut req, ::krate("baz"));
+
+    for sort in ["alpha", "downloads"].iter() {
+        let mut names = Vec::new();
+        let mut query = format!("?sort={}&per_page=2", sort);
+        loop {
+            let mut response = ok_resp!(middle.call(req.with_query(&query[1..])));
+            let json = ::json::<CrateList>(&mut response);
+            assert_eq!(json.meta.total, 3);
+            names.extend(json.crates.into_iter().map(|c| c.name));
+            match json.meta.next_page {
+                Some(next) => query = next,
+                None => break,
+            }
+        }
+        names.sort();
+        assert_eq!(names, ["bar", "baz", "foo"]);
+    }
+
+    // A new crate before the last one seen doesn't shift the next page
+    let mut response = ok_resp!(middle.call(req.with_query("per_page=2")));
+    let next = ::json::<CrateList>(&mut response).meta.next_page.unwrap();
+    ::mock_crate(&mut req, ::krate("aaa"));
+    let mut response = ok_resp!(middle.call(req.with_query(&next[1..])));
+    let json = ::json::<CrateList>(&mut response);
+    assert_eq!(json.crates.len(), 1);
+    assert_eq!(json.crates[0].name, "foo");
+
+    bad_resp!(middle.call(req.with_query(&format!("page=2&{}", &next[1..]))));
+    bad_resp!(middle.call(req.with_query("seek=bogus")));
+}
+
+#[test]
+fn index_bad_filters() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates");
+    bad_resp!(middle.call(req.with_query("has_repository=maybe")));
+    bad_resp!(middle.call(req.with_query("updated_since=yesterday")));
+}
+
+#[test]
+fn search_promotes_name_matches() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates");
+    ::mock_user(&mut req, ::user("foo"));
+    let mut krate = ::krate("other");
+    krate.description = Some("foo foo foo foo".to_string());
+    ::mock_crate(&mut req, krate);
+    let mut krate = ::krate("foo_bar");
+    krate.description = Some("foo foo".to_string());
+    ::mock_crate(&mut req, krate);
+    ::mock_crate(&mut req, ::krate("foo"));
+
+    let mut response = ok_resp!(middle.call(req.with_query("q=foo")));
+    let json = ::json::<CrateList>(&mut response);
+    assert_eq!(json.meta.total, 3);
+    let names = json.crates.iter().map(|c| &c.name[..]).collect::<Vec<_>>();
+    assert_eq!(names, ["foo", "foo_bar", "other"]);
+
+    // Prefixes of names match even if nothing else does
+    let mut response = ok_resp!(middle.call(req.with_query("q=fo")));
+    let json = ::json::<CrateList>(&mut response);
+    let names = json.crates.iter().map(|c| &c.name[..]).collect::<Vec<_>>();
+    assert_eq!(names, ["foo", "foo_bar"]);
+
+    // Other sort orders apply after the name matches, even to crates which
+    // match the text less well
+    let mut krate = ::krate("another");
+    krate.description = Some("foo".to_string());
+    ::mock_crate(&mut req, krate);
+    {
+        let req: &mut Request = &mut req;
+        req.tx().unwrap().execute("UPDATE crates SET downloads = 10
+                                    WHERE name = 'other'", &[]).unwrap();
+        req.tx().unwrap().execute("UPDATE crates SET downloads = 20
+                                    WHERE name = 'another'", &[]).unwrap();
+    }
+    let mut response = ok_resp!(middle.call(req.with_query("q=foo")));
+    let json = ::json::<CrateList>(&mut response);
+    let names = json.crates.iter().map(|c| &c.name[..]).collect::<Vec<_>>();
+    assert_eq!(names, ["foo", "foo_bar", "other", "another"]);
+    let mut response = ok_resp!(middle.call(req.with_query("q=foo&sort=downloads")));
+    let json = ::json::<CrateList>(&mut response);
+    let names = json.crates.iter().map(|c| &c.name[..]).collect::<Vec<_>>();
+    assert_eq!(names, ["foo", "foo_bar", "another", "other"]);
+}
+
+#[test]
+fn empty_search_matches_everything() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    ::mock_crate(&mut req, ::krate("bar"));
+
+    for query in ["q=", "q=%20"].iter() {
+        let mut response = ok_resp!(middle.call(req.with_query(query)));
+        let json = ::json::<CrateList>(&mut response);
+        assert_eq!(json.meta.total, 2);
+    }
+}
+
+#[test]
+fn search_prefix_is_not_a_pattern() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo_bar"));
+    ::mock_crate(&mut req, ::krate("fooxbar"));
+
+    let mut response = ok_resp!(middle.call(req.with_query("q=foo-b")));
+    let json = ::json::<CrateList>(&mut response);
+    let names = json.crates.iter().map(|c| &c.name[..]).collect::<Vec<_>>();
+    assert_eq!(names, ["foo_bar"]);
+}
+
 #[test]
 fn show() {
     let (_b, _app, mut middle) = ::app();
@@ -186,9 +362,11 @@ fn new_bad_names() {
 fn new_krate() {
     let (_b, app, middle) = ::app();
     let mut req = ::new_req(app, "foo", "1.0.0");
-    let user = ::mock_user(&mut req, ::user("foo"));
+    let user = ::user("foo");
+    let token = user.api_token.clone();
+    ::mock_user(&mut req, user);
     ::logout(&mut req);
-    req.header("Authorization", &user.api_token);
+    req.header("Authorization", &token);
     let mut response = ok_resp!(middle.call(&mut req));
     let json: GoodCrate = ::json(&mut response);
     assert_eq!(json.krate.name, "foo");
@@ -199,9 +377,11 @@ fn new_krate() {
 fn new_krate_weird_version() {
     let (_b, app, middle) = ::app();
     let mut req = ::new_req(app, "foo", "0.0.0-pre");
-    let user = ::mock_user(&mut req, ::user("foo"));
+    let user = ::user("foo");
+    let token = user.api_token.clone();
+    ::mock_user(&mut req, user);
     ::logout(&mut req);
-    req.header("Authorization", &user.api_token);
+    req.header("Authorization", &token);
     let mut response = ok_resp!(middle.call(&mut req));
     let json: GoodCrate = ::json(&mut response);
     assert_eq!(json.krate.name, "foo");
@@ -292,12 +472,17 @@ fn new_crate_owner() {
     let mut response = ok_resp!(middle.call(&mut req));
     ::json::<GoodCrate>(&mut response);
 
-    // Flag the second user as an owner
+    // Invite the second user to be an owner, which they have to accept
     let body = r#"{"users":["bar"]}"#;
     let mut response = ok_resp!(middle.call(req.with_path("/api/v1/crates/foo/owners")
                                                .with_method(Method::Put)
                                                .with_body(body.as_bytes())));
     assert!(::json::<O>(&mut response).ok);
+    ::mock_user(&mut req, u2.clone());
+    let mut response = ok_resp!(middle.call(req.with_path("/me/crate_owner_invitations/foo/accept")
+                                               .with_method(Method::Put)));
+    assert!(::json::<O>(&mut response).ok);
+    ::mock_user(&mut req, ::user("foo"));
     bad_resp!(middle.call(req.with_path("/api/v1/crates/foo/owners")
                              .with_method(Method::Put)
                              .with_body(body.as_bytes())));
@@ -336,6 +521,104 @@ fn new_krate_too_big() {
     bad_resp!(middle.call(&mut req));
 }
 
+fn new_req_with_tarball(app: Arc<App>, tarball: &[u8]) -> MockRequest {
+    let new_crate = u::NewCrate {
+        name: u::CrateName("foo".to_string()),
+        vers: u::CrateVersion(semver::Version::parse("1.0.0").unwrap()),
+        features: HashMap::new(),
+        deps: Vec::new(),
+        authors: vec!["foo".to_string()],
+        description: Some("description".to_string()),
+        homepage: None,
+        documentation: None,
+        readme: None,
+        keywords: None,
+        license: Some("MIT".to_string()),
+        license_file: None,
+        repository: None,
+    };
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/new");
+    req.with_body(&::new_crate_to_body_with_tarball(&new_crate, tarball));
+    return req;
+}
+
+fn bad_tarball(tarball: &[u8], msg: &str) {
+    let (_b, app, middle) = ::app();
+    let mut req = new_req_with_tarball(app, tarball);
+    ::mock_user(&mut req, ::user("foo"));
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.contains(msg), "{:?}", json.errors);
+}
+
+#[test]
+fn new_krate_not_gzip() {
+    bad_tarball(b"not a tarball", "not a valid gzip file");
+}
+
+#[test]
+fn new_krate_wrong_manifest() {
+    bad_tarball(&::crate_tarball("bar", "1.0.0"), "missing `foo-1.0.0/Cargo.toml`");
+
+    let manifest = b"[package]\nname = \"bar\"\nversion = \"1.0.0\"\n";
+    bad_tarball(&::tarball(&[("foo-1.0.0/Cargo.toml", &manifest[..])]),
+                "does not match the name");
+
+    let manifest = b"[package]\nname = \"foo\"\nversion = \"2.0.0\"\n";
+    bad_tarball(&::tarball(&[("foo-1.0.0/Cargo.toml", &manifest[..])]),
+                "does not match the version");
+}
+
+#[test]
+fn new_krate_with_readme() {
+    #[derive(RustcDecodable)] struct R { readme: Readme }
+    #[derive(RustcDecodable)] struct Readme { content: String }
+
+    let (_b, app, middle) = ::app();
+    let manifest = b"[package]\nname = \"foo\"\nversion = \"1.0.0\"\n\
+                     readme = \"docs/intro.md\"\n";
+    let tarball = ::tarball(&[("foo-1.0.0/Cargo.toml", &manifest[..]),
+                              ("foo-1.0.0/README.md", &b"wrong"[..]),
+                              ("foo-1.0.0/docs/intro.md", &b"# foo"[..])]);
+    let mut req = new_req_with_tarball(app, &tarball);
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+
+    req.with_method(Method::Get).with_path("/api/v1/crates/foo/1.0.0/readme");
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json: R = ::json(&mut response);
+    assert_eq!(json.readme.content, "# foo");
+}
+
+#[test]
+fn new_krate_path_outside_prefix() {
+    let manifest = b"[package]\nname = \"foo\"\nversion = \"1.0.0\"\n";
+    bad_tarball(&::tarball(&[("foo-1.0.0/Cargo.toml", &manifest[..]),
+                             ("bar/lib.rs", &b""[..])]),
+                "all files must be within `foo-1.0.0/`");
+}
+
+#[test]
+fn new_krate_symlink_outside_prefix() {
+    let manifest = b"[package]\nname = \"foo\"\nversion = \"1.0.0\"\n";
+    let mut ar = tar::Builder::new(GzEncoder::new(Vec::new(),
+                                                  Compression::Default));
+    let mut header = tar::Header::new_gnu();
+    header.set_path("foo-1.0.0/Cargo.toml").unwrap();
+    header.set_size(manifest.len() as u64);
+    header.set_cksum();
+    ar.append(&header, &manifest[..]).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_path("foo-1.0.0/src/lib.rs").unwrap();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_link_name("../../etc/passwd").unwrap();
+    header.set_size(0);
+    header.set_cksum();
+    ar.append(&header, &b""[..]).unwrap();
+    let tarball = ar.into_inner().unwrap().finish().unwrap();
+
+    bad_tarball(&tarball, "points outside of `foo-1.0.0/`");
+}
+
 #[test]
 fn new_krate_duplicate_version() {
     let (_b, app, middle) = ::app();
@@ -388,7 +671,10 @@ fn new_krate_git_upload() {
     let mut response = ok_resp!(middle.call(&mut req));
     ::json::<GoodCrate>(&mut response);
 
+    // The index isn't updated until the background job runs
     let path = ::git::checkout().join("3/f/foo");
+    assert!(!path.exists());
+    ::run_jobs(&mut req);
     assert!(path.exists());
     let mut contents = String::new();
     File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
@@ -396,8 +682,20 @@ fn new_krate_git_upload() {
     assert_eq!(p.name, "foo");
     assert_eq!(p.vers, "1.0.0");
     assert!(p.deps.is_empty());
-    assert_eq!(p.cksum,
-               "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    let tarball = ::crate_tarball("foo", "1.0.0");
+    assert_eq!(p.cksum, hash(Type::SHA256, &tarball).to_hex());
+}
+
+#[test]
+fn new_krate_stored() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    let mut response = ok_resp!(middle.call(&mut req));
+    ::json::<GoodCrate>(&mut response);
+
+    let path = ::git::storage().join("crates/foo/foo-1.0.0.crate");
+    assert!(path.exists());
 }
 
 #[test]
@@ -413,6 +711,7 @@ fn new_krate_git_upload_appends() {
     ::mock_user(&mut req, ::user("foo"));
     let mut response = ok_resp!(middle.call(&mut req));
     ::json::<GoodCrate>(&mut response);
+    ::run_jobs(&mut req);
 
     let mut contents = String::new();
     File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
@@ -446,6 +745,7 @@ fn new_krate_git_upload_with_conflicts() {
     ::mock_user(&mut req, ::user("foo"));
     let mut response = ok_resp!(middle.call(&mut req));
     ::json::<GoodCrate>(&mut response);
+    ::run_jobs(&mut req);
 }
 
 #[test]
@@ -481,8 +781,13 @@ fn download() {
     let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
     ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
-    let resp = t_resp!(middle.call(&mut req));
-    assert_eq!(resp.status.0, 302);
+    let path = ::git::storage().join("crates/foo/foo-1.0.0.crate");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    File::create(&path).unwrap().write_all(b"contents").unwrap();
+    let mut resp = ok_resp!(middle.call(&mut req));
+    let mut body = Vec::new();
+    resp.body.read_to_end(&mut body).unwrap();
+    assert_eq!(body, b"contents");
 
     req.with_path("/api/v1/crates/foo/1.0.0/downloads");
     let mut resp = ok_resp!(middle.call(&mut req));
@@ -490,8 +795,7 @@ fn download() {
     assert_eq!(downloads.version_downloads.len(), 1);
 
     req.with_path("/api/v1/crates/FOO/1.0.0/download");
-    let resp = t_resp!(middle.call(&mut req));
-    assert_eq!(resp.status.0, 302);
+    ok_resp!(middle.call(&mut req));
 
     req.with_path("/api/v1/crates/FOO/1.0.0/downloads");
     let mut resp = ok_resp!(middle.call(&mut req));
@@ -499,6 +803,247 @@ fn download() {
     assert_eq!(downloads.version_downloads.len(), 1);
 }
 
+#[test]
+fn download_auth_required() {
+    let (_b, app, middle) = ::app_with_config(|c| c.auth_required = true);
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
+    let user = ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    let path = ::git::storage().join("crates/foo/foo-1.0.0.crate");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    File::create(&path).unwrap().write_all(b"contents").unwrap();
+    ::logout(&mut req);
+    let mut resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 403);
+    let json = ::json::<::Bad>(&mut resp);
+    assert!(json.errors[0].detail.contains("must be logged in"),
+            "{:?}", json.errors);
+
+    ::mock_user(&mut req, user);
+    ok_resp!(middle.call(&mut req));
+}
+
+#[test]
+fn download_counts_in_one_row_per_day() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
+    ::mock_user(&mut req, ::user("foo"));
+    let (_, version) = ::mock_crate(&mut req, ::krate("foo"));
+    let req: &mut Request = &mut req;
+    let tx = req.tx().unwrap();
+    for _ in 0..3 {
+        download::increment(tx, version.id).unwrap();
+    }
+    let stmt = tx.prepare("SELECT downloads FROM version_downloads
+                           WHERE version_id = $1").unwrap();
+    let rows = stmt.query(&[&version.id]).unwrap();
+    let downloads = rows.iter().map(|r| r.get::<_, i32>(0)).collect::<Vec<_>>();
+    assert_eq!(downloads, [3]);
+}
+
+#[test]
+fn download_records_clients() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
+    ::mock_user(&mut req, ::user("foo"));
+    let (_, version) = ::mock_crate(&mut req, ::krate("foo"));
+    let path = ::git::storage().join("crates/foo/foo-1.0.0.crate");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    File::create(&path).unwrap().write_all(b"contents").unwrap();
+
+    ok_resp!(middle.call(req.header("User-Agent", "cargo 0.7.0 (abc 2015-12-01)")));
+    ok_resp!(middle.call(&mut req));
+    ok_resp!(middle.call(req.header("User-Agent", "Mozilla/5.0 (X11; Linux)")));
+
+    let req: &mut Request = &mut req;
+    let stmt = req.tx().unwrap()
+                  .prepare("SELECT client, downloads, fingerprint
+                              FROM version_download_clients
+                             WHERE version_id = $1
+                             ORDER BY client ASC").unwrap();
+    let rows = stmt.query(&[&version.id]).unwrap();
+    let clients = rows.iter().map(|row| {
+        let client: String = row.get("client");
+        let downloads: i32 = row.get("downloads");
+        let fingerprint: String = row.get("fingerprint");
+        // The address and User-Agent aren't recorded as they are
+        assert_eq!(fingerprint.len(), 32);
+        (client, downloads)
+    }).collect::<Vec<_>>();
+    assert_eq!(clients, [("browser".to_string(), 1), ("cargo".to_string(), 2)]);
+}
+
+#[test]
+fn download_ranges() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/downloads");
+    ::mock_user(&mut req, ::user("foo"));
+    let (krate, v1) = ::mock_crate(&mut req, ::krate("foo"));
+    let v2 = semver::Version::parse("2.0.0").unwrap();
+    let (_, v2) = ::mock_crate_vers(&mut req, krate, &v2);
+    {
+        let req: &mut Request = &mut req;
+        let tx = req.tx().unwrap();
+        for &(version, downloads, date) in [(v1.id, 1, "2015-01-05"),
+                                            (v1.id, 2, "2015-01-20"),
+                                            (v2.id, 4, "2015-01-20"),
+                                            (v2.id, 8, "2015-02-01")].iter() {
+            tx.execute("INSERT INTO version_downloads
+                        (version_id, downloads, counted, date, processed)
+                        VALUES ($1, $2, 0, $3::text::timestamp, FALSE)",
+                       &[&version, &downloads, &date]).unwrap();
+        }
+    }
+
+    // Nothing in the default range of the last 90 days
+    let mut resp = ok_resp!(middle.call(&mut req));
+    assert_eq!(::json::<Downloads>(&mut resp).version_downloads.len(), 0);
+
+    req.with_query("from=2015-01-01&to=2015-01-31");
+    let mut resp = ok_resp!(middle.call(&mut req));
+    let downloads = ::json::<Downloads>(&mut resp).version_downloads;
+    let days = downloads.iter().map(|d| (d.version, &d.date[..10], d.downloads))
+                        .collect::<Vec<_>>();
+    assert_eq!(days, [(v1.id, "2015-01-05", 1), (v1.id, "2015-01-20", 2),
+                      (v2.id, "2015-01-20", 4)]);
+
+    req.with_query("from=2015-01-01&to=2015-02-28&granularity=month");
+    let mut resp = ok_resp!(middle.call(&mut req));
+    let downloads = ::json::<Downloads>(&mut resp).version_downloads;
+    let months = downloads.iter().map(|d| (d.version, &d.date[..10], d.downloads))
+                          .collect::<Vec<_>>();
+    assert_eq!(months, [(v1.id, "2015-01-01", 3), (v2.id, "2015-01-01", 4),
+                        (v2.id, "2015-02-01", 8)]);
+
+    req.with_query("from=2015-01-01&to=2015-02-28&granularity=month&format=csv");
+    let mut resp = ok_resp!(middle.call(&mut req));
+    let mut csv = String::new();
+    resp.body.read_to_string(&mut csv).unwrap();
+    assert_eq!(csv.lines().collect::<Vec<_>>(),
+               ["date,version,downloads,unique_downloads", "2015-01-01,1.0.0,3,0",
+                "2015-01-01,2.0.0,4,0", "2015-02-01,2.0.0,8,0"]);
+
+    req.with_path("/api/v1/crates/foo/2.0.0/downloads")
+       .with_query("from=2015-01-01&to=2015-02-28&granularity=week");
+    let mut resp = ok_resp!(middle.call(&mut req));
+    let downloads = ::json::<Downloads>(&mut resp).version_downloads;
+    let weeks = downloads.iter().map(|d| (&d.date[..10], d.downloads))
+                         .collect::<Vec<_>>();
+    assert_eq!(weeks, [("2015-01-19", 4), ("2015-01-26", 8)]);
+
+    bad_resp!(middle.call(req.with_query("granularity=year")));
+    bad_resp!(middle.call(req.with_query("from=2015-02-01&to=2015-01-01")));
+    bad_resp!(middle.call(req.with_path("/api/v1/crates/foo/downloads")
+                             .with_query("versions=some")));
+}
+
+#[test]
+fn download_local() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
+    ::mock_user(&mut req, ::user("foo"));
+    let (_, mut v) = ::mock_crate(&mut req, ::krate("foo"));
+    {
+        let req: &mut Request = &mut req;
+        v.set_checksum(req.tx().unwrap(), "abcd").unwrap();
+    }
+    let path = ::git::storage().join("crates/foo/foo-1.0.0.crate");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    File::create(&path).unwrap().write_all(b"0123456789").unwrap();
+
+    let resp = ok_resp!(middle.call(&mut req));
+    assert_eq!(resp.headers["Content-Type"], ["application/x-tar"]);
+    assert_eq!(resp.headers["Content-Length"], ["10"]);
+    assert_eq!(resp.headers["ETag"], ["\"abcd\""]);
+
+    req.header("If-None-Match", "\"abcd\"");
+    let resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 304);
+
+    req.header("Range", "bytes=2-5");
+    let mut resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 206);
+    assert_eq!(resp.headers["Content-Range"], ["bytes 2-5/10"]);
+    let mut body = Vec::new();
+    resp.body.read_to_end(&mut body).unwrap();
+    assert_eq!(body, b"2345");
+
+    req.header("Range", "bytes=-3");
+    let mut resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 206);
+    let mut body = Vec::new();
+    resp.body.read_to_end(&mut body).unwrap();
+    assert_eq!(body, b"789");
+
+    req.header("Range", "bytes=10-");
+    let resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 416);
+    assert_eq!(resp.headers["Content-Range"], ["bytes */10"]);
+}
+
+#[test]
+fn download_local_without_checksum() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    let path = ::git::storage().join("crates/foo/foo-1.0.0.crate");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    File::create(&path).unwrap().write_all(b"0123456789").unwrap();
+
+    // Versions published before checksums were recorded get a weak ETag
+    let resp = ok_resp!(middle.call(&mut req));
+    let etag = resp.headers["ETag"][0].clone();
+    assert!(etag.starts_with("W/\"10-"), "{}", etag);
+
+    req.header("If-None-Match", &etag);
+    let resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 304);
+}
+
+#[test]
+fn download_counts_only_whole_files() {
+    fn downloads(req: &mut Request, version_id: i32) -> i32 {
+        let tx = req.tx().unwrap();
+        let stmt = tx.prepare("SELECT COALESCE(SUM(downloads), 0)::int
+                                 FROM version_downloads
+                                WHERE version_id = $1").unwrap();
+        let rows = stmt.query(&[&version_id]).unwrap();
+        rows.iter().next().unwrap().get(0)
+    }
+
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/download");
+    ::mock_user(&mut req, ::user("foo"));
+    let (_, mut v) = ::mock_crate(&mut req, ::krate("foo"));
+    {
+        let req: &mut Request = &mut req;
+        v.set_checksum(req.tx().unwrap(), "abcd").unwrap();
+    }
+    let path = ::git::storage().join("crates/foo/foo-1.0.0.crate");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    File::create(&path).unwrap().write_all(b"0123456789").unwrap();
+
+    ok_resp!(middle.call(&mut req));
+    assert_eq!(downloads(&mut req, v.id), 1);
+
+    // A conditional request for a file the client already has isn't counted
+    req.header("If-None-Match", "\"abcd\"");
+    let resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 304);
+    assert_eq!(downloads(&mut req, v.id), 1);
+
+    req.header("If-None-Match", "\"other\"");
+    ok_resp!(middle.call(&mut req));
+    assert_eq!(downloads(&mut req, v.id), 2);
+
+    // Neither is resuming a download with a range
+    req.header("Range", "bytes=5-");
+    let resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 206);
+    assert_eq!(downloads(&mut req, v.id), 2);
+}
+
 #[test]
 fn download_bad() {
     let (_b, _app, mut middle) = ::app();
@@ -583,14 +1128,17 @@ fn following() {
 
 #[test]
 fn owners() {
-    #[derive(RustcDecodable)] struct R { users: Vec<EncodableUser> }
+    #[derive(RustcDecodable)]
+    struct R {
+        users: Vec<EncodableUser>,
+        pending_invitations: Vec<EncodableCrateOwnerInvitation>,
+    }
     #[derive(RustcDecodable)] struct O { ok: bool }
 
     let (_b, app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/owners");
-    let other = ::user("foobar");
-    ::mock_user(&mut req, other);
-    ::mock_user(&mut req, ::user("foo"));
+    let other = ::mock_user(&mut req, ::user("foobar"));
+    let me = ::mock_user(&mut req, ::user("foo"));
     ::mock_crate(&mut req, ::krate("foo"));
 
     let mut response = ok_resp!(middle.call(&mut req));
@@ -606,9 +1154,24 @@ fn owners() {
                                                .with_body(body.as_bytes())));
     assert!(::json::<O>(&mut response).ok);
 
+    // Invitees are listed separately until they accept
     let mut response = ok_resp!(middle.call(req.with_method(Method::Get)));
     let r: R = ::json(&mut response);
+    assert_eq!(r.users.len(), 1);
+    assert_eq!(r.pending_invitations.len(), 1);
+    assert_eq!(r.pending_invitations[0].invited_user, "foobar");
+    assert_eq!(r.pending_invitations[0].invited_by, "foo");
+
+    ::mock_user(&mut req, other.clone());
+    ok_resp!(middle.call(req.with_path("/me/crate_owner_invitations/foo/accept")
+                            .with_method(Method::Put)));
+    ::mock_user(&mut req, me.clone());
+
+    let mut response = ok_resp!(middle.call(req.with_path("/api/v1/crates/foo/owners")
+                                               .with_method(Method::Get)));
+    let r: R = ::json(&mut response);
     assert_eq!(r.users.len(), 2);
+    assert_eq!(r.pending_invitations.len(), 0);
 
     let body = r#"{"users":["foobar"]}"#;
     let mut response = ok_resp!(middle.call(req.with_method(Method::Delete)
@@ -642,6 +1205,7 @@ fn yank() {
     ::mock_user(&mut req, ::user("foo"));
     let mut response = ok_resp!(middle.call(&mut req));
     ::json::<GoodCrate>(&mut response);
+    ::run_jobs(&mut req);
     let mut contents = String::new();
     File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
     assert!(contents.contains("\"yanked\":false"));
@@ -655,6 +1219,7 @@ fn yank() {
     let mut r = ok_resp!(middle.call(req.with_method(Method::Delete)
                                         .with_path("/api/v1/crates/foo/1.0.0/yank")));
     assert!(::json::<O>(&mut r).ok);
+    ::run_jobs(&mut req);
     let mut contents = String::new();
     File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
     assert!(contents.contains("\"yanked\":true"));
@@ -666,6 +1231,7 @@ fn yank() {
     let mut r = ok_resp!(middle.call(req.with_method(Method::Put)
                                         .with_path("/api/v1/crates/foo/1.0.0/unyank")));
     assert!(::json::<O>(&mut r).ok);
+    ::run_jobs(&mut req);
     let mut contents = String::new();
     File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
     assert!(contents.contains("\"yanked\":false"));
@@ -800,3 +1366,293 @@ fn author_license_and_description_required() {
             !json.errors[0].detail.contains("license"),
             "{:?}", json.errors);
 }
+
+#[test]
+fn rename() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+
+    let body = r#"{"name":"bar"}"#;
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Put)
+                                               .with_path("/api/v1/crates/foo/rename")
+                                               .with_body(body.as_bytes())));
+    assert_eq!(::json::<GoodCrate>(&mut response).krate.name, "bar");
+    ::run_jobs(&mut req);
+
+    // Versions published before the rename stay under the old name, which is
+    // the one their tarballs contain, and the new name gets an empty file
+    let read = |path: &str| {
+        let mut contents = String::new();
+        File::open(&::git::checkout().join(path)).unwrap()
+            .read_to_string(&mut contents).unwrap();
+        contents.lines().map(|l| json::decode::<GitCrate>(l).unwrap())
+                .collect::<Vec<_>>()
+    };
+    assert_eq!(read("3/b/bar").len(), 0);
+    let lines = read("3/f/foo");
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].name, "foo");
+    assert_eq!(lines[0].vers, "1.0.0");
+
+    // and are yanked there
+    ok_resp!(middle.call(req.with_method(Method::Delete)
+                            .with_path("/api/v1/crates/bar/1.0.0/yank")));
+    ::run_jobs(&mut req);
+    assert_eq!(read("3/f/foo")[0].yanked, Some(true));
+
+    // New versions go under the new name
+    let body = ::new_req_body(::krate("bar"), "2.0.0", Vec::new());
+    ok_resp!(middle.call(req.with_method(Method::Put)
+                            .with_path("/api/v1/crates/new")
+                            .with_body(&body)));
+    ::run_jobs(&mut req);
+    let lines = read("3/b/bar");
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0].name, "bar");
+    assert_eq!(lines[0].vers, "2.0.0");
+    assert_eq!(read("3/f/foo").len(), 1);
+
+    {
+        let req: &mut Request = &mut req;
+        let krate = Crate::find_by_name(req.tx().unwrap(), "FOO").unwrap();
+        assert_eq!(krate.name, "bar");
+    }
+
+    let response = t_resp!(middle.call(req.with_method(Method::Get)
+                                          .with_path("/api/v1/crates/foo")));
+    assert_eq!(response.status.0, 302);
+    assert_eq!(response.headers["Location"], vec!["/api/v1/crates/bar"]);
+    let mut response = ok_resp!(middle.call(req.with_path("/api/v1/crates/bar")));
+    assert_eq!(::json::<CrateResponse>(&mut response).krate.name, "bar");
+
+    let path = "/api/v1/crates/foo/1.0.0/download";
+    let response = t_resp!(middle.call(req.with_path(path)));
+    assert_eq!(response.status.0, 302);
+    assert_eq!(response.headers["Location"],
+               vec!["/api/v1/crates/bar/1.0.0/download"]);
+}
+
+#[test]
+fn rename_back_to_old_name() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/foo/rename");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    {
+        let req: &mut Request = &mut req;
+        let mut krate = Crate::find_by_name(req.tx().unwrap(), "foo").unwrap();
+        krate.rename(req.tx().unwrap(), "bar").unwrap();
+        krate.rename(req.tx().unwrap(), "foo").unwrap();
+        let krate = Crate::find_by_name(req.tx().unwrap(), "bar").unwrap();
+        assert_eq!(krate.name, "foo");
+    }
+}
+
+#[test]
+fn download_after_rename() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+
+    for name in ["Foo", "bar"].iter() {
+        // The old name keeps resolving to the crate
+        let body = format!(r#"{{"name":"{}"}}"#, name);
+        ok_resp!(middle.call(req.with_method(Method::Put)
+                                .with_path("/api/v1/crates/foo/rename")
+                                .with_body(body.as_bytes())));
+
+        let path = format!("/api/v1/crates/{}/1.0.0/download", name);
+        let mut resp = ok_resp!(middle.call(req.with_method(Method::Get)
+                                               .with_path(&path)));
+        let mut body = Vec::new();
+        resp.body.read_to_end(&mut body).unwrap();
+        assert_eq!(body, ::crate_tarball("foo", "1.0.0"));
+    }
+}
+
+#[test]
+fn dependency_on_old_name_after_rename() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ok_resp!(middle.call(&mut req));
+    let body = r#"{"name":"bar"}"#;
+    ok_resp!(middle.call(req.with_method(Method::Put)
+                            .with_path("/api/v1/crates/foo/rename")
+                            .with_body(body.as_bytes())));
+
+    // Each dependency is listed under the name whose index file has the
+    // versions it was published against
+    for (krate, dep_name) in vec![("baz", "foo"), ("quux", "bar")] {
+        let dep = u::CrateDependency {
+            name: u::CrateName(dep_name.to_string()),
+            optional: false,
+            default_features: true,
+            features: Vec::new(),
+            version_req: u::CrateVersionReq(semver::VersionReq::parse(">= 0").unwrap()),
+            target: None,
+            kind: None,
+        };
+        let body = ::new_req_body(::krate(krate), "1.0.0", vec![dep]);
+        ok_resp!(middle.call(req.with_method(Method::Put)
+                                .with_path("/api/v1/crates/new")
+                                .with_body(&body)));
+        ::run_jobs(&mut req);
+
+        let path = ::cargo_registry::git::index_file(&::git::checkout(), krate);
+        let mut contents = String::new();
+        File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        let line = json::Json::from_str(contents.trim()).unwrap();
+        let deps = line.find("deps").and_then(|d| d.as_array()).unwrap();
+        assert_eq!(deps[0].find("name").and_then(|n| n.as_string()),
+                   Some(dep_name));
+    }
+}
+
+#[test]
+fn rename_requires_owner() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/foo/rename");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    ::mock_user(&mut req, ::user("bar"));
+    let body = r#"{"name":"bar"}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.contains("only owners"), "{:?}", json.errors);
+}
+
+#[test]
+fn rename_to_taken_name() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/foo/rename");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    ::mock_crate(&mut req, ::krate("bar"));
+    let body = r#"{"name":"Bar"}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.contains("already taken"), "{:?}", json.errors);
+}
+
+#[test]
+fn old_name_stays_reserved() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    {
+        let req: &mut Request = &mut req;
+        let mut krate = Crate::find_by_name(req.tx().unwrap(), "foo").unwrap();
+        krate.rename(req.tx().unwrap(), "bar").unwrap();
+    }
+    ::mock_user(&mut req, ::user("baz"));
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.contains("has been renamed to `bar`"),
+            "{:?}", json.errors);
+}
+
+fn assert_not_published(req: &mut Request, name: &str, vers: &str) {
+    assert!(Crate::find_by_name(req.tx().unwrap(), name).is_err());
+    let path = ::git::storage().join(format!("crates/{0}/{0}-{1}.crate",
+                                             name, vers));
+    assert!(!path.exists());
+    ::run_jobs(req);
+    assert!(!::git::checkout().join("3/f/foo").is_file());
+}
+
+#[test]
+fn new_krate_rolled_back_on_db_failure() {
+    let (_b, app, middle) = ::app();
+    let dep = u::CrateDependency {
+        name: u::CrateName("missing".to_string()),
+        optional: false,
+        default_features: true,
+        features: Vec::new(),
+        version_req: u::CrateVersionReq(semver::VersionReq::parse(">= 0").unwrap()),
+        target: None,
+        kind: None,
+    };
+    let mut req = ::new_req_full(app, ::krate("foo"), "1.0.0", vec![dep]);
+    ::mock_user(&mut req, ::user("foo"));
+    bad_resp!(middle.call(&mut req));
+    assert_not_published(&mut req, "foo", "1.0.0");
+}
+
+#[test]
+fn new_krate_rolled_back_on_storage_failure() {
+    let (_b, app, middle) = ::app();
+    // A file where the crate's storage directory should be
+    let path = ::git::storage().join("crates/foo");
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    File::create(&path).unwrap();
+
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    assert!(middle.call(&mut req).is_err());
+    assert_not_published(&mut req, "foo", "1.0.0");
+}
+
+#[test]
+fn publish_undone_when_not_finished() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app.clone(), Method::Put, "/api/v1/crates/new");
+    let user = ::mock_user(&mut req, ::user("foo"));
+    {
+        let req: &mut Request = &mut req;
+        let mut publish = Publish::begin(&app, req.tx().unwrap()).unwrap();
+        Crate::find_or_insert(publish.conn(), "foo", user.id, &None, &None,
+                              &None, &None, &[], &None, &None, &None).unwrap();
+        let cksum = publish.upload("crates/foo/foo-1.0.0.crate",
+                                   b"contents").unwrap();
+        publish.add_to_index(::cargo_registry::git::Crate {
+            name: "foo".to_string(),
+            vers: "1.0.0".to_string(),
+            deps: Vec::new(),
+            cksum: cksum,
+            features: HashMap::new(),
+            yanked: Some(false),
+        }).unwrap();
+        assert!(::git::storage().join("crates/foo/foo-1.0.0.crate").exists());
+    }
+    assert_not_published(&mut req, "foo", "1.0.0");
+}
+
+#[test]
+fn publish_kept_when_finished() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app.clone(), Method::Put, "/api/v1/crates/new");
+    let user = ::mock_user(&mut req, ::user("foo"));
+    let req: &mut Request = &mut req;
+    {
+        let mut publish = Publish::begin(&app, req.tx().unwrap()).unwrap();
+        Crate::find_or_insert(publish.conn(), "foo", user.id, &None, &None,
+                              &None, &None, &[], &None, &None, &None).unwrap();
+        publish.upload("crates/foo/foo-1.0.0.crate", b"contents").unwrap();
+        publish.finish(req).unwrap();
+    }
+    assert!(Crate::find_by_name(req.tx().unwrap(), "foo").is_ok());
+    assert!(::git::storage().join("crates/foo/foo-1.0.0.crate").exists());
+}
+
+#[test]
+fn finished_publish_undone_when_request_rolled_back() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app.clone(), Method::Put, "/api/v1/crates/new");
+    let user = ::mock_user(&mut req, ::user("foo"));
+    {
+        let req: &mut Request = &mut req;
+        let mut publish = Publish::begin(&app, req.tx().unwrap()).unwrap();
+        Crate::find_or_insert(publish.conn(), "foo", user.id, &None, &None,
+                              &None, &None, &[], &None, &None, &None).unwrap();
+        publish.upload("crates/foo/foo-1.0.0.crate", b"contents").unwrap();
+        publish.finish(req).unwrap();
+    }
+    let path = ::git::storage().join("crates/foo/foo-1.0.0.crate");
+    assert!(path.exists());
+
+    // The request's transaction is never marked to be committed
+    drop(req);
+    assert!(!path.exists());
+}
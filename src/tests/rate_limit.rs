@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use conduit::{Handler, Method, Request, Response};
+use conduit_middleware::MiddlewareBuilder;
+use time::{self, Duration};
+
+use cargo_registry::app::AppMiddleware;
+use cargo_registry::db::RequestTransaction;
+use cargo_registry::rate_limit::{self, RateLimit, RateLimited};
+use cargo_registry::util::{C, CargoError, CargoResult, RequestUtils};
+
+/// The rate limits of the test app, where only the `limited` group, which no
+/// routes belong to, is limited.
+pub fn limits() -> HashMap<String, RateLimit> {
+    let mut limits = HashMap::new();
+    limits.insert("limited".to_string(),
+                  RateLimit { burst: 1.0, per_second: 1.0 / 60.0 });
+    limits
+}
+
+#[test]
+fn parse() {
+    let limit = RateLimit::parse("10/60").unwrap();
+    assert_eq!(limit.burst, 10.0);
+    assert_eq!(limit.per_second, 10.0 / 60.0);
+    assert!(RateLimit::parse("10").is_none());
+    assert!(RateLimit::parse("0/60").is_none());
+    assert!(RateLimit::parse("10/0").is_none());
+}
+
+#[test]
+fn takes_tokens_until_empty() {
+    let (_b, app, _middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/");
+    let req: &mut Request = &mut req;
+    let tx = req.tx().unwrap();
+    let limit = RateLimit { burst: 2.0, per_second: 1.0 };
+    let now = time::now_utc().to_timespec();
+
+    rate_limit::take(tx, "test", "user:1", &limit, now).unwrap();
+    rate_limit::take(tx, "test", "user:1", &limit, now).unwrap();
+    let err = rate_limit::take(tx, "test", "user:1", &limit, now).unwrap_err();
+    let resp = err.response().unwrap();
+    assert_eq!(resp.status.0, 429);
+    assert_eq!(resp.headers["Retry-After"], vec!["1".to_string()]);
+
+    // Other clients and groups have their own buckets
+    rate_limit::take(tx, "test", "ip:127.0.0.1", &limit, now).unwrap();
+    rate_limit::take(tx, "other", "user:1", &limit, now).unwrap();
+
+    // And the bucket refills over time
+    let later = now + Duration::seconds(1);
+    rate_limit::take(tx, "test", "user:1", &limit, later).unwrap();
+    assert!(rate_limit::take(tx, "test", "user:1", &limit, later).is_err());
+}
+
+#[test]
+fn limited_requests_are_rejected() {
+    fn ok(req: &mut Request) -> CargoResult<Response> {
+        Ok(req.json(&true))
+    }
+
+    let (_b, app, _middle) = ::app();
+    let mut middle = MiddlewareBuilder::new(RateLimited { group: "limited",
+                                                          handler: C(ok) });
+    middle.add(AppMiddleware::new(app.clone()));
+    let mut req = ::req(app.clone(), Method::Get, "/");
+    let user = ::mock_user(&mut req, ::user("foo"));
+
+    ok_resp!(middle.call(&mut req));
+    let resp = t_resp!(middle.call(&mut req));
+    assert_eq!(resp.status.0, 429);
+    assert_eq!(resp.headers["Retry-After"], vec!["60".to_string()]);
+
+    // Buckets are kept outside of the request's transaction, which holds the
+    // only connection of the test app's pool
+    let conn = app.rate_limit_database.get().unwrap();
+    conn.execute("DELETE FROM rate_limit_buckets
+                   WHERE route_group = 'limited' AND key = $1",
+                 &[&format!("user:{}", user.id)]).unwrap();
+}
@@ -1,7 +1,11 @@
 use std::sync::ONCE_INIT;
-use conduit::{Handler, Method};
+use conduit::{Handler, Request, Method};
+use conduit_test::MockRequest;
+use time::{self, Duration};
 
 use cargo_registry::User;
+use cargo_registry::db::RequestTransaction;
+use cargo_registry::owner::{Membership, Team};
 use record::GhUser;
 
 // Users: `crates-tester-1` and `crates-tester-2`
@@ -235,3 +239,149 @@ fn add_owners_as_team_owner() {
             "{:?}", json.errors);
 }
 
+
+#[derive(RustcDecodable)]
+struct Member { login: String }
+#[derive(RustcDecodable)]
+struct Members { users: Vec<Member> }
+
+fn members(middle: &Handler, req: &mut MockRequest, team: &str) -> Vec<String> {
+    let path = format!("/api/v1/teams/{}/members", team);
+    let mut response = ok_resp!(middle.call(req.with_path(&path)
+                                               .with_method(Method::Get)));
+    ::json::<Members>(&mut response).users.into_iter().map(|u| u.login)
+                                          .collect()
+}
+
+// Test adding a registry-native team, which needs no calls to Github
+#[test]
+fn native_team() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    let member = ::mock_user(&mut req, ::user("member"));
+    let owner = ::mock_user(&mut req, ::user("owner"));
+    ::mock_crate(&mut req, ::krate("foo"));
+
+    // Whoever adds a new team becomes its first member
+    let body = r#"{"users":["local:release"]}"#;
+    ok_resp!(middle.call(req.with_path("/api/v1/crates/foo/owners")
+                            .with_method(Method::Put)
+                            .with_body(body.as_bytes())));
+    assert_eq!(members(&middle, &mut req, "local:release"), ["owner"]);
+
+    // Others can't publish until they've been made members
+    ::mock_user(&mut req, member.clone());
+    let body = ::new_req_body(::krate("foo"), "2.0.0", vec![]);
+    let json = bad_resp!(middle.call(req.with_path("/api/v1/crates/new")
+                                        .with_body(&body)
+                                        .with_method(Method::Put)));
+    assert!(json.errors[0].detail.contains("another user"),
+            "{:?}", json.errors);
+
+    // Nor can they make themselves members
+    let body = r#"{"users":["member"]}"#;
+    let json = bad_resp!(middle.call(req.with_path("/api/v1/teams/local:release/members")
+                                        .with_method(Method::Put)
+                                        .with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.contains("only members"),
+            "{:?}", json.errors);
+
+    ::mock_user(&mut req, owner.clone());
+    ok_resp!(middle.call(req.with_path("/api/v1/teams/local:release/members")
+                            .with_method(Method::Put)
+                            .with_body(body.as_bytes())));
+    assert_eq!(members(&middle, &mut req, "local:release"), ["member", "owner"]);
+
+    ::mock_user(&mut req, member.clone());
+    let body = ::new_req_body(::krate("foo"), "2.0.0", vec![]);
+    ok_resp!(middle.call(req.with_path("/api/v1/crates/new")
+                            .with_body(&body)
+                            .with_method(Method::Put)));
+
+    // Teams can't be left without any members
+    let body = r#"{"users":["member","owner"]}"#;
+    let json = bad_resp!(middle.call(req.with_path("/api/v1/teams/local:release/members")
+                                        .with_method(Method::Delete)
+                                        .with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.contains("every member"),
+            "{:?}", json.errors);
+
+    let body = r#"{"users":["owner"]}"#;
+    ok_resp!(middle.call(req.with_path("/api/v1/teams/local:release/members")
+                            .with_method(Method::Delete)
+                            .with_body(body.as_bytes())));
+    assert_eq!(members(&middle, &mut req, "local:release"), ["member"]);
+}
+
+#[test]
+fn native_team_bad_name() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("owner"));
+    ::mock_crate(&mut req, ::krate("foo"));
+
+    let body = r#"{"users":["local:a/b"]}"#;
+    let json = bad_resp!(middle.call(req.with_path("/api/v1/crates/foo/owners")
+                                        .with_method(Method::Put)
+                                        .with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.contains("team names may only contain"),
+            "{:?}", json.errors);
+}
+
+// Pretends Github said whether `user` is a member of `team` `age` seconds ago
+fn cache_membership(req: &mut Request, team: &Team, user: &User,
+                    is_member: bool, age: i64) {
+    let checked_at = time::now_utc().to_timespec() - Duration::seconds(age);
+    let tx = req.tx().unwrap();
+    tx.execute("DELETE FROM team_memberships
+                 WHERE team_id = $1 AND user_id = $2",
+               &[&team.id, &user.id]).unwrap();
+    tx.execute("INSERT INTO team_memberships
+                (team_id, user_id, is_member, checked_at)
+                VALUES ($1, $2, $3, $4)",
+               &[&team.id, &user.id, &is_member, &checked_at]).unwrap();
+}
+
+// Test that cached Github memberships are trusted until they expire, without
+// any calls to Github
+#[test]
+fn cached_membership() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    let member = ::mock_user(&mut req, ::user("member"));
+    let owner = ::mock_user(&mut req, ::user("owner"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    let team = {
+        let req: &mut Request = &mut req;
+        Team::insert(req.tx().unwrap(), "github:test-org:cached", Some(1),
+                     None, None).unwrap()
+    };
+    cache_membership(&mut req, &team, &owner, true, 0);
+
+    let body = r#"{"users":["github:test-org:cached"]}"#;
+    ok_resp!(middle.call(req.with_path("/api/v1/crates/foo/owners")
+                            .with_method(Method::Put)
+                            .with_body(body.as_bytes())));
+
+    // Github said they weren't a member
+    cache_membership(&mut req, &team, &member, false, 0);
+    ::mock_user(&mut req, member.clone());
+    let body = ::new_req_body(::krate("foo"), "2.0.0", vec![]);
+    let json = bad_resp!(middle.call(req.with_path("/api/v1/crates/new")
+                                        .with_body(&body)
+                                        .with_method(Method::Put)));
+    assert!(json.errors[0].detail.contains("another user"),
+            "{:?}", json.errors);
+
+    // Recent enough to be trusted, but old enough to be checked again in
+    // the background
+    cache_membership(&mut req, &team, &member, true, 45 * 60);
+    ok_resp!(middle.call(req.with_path("/api/v1/crates/new")
+                            .with_body(&body)
+                            .with_method(Method::Put)));
+    let req: &mut Request = &mut req;
+    let membership = Membership::find(req.tx().unwrap(), team.id, member.id)
+                                    .unwrap().unwrap();
+    assert!(membership.is_member);
+    assert!(membership.refresh_requested);
+}
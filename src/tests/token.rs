@@ -0,0 +1,190 @@
+use conduit::{Handler, Request, Method};
+
+use cargo_registry::db::RequestTransaction;
+use cargo_registry::token::{self, ApiToken, EncodableApiToken, Scope};
+
+#[derive(RustcDecodable)]
+struct TokenList { api_tokens: Vec<EncodableApiToken> }
+#[derive(RustcDecodable)]
+struct NewTokenResponse { api_token: EncodableApiToken, token: String }
+
+fn insert(req: &mut Request, name: &str, scopes: Option<&[Scope]>,
+          crates: Option<&[String]>) -> ApiToken {
+    let user_id = req.extensions().find::<::User>().unwrap().id;
+    let (mut token, plaintext) = ApiToken::insert(req.tx().unwrap(), user_id,
+                                                  name, scopes,
+                                                  crates).unwrap();
+    // Tests authenticate with the plaintext token
+    token.token = plaintext;
+    token
+}
+
+#[test]
+fn create_list_and_revoke() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/me/tokens");
+    ::mock_user(&mut req, ::user("foo"));
+    let mut response = ok_resp!(middle.call(&mut req));
+    assert_eq!(::json::<TokenList>(&mut response).api_tokens.len(), 0);
+
+    let body = r#"{"api_token":{"name":"ci","scopes":["publish"]}}"#;
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Put)
+                                               .with_body(body.as_bytes())));
+    let json: NewTokenResponse = ::json(&mut response);
+    assert_eq!(json.api_token.name, "ci");
+    assert_eq!(json.api_token.scopes, Some(vec!["publish".to_string()]));
+    assert_eq!(json.api_token.crates, None);
+    assert!(json.api_token.last_used_at.is_none());
+    assert!(!json.token.is_empty());
+    {
+        let req: &mut Request = &mut req;
+        let stored = ApiToken::find_by_token(req.tx().unwrap(), &json.token);
+        assert_eq!(stored.unwrap().token, token::digest(&json.token));
+    }
+
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Get)));
+    let json: TokenList = ::json(&mut response);
+    assert_eq!(json.api_tokens.len(), 1);
+    let id = json.api_tokens[0].id;
+
+    let path = format!("/me/tokens/{}", id);
+    ok_resp!(middle.call(req.with_method(Method::Delete).with_path(&path)));
+    bad_resp!(middle.call(&mut req));
+
+    let mut response = ok_resp!(middle.call(req.with_method(Method::Get)
+                                               .with_path("/me/tokens")));
+    assert_eq!(::json::<TokenList>(&mut response).api_tokens.len(), 0);
+}
+
+#[test]
+fn create_bad_scope() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/me/tokens");
+    ::mock_user(&mut req, ::user("foo"));
+    let body = r#"{"api_token":{"name":"ci","scopes":["everything"]}}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.contains("invalid api token scope"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn cannot_revoke_other_users_token() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Delete, "/me/tokens");
+    ::mock_user(&mut req, ::user("foo"));
+    let token = insert(&mut req, "mine", None, None);
+    ::mock_user(&mut req, ::user("bar"));
+
+    let path = format!("/me/tokens/{}", token.id);
+    bad_resp!(middle.call(req.with_path(&path)));
+}
+
+#[test]
+fn token_authenticates_and_is_touched() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    let token = insert(&mut req, "ci", None, None);
+    ::logout(&mut req);
+    req.header("Authorization", &token.token);
+    ok_resp!(middle.call(&mut req));
+
+    let req: &mut Request = &mut req;
+    let token = ApiToken::find_by_token(req.tx().unwrap(), &token.token);
+    assert!(token.unwrap().last_used_at.is_some());
+}
+
+#[test]
+fn publish_requires_scope() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    let token = insert(&mut req, "yank-only", Some(&[Scope::Yank][..]), None);
+    ::logout(&mut req);
+    req.header("Authorization", &token.token);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.contains("does not have the `publish` scope"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn publish_requires_crate() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::new_req(app, "foo", "1.0.0");
+    ::mock_user(&mut req, ::user("foo"));
+    let bar = insert(&mut req, "bar-only", None,
+                     Some(&["bar".to_string()][..]));
+    let foo = insert(&mut req, "foo-only", Some(&[Scope::Publish][..]),
+                     Some(&["foo".to_string()][..]));
+    ::logout(&mut req);
+
+    req.header("Authorization", &bar.token);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.contains("for the crate `foo`"),
+            "{:?}", json.errors);
+
+    req.header("Authorization", &foo.token);
+    ok_resp!(middle.call(&mut req));
+}
+
+#[test]
+fn yank_requires_scope() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Delete, "/api/v1/crates/foo/1.0.0/yank");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    let token = insert(&mut req, "publish-only", Some(&[Scope::Publish][..]),
+                       None);
+    ::logout(&mut req);
+    req.header("Authorization", &token.token);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.contains("does not have the `yank` scope"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn owners_requires_scope() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/api/v1/crates/foo/owners");
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_user(&mut req, ::user("bar"));
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("foo"));
+    let token = insert(&mut req, "yank-only", Some(&[Scope::Yank][..]), None);
+    ::logout(&mut req);
+    req.header("Authorization", &token.token);
+    let body = r#"{"users":["bar"]}"#;
+    let json = bad_resp!(middle.call(req.with_body(body.as_bytes())));
+    assert!(json.errors[0].detail.contains("does not have the `owners` scope"),
+            "{:?}", json.errors);
+}
+
+#[test]
+fn restricted_token_cannot_manage_tokens() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/me/tokens");
+    ::mock_user(&mut req, ::user("foo"));
+    let token = insert(&mut req, "yank-only", Some(&[Scope::Yank][..]), None);
+    ::logout(&mut req);
+    req.header("Authorization", &token.token);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.contains("restricted API token"),
+            "{:?}", json.errors);
+
+    let body = r#"{"api_token":{"name":"escalate"}}"#;
+    bad_resp!(middle.call(req.with_method(Method::Put)
+                             .with_body(body.as_bytes())));
+}
+
+#[test]
+fn restricted_token_cannot_reset_account_token() {
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/me/reset_token");
+    ::mock_user(&mut req, ::user("foo"));
+    let token = insert(&mut req, "yank-only", Some(&[Scope::Yank][..]), None);
+    ::logout(&mut req);
+    req.header("Authorization", &token.token);
+    let json = bad_resp!(middle.call(&mut req));
+    assert!(json.errors[0].detail.contains("restricted API token"),
+            "{:?}", json.errors);
+}
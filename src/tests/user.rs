@@ -4,16 +4,19 @@ use conduit::{Handler, Request, Response, Method};
 use conduit_middleware::Middleware;
 use conduit_test::MockRequest;
 
+use rustc_serialize::json::Json;
+
 use cargo_registry::Model;
 use cargo_registry::krate::EncodableCrate;
 use cargo_registry::user::{User, EncodableUser};
 use cargo_registry::db::RequestTransaction;
+use cargo_registry::token;
 use cargo_registry::version::EncodableVersion;
 
 #[derive(RustcDecodable)]
 struct AuthResponse { url: String, state: String }
 #[derive(RustcDecodable)]
-struct MeResponse { user: EncodableUser, api_token: String }
+struct MeResponse { user: EncodableUser }
 
 #[test]
 fn auth_gives_a_token() {
@@ -41,6 +44,7 @@ fn user_insert() {
 
     let user = t!(User::find_or_insert(&tx, "foo", None, None, None, "bar", "baz"));
     assert_eq!(t!(User::find_by_api_token(&tx, "baz")), user);
+    assert_eq!(user.api_token, token::digest("baz"));
     assert_eq!(t!(User::find(&tx, user.id)), user);
 
     assert_eq!(t!(User::find_or_insert(&tx, "foo", None, None, None,
@@ -63,7 +67,10 @@ fn me() {
     let mut response = ok_resp!(middle.call(&mut req));
     let json: MeResponse = ::json(&mut response);
     assert_eq!(json.user.email, user.email);
-    assert_eq!(json.api_token, user.api_token);
+
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json = ::json::<Json>(&mut response);
+    assert!(json.find("api_token").is_none());
 }
 
 #[test]
@@ -94,6 +101,24 @@ fn reset_token() {
     }
 }
 
+#[test]
+fn reset_token_is_stored_as_digest() {
+    #[derive(RustcDecodable)]
+    struct R { api_token: String }
+
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Put, "/me/reset_token");
+    let user = ::mock_user(&mut req, ::user("foo"));
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json: R = ::json(&mut response);
+
+    let req: &mut Request = &mut req;
+    let tx = req.tx().unwrap();
+    let u2 = User::find(tx, user.id).unwrap();
+    assert_eq!(u2.api_token, token::digest(&json.api_token));
+    assert_eq!(User::find_by_api_token(tx, &json.api_token).unwrap(), u2);
+}
+
 #[test]
 fn my_packages() {
     let (_b, app, middle) = ::app();
@@ -116,7 +141,7 @@ fn following() {
         versions: Vec<EncodableVersion>,
         meta: Meta,
     }
-    #[derive(RustcDecodable)] struct Meta { more: bool }
+    #[derive(RustcDecodable)] struct Meta { more: bool, next_page: Option<String> }
 
     let (_b, app, middle) = ::app();
     let mut req = ::req(app, Method::Get, "/");
@@ -147,6 +172,13 @@ fn following() {
     let r = ::json::<R>(&mut response);
     assert_eq!(r.versions.len(), 1);
     assert_eq!(r.meta.more, true);
+    let next = r.meta.next_page.unwrap();
+    let mut response = ok_resp!(middle.call(req.with_query(&next[1..])));
+    let r2 = ::json::<R>(&mut response);
+    assert_eq!(r2.versions.len(), 1);
+    assert!(r2.versions[0].id != r.versions[0].id);
+    assert_eq!(r2.meta.more, false);
+    assert!(r2.meta.next_page.is_none());
 
     ok_resp!(middle.call(req.with_path("/api/v1/crates/bar/follow")
                             .with_method(Method::Delete)));
@@ -1,10 +1,14 @@
 use std::collections::HashMap;
-use rustc_serialize::json::Json;
+use std::fs::File;
+use std::io::prelude::*;
+use rustc_serialize::json::{self, Json};
 
 use conduit::{Handler, Request, Method};
 use semver;
 
+use cargo_registry::Crate;
 use cargo_registry::db::RequestTransaction;
+use cargo_registry::upload as u;
 use cargo_registry::version::{EncodableVersion, Version};
 
 #[derive(RustcDecodable)]
@@ -70,3 +74,64 @@ fn authors() {
     let json = json.as_object().unwrap();
     assert!(json.contains_key(&"users".to_string()));
 }
+
+#[test]
+fn readme() {
+    #[derive(RustcDecodable)]
+    struct R { readme: Readme }
+    #[derive(RustcDecodable)]
+    struct Readme { content: String, rendered: String }
+
+    let (_b, app, middle) = ::app();
+    let mut req = ::req(app, Method::Get, "/api/v1/crates/foo/1.0.0/readme");
+    ::mock_user(&mut req, ::user("foo"));
+    let (_, v) = ::mock_crate(&mut req, ::krate("foo"));
+    bad_resp!(middle.call(&mut req));
+
+    {
+        let req: &mut Request = &mut req;
+        let content = "# foo\n\n<script>alert(1)</script>\n\n[a](javascript:b)";
+        v.set_readme(req.tx().unwrap(), content).unwrap();
+    }
+    let mut response = ok_resp!(middle.call(&mut req));
+    let json: R = ::json(&mut response);
+    assert!(json.readme.content.starts_with("# foo"));
+    assert!(json.readme.rendered.contains("<h1>foo</h1>"),
+            "{}", json.readme.rendered);
+    assert!(!json.readme.rendered.contains("<script>"),
+            "{}", json.readme.rendered);
+    assert!(!json.readme.rendered.contains("javascript"),
+            "{}", json.readme.rendered);
+}
+
+#[test]
+fn git_encode_matches_index() {
+    let (_b, app, middle) = ::app();
+    let dep = u::CrateDependency {
+        name: u::CrateName("bar".to_string()),
+        optional: true,
+        default_features: false,
+        features: vec![u::Feature("baz".to_string())],
+        version_req: u::CrateVersionReq(semver::VersionReq::parse("^0.1").unwrap()),
+        target: None,
+        kind: None,
+    };
+    let mut req = ::new_req_full(app, ::krate("foo"), "1.0.0", vec![dep]);
+    ::mock_user(&mut req, ::user("foo"));
+    ::mock_crate(&mut req, ::krate("bar"));
+    ok_resp!(middle.call(&mut req));
+    ::run_jobs(&mut req);
+
+    let mut contents = String::new();
+    File::open(&::git::checkout().join("3/f/foo")).unwrap()
+        .read_to_string(&mut contents).unwrap();
+
+    let req: &mut Request = &mut req;
+    let tx = req.tx().unwrap();
+    let krate = Crate::find_by_name(tx, "foo").unwrap();
+    let version = Version::find_by_num(tx, krate.id, &sv("1.0.0")).unwrap()
+                          .unwrap();
+    let line = json::encode(&version.git_encode(tx, "foo").unwrap()).unwrap();
+    assert_eq!(Json::from_str(&line).unwrap(),
+               Json::from_str(contents.trim()).unwrap());
+}
@@ -0,0 +1,265 @@
+use std::io::prelude::*;
+
+use conduit::{Request, Response};
+use conduit_router::RequestParams;
+use pg::GenericConnection;
+use pg::rows::Row;
+use openssl::crypto::hash::{hash, Type};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json;
+use time::Timespec;
+
+use {Model, User};
+use db::RequestTransaction;
+use krate::canonical_name;
+use user::RequestUser;
+use util::{RequestUtils, CargoResult, ChainError, internal, human};
+use util::errors::NotFound;
+
+/// A named API token belonging to a user, optionally restricted in what it
+/// may be used for.
+#[derive(Clone, Debug)]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub name: String,
+    /// The digest of the token, see `digest`.
+    pub token: String,
+    pub created_at: Timespec,
+    pub last_used_at: Option<Timespec>,
+    /// The actions this token may be used for, or `None` for all of them.
+    pub scopes: Option<Vec<Scope>>,
+    /// The crates this token may be used on, or `None` for all of them.
+    pub crates: Option<Vec<String>>,
+}
+
+/// An action which can be granted to an API token.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Scope {
+    Publish,
+    Yank,
+    Owners,
+}
+
+#[derive(RustcDecodable, RustcEncodable)]
+pub struct EncodableApiToken {
+    pub id: i32,
+    pub name: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    pub crates: Option<Vec<String>>,
+}
+
+impl Scope {
+    pub fn from_str(s: &str) -> Option<Scope> {
+        match s {
+            "publish" => Some(Scope::Publish),
+            "yank" => Some(Scope::Yank),
+            "owners" => Some(Scope::Owners),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Scope::Publish => "publish",
+            Scope::Yank => "yank",
+            Scope::Owners => "owners",
+        }
+    }
+}
+
+/// Hashes a plaintext API token into the hex-encoded SHA-256 digest that we
+/// actually store, so that the database never contains usable credentials.
+pub fn digest(token: &str) -> String {
+    hash(Type::SHA256, token.as_bytes()).to_hex()
+}
+
+impl ApiToken {
+    /// Creates a new token, returning it along with its plaintext value. This
+    /// is the only time the plaintext is available.
+    pub fn insert(conn: &GenericConnection,
+                  user_id: i32,
+                  name: &str,
+                  scopes: Option<&[Scope]>,
+                  crates: Option<&[String]>)
+                  -> CargoResult<(ApiToken, String)> {
+        let plaintext = User::new_api_token();
+        let token = digest(&plaintext);
+        let scopes = scopes.map(|s| {
+            s.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(",")
+        });
+        let crates = crates.map(|c| c.join(","));
+        let stmt = try!(conn.prepare("INSERT INTO api_tokens
+                                      (user_id, name, token, scopes, crates)
+                                      VALUES ($1, $2, $3, $4, $5)
+                                      RETURNING *"));
+        let rows = try!(stmt.query(&[&user_id, &name, &token, &scopes,
+                                     &crates]));
+        let token = Model::from_row(&try!(rows.iter().next().chain_error(|| {
+            internal("no api token returned")
+        })));
+        Ok((token, plaintext))
+    }
+
+    pub fn find_by_token(conn: &GenericConnection,
+                         token: &str) -> CargoResult<ApiToken> {
+        let stmt = try!(conn.prepare("SELECT * FROM api_tokens
+                                      WHERE token = $1 LIMIT 1"));
+        let rows = try!(stmt.query(&[&digest(token)]));
+        rows.iter().next().map(|r| Model::from_row(&r)).chain_error(|| {
+            NotFound
+        })
+    }
+
+    pub fn belonging_to(conn: &GenericConnection,
+                        user: &User) -> CargoResult<Vec<ApiToken>> {
+        let stmt = try!(conn.prepare("SELECT * FROM api_tokens
+                                      WHERE user_id = $1
+                                      ORDER BY created_at ASC"));
+        let rows = try!(stmt.query(&[&user.id]));
+        Ok(rows.iter().map(|r| Model::from_row(&r)).collect())
+    }
+
+    /// Records that this token was just used to authenticate a request.
+    pub fn touch(&self, conn: &GenericConnection) -> CargoResult<()> {
+        try!(conn.execute("UPDATE api_tokens SET last_used_at = $1
+                           WHERE id = $2", &[&::now(), &self.id]));
+        Ok(())
+    }
+
+    /// Returns whether this token may be used to perform `scope` on the
+    /// crate named `krate`.
+    pub fn allows(&self, scope: Scope, krate: &str) -> bool {
+        let scope_ok = self.scopes.as_ref().map_or(true, |s| {
+            s.contains(&scope)
+        });
+        let crate_ok = self.crates.as_ref().map_or(true, |c| {
+            c.iter().any(|c| canonical_name(c) == canonical_name(krate))
+        });
+        scope_ok && crate_ok
+    }
+
+    /// Returns whether this token is restricted in any way.
+    pub fn is_restricted(&self) -> bool {
+        self.scopes.is_some() || self.crates.is_some()
+    }
+
+    pub fn encodable(self) -> EncodableApiToken {
+        let ApiToken { id, user_id: _, name, token: _, created_at,
+                       last_used_at, scopes, crates } = self;
+        EncodableApiToken {
+            id: id,
+            name: name,
+            created_at: ::encode_time(created_at),
+            last_used_at: last_used_at.map(::encode_time),
+            scopes: scopes.map(|s| {
+                s.iter().map(|s| s.as_str().to_string()).collect()
+            }),
+            crates: crates,
+        }
+    }
+}
+
+impl Model for ApiToken {
+    fn from_row(row: &Row) -> ApiToken {
+        let scopes: Option<String> = row.get("scopes");
+        let crates: Option<String> = row.get("crates");
+        ApiToken {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            name: row.get("name"),
+            token: row.get("token"),
+            created_at: row.get("created_at"),
+            last_used_at: row.get("last_used_at"),
+            scopes: scopes.map(|s| {
+                s.split(',').filter_map(Scope::from_str).collect()
+            }),
+            crates: crates.map(|c| {
+                c.split(',').filter(|c| !c.is_empty())
+                 .map(|c| c.to_string()).collect()
+            }),
+        }
+    }
+
+    fn table_name(_: Option<ApiToken>) -> &'static str { "api_tokens" }
+}
+
+/// Handles the `GET /me/tokens` route.
+pub fn list(req: &mut Request) -> CargoResult<Response> {
+    // Otherwise a restricted token could create one without restrictions
+    try!(req.check_not_restricted("manage API tokens"));
+    let user = try!(req.user());
+    let tokens = try!(ApiToken::belonging_to(try!(req.tx()), user));
+    let tokens = tokens.into_iter().map(|t| t.encodable()).collect();
+
+    #[derive(RustcEncodable)]
+    struct R { api_tokens: Vec<EncodableApiToken> }
+    Ok(req.json(&R { api_tokens: tokens }))
+}
+
+/// Handles the `PUT /me/tokens` route.
+pub fn new(req: &mut Request) -> CargoResult<Response> {
+    try!(req.check_not_restricted("manage API tokens"));
+    let mut body = String::new();
+    try!(req.body().read_to_string(&mut body));
+
+    #[derive(RustcDecodable)]
+    struct NewApiTokenRequest { api_token: NewApiToken }
+    #[derive(RustcDecodable)]
+    struct NewApiToken {
+        name: String,
+        scopes: Option<Vec<String>>,
+        crates: Option<Vec<String>>,
+    }
+
+    let new: NewApiTokenRequest = try!(json::decode(&body).map_err(|_| {
+        human("invalid json request")
+    }));
+    let new = new.api_token;
+    if new.name.is_empty() {
+        return Err(human("api token name must not be empty"))
+    }
+    let scopes = match new.scopes {
+        Some(scopes) => {
+            let mut parsed = Vec::new();
+            for scope in scopes.iter() {
+                parsed.push(try!(Scope::from_str(scope).chain_error(|| {
+                    human(format!("invalid api token scope: `{}`", scope))
+                })));
+            }
+            Some(parsed)
+        }
+        None => None,
+    };
+
+    let user = try!(req.user());
+    let scopes = scopes.as_ref().map(|s| &s[..]);
+    let crates = new.crates.as_ref().map(|c| &c[..]);
+    let (token, plaintext) = try!(ApiToken::insert(try!(req.tx()), user.id,
+                                                   &new.name, scopes, crates));
+
+    #[derive(RustcEncodable)]
+    struct R { api_token: EncodableApiToken, token: String }
+    Ok(req.json(&R { api_token: token.encodable(), token: plaintext }))
+}
+
+/// Handles the `DELETE /me/tokens/:id` route.
+pub fn revoke(req: &mut Request) -> CargoResult<Response> {
+    try!(req.check_not_restricted("manage API tokens"));
+    let id = try!(req.params()["id"].parse::<i32>().map_err(|_| {
+        human("invalid api token id")
+    }));
+    let user = try!(req.user());
+    let amt = try!(try!(req.tx()).execute("DELETE FROM api_tokens
+                                           WHERE id = $1 AND user_id = $2",
+                                          &[&id, &user.id]));
+    if amt == 0 {
+        return Err(human("api token not found"))
+    }
+
+    #[derive(RustcEncodable)]
+    struct R { ok: bool }
+    Ok(req.json(&R{ ok: true }))
+}
@@ -7,7 +7,8 @@ use conduit_cookie::RequestSession;
 use Model;
 use db::RequestTransaction;
 use super::User;
-use util::errors::{CargoResult, Unauthorized, ChainError, std_error};
+use token::{ApiToken, Scope};
+use util::errors::{CargoResult, Unauthorized, ChainError, std_error, human};
 
 pub struct Middleware;
 
@@ -22,16 +23,36 @@ impl conduit_middleware::Middleware for Middleware {
                 }
             }
             None => {
-                let tx = try!(req.tx().map_err(std_error));
-                match req.headers().find("Authorization") {
-                    Some(headers) => {
-                        match User::find_by_api_token(tx, &headers[0]) {
-                            Ok(user) => user,
-                            Err(..) => return Ok(())
+                let header = req.headers().find("Authorization")
+                                .map(|h| h[0].to_string());
+                let header = match header {
+                    Some(header) => header,
+                    None => return Ok(()),
+                };
+                let (user, token) = {
+                    let tx = try!(req.tx().map_err(std_error));
+
+                    // Named tokens take precedence over the user's own token
+                    match ApiToken::find_by_token(tx, &header) {
+                        Ok(token) => {
+                            try!(token.touch(tx).map_err(std_error));
+                            match User::find(tx, token.user_id) {
+                                Ok(user) => (user, Some(token)),
+                                Err(..) => return Ok(()),
+                            }
+                        }
+                        Err(..) => {
+                            match User::find_by_api_token(tx, &header) {
+                                Ok(user) => (user, None),
+                                Err(..) => return Ok(())
+                            }
                         }
                     }
-                    None => return Ok(())
+                };
+                if let Some(token) = token {
+                    req.mut_extensions().insert(token);
                 }
+                user
             }
         };
 
@@ -42,10 +63,41 @@ impl conduit_middleware::Middleware for Middleware {
 
 pub trait RequestUser {
     fn user(&self) -> CargoResult<&User>;
+
+    /// Checks that the API token used to authenticate this request, if any,
+    /// may be used to perform `scope` on the crate named `krate`.
+    fn check_scope(&self, scope: Scope, krate: &str) -> CargoResult<()>;
+
+    /// Checks that the API token used to authenticate this request, if any,
+    /// isn't restricted to some scopes or crates. Requests which could be
+    /// used to get around those restrictions, such as creating another token,
+    /// need an unrestricted one. `action` describes what was attempted.
+    fn check_not_restricted(&self, action: &str) -> CargoResult<()>;
 }
 
 impl<'a> RequestUser for Request + 'a {
     fn user(&self) -> CargoResult<&User> {
         self.extensions().find::<User>().chain_error(|| Unauthorized)
     }
+
+    fn check_scope(&self, scope: Scope, krate: &str) -> CargoResult<()> {
+        match self.extensions().find::<ApiToken>() {
+            Some(token) if !token.allows(scope, krate) => {
+                Err(human(format!("this API token does not have the `{}` \
+                                   scope for the crate `{}`",
+                                  scope.as_str(), krate)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn check_not_restricted(&self, action: &str) -> CargoResult<()> {
+        match self.extensions().find::<ApiToken>() {
+            Some(token) if token.is_restricted() => {
+                Err(human(format!("a restricted API token cannot be used to \
+                                   {}", action)))
+            }
+            _ => Ok(()),
+        }
+    }
 }
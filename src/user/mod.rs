@@ -4,17 +4,18 @@ use conduit::{Request, Response};
 use conduit_cookie::{RequestSession};
 use pg::GenericConnection;
 use pg::rows::Row;
-use pg::types::Slice;
+use pg::types::{Slice, ToSql};
 use rand::{thread_rng, Rng};
+use time::Timespec;
 
 use {Model, Version};
 use app::RequestApp;
 use db::RequestTransaction;
 use krate::{Crate, EncodableCrate};
 use util::errors::NotFound;
-use util::{RequestUtils, CargoResult, internal, ChainError, human};
+use util::{RequestUtils, CargoResult, internal, ChainError, human, encode_seek};
 use version::EncodableVersion;
-use http;
+use {http, token};
 
 pub use self::middleware::{Middleware, RequestUser};
 
@@ -28,6 +29,7 @@ pub struct User {
     pub email: Option<String>,
     pub avatar: Option<String>,
     pub gh_access_token: String,
+    /// The digest of the user's API token, see `token::digest`.
     pub api_token: String,
 }
 
@@ -56,7 +58,7 @@ impl User {
                              token: &str) -> CargoResult<User> {
         let stmt = try!(conn.prepare("SELECT * FROM users \
                                       WHERE api_token = $1 LIMIT 1"));
-        return try!(stmt.query(&[&token])).iter().next()
+        return try!(stmt.query(&[&token::digest(token)])).iter().next()
                         .map(|r| Model::from_row(&r)).chain_error(|| {
             NotFound
         })
@@ -94,7 +96,7 @@ impl User {
                                       RETURNING *"));
         let rows = try!(stmt.query(&[&email,
                                      &access_token,
-                                     &api_token,
+                                     &token::digest(api_token),
                                      &login,
                                      &name, &avatar]));
         Ok(Model::from_row(&try!(rows.iter().next().chain_error(|| {
@@ -180,7 +182,6 @@ pub fn github_authorize(req: &mut Request) -> CargoResult<Response> {
 ///
 /// ```json
 /// {
-///     "api_token": "b84a63c4ea3fcb4ac84",
 ///     "user": {
 ///         "email": "foo@bar.org",
 ///         "name": "Foo Bar",
@@ -247,13 +248,18 @@ pub fn logout(req: &mut Request) -> CargoResult<Response> {
 }
 
 /// Handles the `GET /me/reset_token` route.
+///
+/// Only a digest of the token is stored, so this is the only time that the
+/// plaintext token is ever available.
 pub fn reset_token(req: &mut Request) -> CargoResult<Response> {
+    // The new token has no restrictions, so a restricted one can't get it.
+    try!(req.check_not_restricted("reset the account's API token"));
     let user = try!(req.user());
 
     let token = User::new_api_token();
     let conn = try!(req.tx());
     try!(conn.execute("UPDATE users SET api_token = $1 WHERE id = $2",
-                      &[&token, &user.id]));
+                      &[&token::digest(&token), &user.id]));
 
     #[derive(RustcEncodable)]
     struct R { api_token: String }
@@ -265,9 +271,8 @@ pub fn me(req: &mut Request) -> CargoResult<Response> {
     let user = try!(req.user());
 
     #[derive(RustcEncodable)]
-    struct R { user: EncodableUser, api_token: String }
-    let token = user.api_token.clone();
-    Ok(req.json(&R{ user: user.clone().encodable(), api_token: token }))
+    struct R { user: EncodableUser }
+    Ok(req.json(&R{ user: user.clone().encodable() }))
 }
 
 /// Handles the `GET /me/updates` route.
@@ -275,21 +280,39 @@ pub fn updates(req: &mut Request) -> CargoResult<Response> {
     let user = try!(req.user());
     let (offset, limit) = try!(req.pagination(10, 100));
     let tx = try!(req.tx());
-    let sql = "SELECT versions.* FROM versions
-               INNER JOIN follows
-                  ON follows.user_id = $1 AND
-                     follows.crate_id = versions.crate_id
-               ORDER BY versions.created_at DESC OFFSET $2 LIMIT $3";
-
-    // Load all versions
-    let stmt = try!(tx.prepare(sql));
-    let mut versions = Vec::new();
-    let mut crate_ids = Vec::new();
-    for row in try!(stmt.query(&[&user.id, &offset, &limit])) {
-        let version: Version = Model::from_row(&row);
-        crate_ids.push(version.crate_id);
-        versions.push(version);
+
+    // Later pages can start after the last version seen, given by when it
+    // was created and its id.
+    let seek = try!(req.seek::<(i64, i32, i32)>()).map(|(sec, nsec, id)| {
+        (Timespec::new(sec, nsec), id)
+    });
+    let limit_and_one = limit + 1;
+    let mut args = vec![&user.id as &ToSql, &offset, &limit_and_one];
+    let mut sql = "SELECT versions.* FROM versions
+                   INNER JOIN follows
+                      ON follows.user_id = $1 AND
+                         follows.crate_id = versions.crate_id".to_string();
+    if let Some((ref created_at, ref id)) = seek {
+        sql.push_str(" WHERE (versions.created_at, versions.id) < ($4, $5)");
+        args.push(created_at);
+        args.push(id);
     }
+    sql.push_str(" ORDER BY versions.created_at DESC, versions.id DESC
+                   OFFSET $2 LIMIT $3");
+
+    // Load all versions, plus one to tell whether there are more
+    let stmt = try!(tx.prepare(&sql));
+    let mut versions = try!(stmt.query(&args)).iter().map(|row| {
+        Model::from_row(&row)
+    }).collect::<Vec<Version>>();
+    let more = versions.len() as i64 > limit;
+    versions.truncate(limit as usize);
+    let crate_ids = versions.iter().map(|v| v.crate_id).collect::<Vec<_>>();
+    let next_page = versions.last().and_then(|last| {
+        if !more { return None }
+        let seek = (last.created_at.sec, last.created_at.nsec, last.id);
+        Some(req.next_page(Some(encode_seek(&seek))))
+    });
 
     // Load all crates
     let mut map = HashMap::new();
@@ -310,12 +333,6 @@ pub fn updates(req: &mut Request) -> CargoResult<Response> {
         v.encodable(&map[&id])
     }).collect();
 
-    // Check if we have another
-    let sql = format!("SELECT 1 WHERE EXISTS({})", sql);
-    let stmt = try!(tx.prepare(&sql));
-    let more = try!(stmt.query(&[&user.id, &(offset + limit), &limit]))
-                  .iter().next().is_some();
-
     #[derive(RustcEncodable)]
     struct R {
         versions: Vec<EncodableVersion>,
@@ -323,6 +340,10 @@ pub fn updates(req: &mut Request) -> CargoResult<Response> {
         meta: Meta,
     }
     #[derive(RustcEncodable)]
-    struct Meta { more: bool }
-    Ok(req.json(&R{ versions: versions, crates: crates, meta: Meta { more: more } }))
+    struct Meta { more: bool, next_page: Option<String> }
+    Ok(req.json(&R{
+        versions: versions,
+        crates: crates,
+        meta: Meta { more: more, next_page: next_page },
+    }))
 }
@@ -176,6 +176,34 @@ impl fmt::Display for NotFound {
     }
 }
 
+pub struct TooManyRequests {
+    /// How many seconds until the request may be retried.
+    pub retry_after: i64,
+}
+
+impl CargoError for TooManyRequests {
+    fn description(&self) -> &str { "too many requests" }
+
+    fn response(&self) -> Option<Response> {
+        let mut response = json_response(&Bad {
+            errors: vec![StringError {
+                detail: format!("too many requests, try again in {} seconds",
+                                self.retry_after),
+            }],
+        });
+        response.status = (429, "Too Many Requests");
+        response.headers.insert("Retry-After".to_string(),
+                                vec![self.retry_after.to_string()]);
+        return Some(response);
+    }
+}
+
+impl fmt::Display for TooManyRequests {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "too many requests, retry after {} seconds", self.retry_after)
+    }
+}
+
 pub struct Unauthorized;
 
 impl CargoError for Unauthorized {
@@ -46,4 +46,9 @@ impl<T> LazyCell<T> {
             None => None
         }
     }
+
+    /// Takes the contents out of this cell, leaving it empty.
+    pub fn take(&mut self) -> Option<T> {
+        self.inner.borrow_mut().take()
+    }
 }
@@ -3,12 +3,15 @@ use std::error::Error;
 use std::io::{self, Cursor};
 use std::sync::Arc;
 
-use rustc_serialize::{json, Encodable};
+use rustc_serialize::{json, Encodable, Decodable};
+use rustc_serialize::base64::{FromBase64, ToBase64, URL_SAFE};
 use rustc_serialize::json::Json;
 use url;
 
 use conduit::{Request, Response, Handler};
 use conduit_router::{RouteBuilder, RequestParams};
+use Env;
+use app::RequestApp;
 use db::RequestTransaction;
 use self::errors::NotFound;
 
@@ -34,6 +37,19 @@ pub trait RequestUtils {
     fn query(&self) -> HashMap<String, String>;
     fn wants_json(&self) -> bool;
     fn pagination(&self, default: usize, max: usize) -> CargoResult<(i64, i64)>;
+
+    /// Returns the sort key of the last item on the previous page, as given
+    /// by the `seek` parameter.
+    fn seek<T: Decodable>(&self) -> CargoResult<Option<T>>;
+
+    /// Returns the query string for the next page, which starts after the
+    /// item whose sort key is `seek`, or is the next numbered page if `None`.
+    fn next_page(&self, seek: Option<String>) -> String;
+}
+
+/// Encodes the sort key of an item for the `seek` parameter.
+pub fn encode_seek<T: Encodable>(key: &T) -> String {
+    json::encode(key).unwrap().as_bytes().to_base64(URL_SAFE)
 }
 
 pub fn json_response<T: Encodable>(t: &T) -> Response {
@@ -69,6 +85,48 @@ pub fn json_response<T: Encodable>(t: &T) -> Response {
     }
 }
 
+/// Responds with `rows` as CSV, the first of which should name the columns.
+pub fn csv_response(rows: &[Vec<String>]) -> Response {
+    let mut csv = String::new();
+    for row in rows {
+        let fields = row.iter().map(|field| {
+            if field.contains(|c: char| "\",\r\n".contains(c)) {
+                format!("\"{}\"", field.replace("\"", "\"\""))
+            } else {
+                field.clone()
+            }
+        }).collect::<Vec<_>>();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    let mut headers = HashMap::new();
+    headers.insert("Content-Type".to_string(),
+                   vec!["text/csv; charset=utf-8".to_string()]);
+    headers.insert("Content-Length".to_string(), vec![csv.len().to_string()]);
+    Response {
+        status: (200, "OK"),
+        headers: headers,
+        body: Box::new(Cursor::new(csv.into_bytes())),
+    }
+}
+
+/// The IP address of the client making `req`.
+///
+/// In production requests come through Heroku's router, which appends the
+/// address it got the request from to `X-Forwarded-For`. Anything before that
+/// was sent by the client, so only the last address can be trusted.
+pub fn client_ip(req: &Request) -> String {
+    if req.app().config.env == Env::Production {
+        let forwarded = req.headers().find("X-Forwarded-For").and_then(|values| {
+            values.last().and_then(|v| v.split(',').last())
+                  .map(|ip| ip.trim().to_string())
+        });
+        if let Some(ip) = forwarded {
+            return ip
+        }
+    }
+    req.remote_addr().ip().to_string()
+}
 
 impl<'a> RequestUtils for Request + 'a {
     fn json<T: Encodable>(&self, t: &T) -> Response {
@@ -108,8 +166,43 @@ impl<'a> RequestUtils for Request + 'a {
         if page == 0 {
             return Err(human("page indexing starts from 1, page 0 is invalid"))
         }
+        if query.contains_key("seek") {
+            if query.contains_key("page") {
+                return Err(human("cannot request both `page` and `seek`"))
+            }
+            return Ok((0, limit as i64))
+        }
         Ok((((page - 1) * limit) as i64, limit as i64))
     }
+
+    fn seek<T: Decodable>(&self) -> CargoResult<Option<T>> {
+        let seek = match self.query().remove("seek") {
+            Some(seek) => seek,
+            None => return Ok(None),
+        };
+        let key = seek.from_base64().ok().and_then(|key| {
+            String::from_utf8(key).ok()
+        }).and_then(|key| json::decode(&key).ok());
+        key.map(Some).chain_error(|| human("invalid `seek` parameter"))
+    }
+
+    fn next_page(&self, seek: Option<String>) -> String {
+        let mut query = self.query();
+        match seek {
+            Some(seek) => {
+                query.remove("page");
+                query.insert("seek".to_string(), seek);
+            }
+            None => {
+                let page = query.get("page").and_then(|s| s.parse::<usize>().ok())
+                                .unwrap_or(1);
+                query.insert("page".to_string(), (page + 1).to_string());
+            }
+        }
+        let mut pairs = query.into_iter().collect::<Vec<_>>();
+        pairs.sort();
+        format!("?{}", url::form_urlencoded::serialize(pairs))
+    }
 }
 
 pub struct C(pub fn(&mut Request) -> CargoResult<Response>);
@@ -7,20 +7,24 @@ use pg::rows::Row;
 use pg::types::Slice;
 use rustc_serialize::json;
 use semver;
-use time::Duration;
 use time::Timespec;
 use url;
 
 use {Model, Crate, User};
 use app::RequestApp;
+use background::Job;
 use db::RequestTransaction;
 use dependency::{Dependency, EncodableDependency, Kind};
-use download::{VersionDownload, EncodableVersionDownload};
+use download::{DownloadRange, EncodableVersionDownload};
 use git;
+use krate::canonical_name;
 use upload;
 use user::RequestUser;
 use owner::{rights, Rights};
+use render;
+use token::Scope;
 use util::{RequestUtils, CargoResult, ChainError, internal, human};
+use util::csv_response;
 
 #[derive(Clone)]
 pub struct Version {
@@ -30,8 +34,10 @@ pub struct Version {
     pub updated_at: Timespec,
     pub created_at: Timespec,
     pub downloads: i32,
+    pub unique_downloads: i32,
     pub features: HashMap<String, Vec<String>>,
     pub yanked: bool,
+    pub checksum: Option<String>,
 }
 
 table! {
@@ -44,9 +50,17 @@ table! {
         downloads -> Integer,
         features -> VarChar,
         yanked -> Bool,
+        checksum -> Nullable<VarChar>,
+        unique_downloads -> Integer,
     }
 }
 
+/// The README of a version, along with its rendering as HTML.
+pub struct Readme {
+    pub content: String,
+    pub rendered: String,
+}
+
 pub enum Author {
     User(User),
     Name(String),
@@ -61,6 +75,7 @@ pub struct EncodableVersion {
     pub updated_at: String,
     pub created_at: String,
     pub downloads: i32,
+    pub unique_downloads: i32,
     pub features: HashMap<String, Vec<String>>,
     pub yanked: bool,
     pub links: VersionLinks,
@@ -71,6 +86,7 @@ pub struct VersionLinks {
     pub dependencies: String,
     pub version_downloads: String,
     pub authors: String,
+    pub readme: String,
 }
 
 impl Version {
@@ -115,7 +131,8 @@ impl Version {
 
     pub fn encodable(self, crate_name: &str) -> EncodableVersion {
         let Version { id, crate_id: _, num, updated_at, created_at,
-                      downloads, features, yanked } = self;
+                      downloads, unique_downloads, features, yanked,
+                      checksum: _ } = self;
         let num = num.to_string();
         EncodableVersion {
             dl_path: format!("/api/v1/crates/{}/{}/download", crate_name, num),
@@ -125,6 +142,7 @@ impl Version {
             updated_at: ::encode_time(updated_at),
             created_at: ::encode_time(created_at),
             downloads: downloads,
+            unique_downloads: unique_downloads,
             features: features,
             yanked: yanked,
             links: VersionLinks {
@@ -133,6 +151,7 @@ impl Version {
                 version_downloads: format!("/api/v1/crates/{}/{}/downloads",
                                            crate_name, num),
                 authors: format!("/api/v1/crates/{}/{}/authors", crate_name, num),
+                readme: format!("/api/v1/crates/{}/{}/readme", crate_name, num),
             },
         }
     }
@@ -147,10 +166,22 @@ impl Version {
         let krate = try!(Crate::find_by_name(conn, name).map_err(|_| {
             human(format!("no known crate named `{}`", &**name))
         }));
+        // A dependency on an old name of a renamed crate is on the versions
+        // in the old name's index file, so its index entry keeps that name.
+        let canon = canonical_name(name);
+        let published_name = if canon == canonical_name(&krate.name) {
+            krate.name.clone()
+        } else {
+            let aliases = try!(krate.aliases(conn));
+            aliases.into_iter().map(|(alias, _)| alias).rev().find(|alias| {
+                canonical_name(alias) == canon
+            }).unwrap_or(krate.name.clone())
+        };
         let features: Vec<String> = dep.features.iter().map(|s| {
             s[..].to_string()
         }).collect();
         let dep = try!(Dependency::insert(conn, self.id, krate.id,
+                                          &published_name,
                                           &*dep.version_req,
                                           dep.kind.unwrap_or(Kind::Normal),
                                           dep.optional,
@@ -168,12 +199,28 @@ impl Version {
                                       FROM dependencies
                                       LEFT JOIN crates
                                         ON crates.id = dependencies.crate_id
-                                      WHERE dependencies.version_id = $1"));
+                                      WHERE dependencies.version_id = $1
+                                      ORDER BY dependencies.id ASC"));
         Ok(try!(stmt.query(&[&self.id])).into_iter().map(|r| {
             (Model::from_row(&r), r.get("crate_name"))
         }).collect())
     }
 
+    /// Returns the line in the git index describing this version of the
+    /// crate `crate_name`.
+    pub fn git_encode(&self, conn: &GenericConnection,
+                      crate_name: &str) -> CargoResult<git::Crate> {
+        let deps = try!(self.dependencies(conn));
+        Ok(git::Crate {
+            name: crate_name.to_string(),
+            vers: self.num.to_string(),
+            deps: deps.iter().map(|&(ref dep, _)| dep.git_encode()).collect(),
+            cksum: self.checksum.clone().unwrap_or(String::new()),
+            features: self.features.clone(),
+            yanked: Some(self.yanked),
+        })
+    }
+
     pub fn authors(&self, conn: &GenericConnection) -> CargoResult<Vec<Author>> {
         let stmt = try!(conn.prepare("SELECT * FROM version_authors
                                        WHERE version_id = $1"));
@@ -198,9 +245,44 @@ impl Version {
         Ok(())
     }
 
+    pub fn readme(&self, conn: &GenericConnection)
+                  -> CargoResult<Option<Readme>> {
+        let stmt = try!(conn.prepare("SELECT * FROM version_readmes
+                                       WHERE version_id = $1"));
+        let rows = try!(stmt.query(&[&self.id]));
+        Ok(rows.iter().next().map(|row| {
+            Readme {
+                content: row.get("content"),
+                rendered: row.get("rendered"),
+            }
+        }))
+    }
+
+    /// Stores the README of this version, rendering it to HTML.
+    pub fn set_readme(&self, conn: &GenericConnection,
+                      content: &str) -> CargoResult<()> {
+        let rendered = render::markdown_to_html(content);
+        try!(conn.execute("INSERT INTO version_readmes
+                           (version_id, content, rendered)
+                           VALUES ($1, $2, $3)",
+                          &[&self.id, &content, &rendered]));
+        Ok(())
+    }
+
+    /// Records the hex-encoded SHA-256 checksum of this version's `.crate`
+    /// file.
+    pub fn set_checksum(&mut self, conn: &GenericConnection,
+                        checksum: &str) -> CargoResult<()> {
+        try!(conn.execute("UPDATE versions SET checksum = $1 WHERE id = $2",
+                          &[&checksum, &self.id]));
+        self.checksum = Some(checksum.to_string());
+        Ok(())
+    }
+
     pub fn yank(&self, conn: &GenericConnection, yanked: bool) -> CargoResult<()> {
-        try!(conn.execute("UPDATE versions SET yanked = $1 WHERE id = $2",
-                          &[&yanked, &self.id]));
+        try!(conn.execute("UPDATE versions SET yanked = $1, updated_at = $2
+                            WHERE id = $3",
+                          &[&yanked, &::now(), &self.id]));
         Ok(())
     }
 }
@@ -219,8 +301,10 @@ impl Model for Version {
             updated_at: row.get("updated_at"),
             created_at: row.get("created_at"),
             downloads: row.get("downloads"),
+            unique_downloads: row.get("unique_downloads"),
             features: features,
             yanked: row.get("yanked"),
+            checksum: row.get("checksum"),
         }
     }
     fn table_name(_: Option<Version>) -> &'static str { "versions" }
@@ -283,6 +367,27 @@ pub fn show(req: &mut Request) -> CargoResult<Response> {
     Ok(req.json(&R { version: version.encodable(&krate.name) }))
 }
 
+/// Handles the `GET /crates/:crate_id/:version/readme` route.
+pub fn readme(req: &mut Request) -> CargoResult<Response> {
+    let (version, krate) = try!(version_and_crate(req));
+    let readme = try!(version.readme(try!(req.tx())));
+    let readme = try!(readme.chain_error(|| {
+        human(format!("version `{}` of crate `{}` does not have a readme",
+                      version.num, krate.name))
+    }));
+
+    #[derive(RustcEncodable)]
+    struct R { readme: EncodableReadme }
+    #[derive(RustcEncodable)]
+    struct EncodableReadme { content: String, rendered: String }
+    Ok(req.json(&R {
+        readme: EncodableReadme {
+            content: readme.content,
+            rendered: readme.rendered,
+        },
+    }))
+}
+
 fn version_and_crate(req: &mut Request) -> CargoResult<(Version, Crate)> {
     let crate_name = &req.params()["crate_id"];
     let semver = &req.params()["version"];
@@ -316,16 +421,21 @@ pub fn dependencies(req: &mut Request) -> CargoResult<Response> {
 /// Handles the `GET /crates/:crate_id/:version/downloads` route.
 pub fn downloads(req: &mut Request) -> CargoResult<Response> {
     let (version, _) = try!(version_and_crate(req));
+    let query = req.query();
+    let range = try!(DownloadRange::from_query(&query));
 
     let tx = try!(req.tx());
-    let cutoff_date = ::now() + Duration::days(-90);
-    let stmt = try!(tx.prepare("SELECT * FROM version_downloads
-                                WHERE date > $1 AND version_id = $2
-                                ORDER BY date ASC"));
-    let mut downloads = Vec::new();
-    for row in try!(stmt.query(&[&cutoff_date, &version.id])) {
-        let download: VersionDownload = Model::from_row(&row);
-        downloads.push(download.encodable());
+    let downloads = try!(range.version_downloads(tx, &[version.id]));
+
+    if query.get("format").map(|s| &s[..]) == Some("csv") {
+        let mut rows = vec![vec!["date".to_string(), "version".to_string(),
+                                 "downloads".to_string(),
+                                 "unique_downloads".to_string()]];
+        rows.extend(downloads.iter().map(|d| {
+            vec![d.date[..10].to_string(), version.num.to_string(),
+                 d.downloads.to_string(), d.unique_downloads.to_string()]
+        }));
+        return Ok(csv_response(&rows))
     }
 
     #[derive(RustcEncodable)]
@@ -367,13 +477,20 @@ fn modify_yank(req: &mut Request, yanked: bool) -> CargoResult<Response> {
     let user = try!(req.user());
     let tx = try!(req.tx());
     let owners = try!(krate.owners(tx));
-    if try!(rights(req.app(), &owners, &user)) < Rights::Publish {
+    if try!(rights(req, tx, &owners, &user, Scope::Yank,
+                   &krate.name)) < Rights::Publish {
         return Err(human("must already be an owner to yank or unyank"))
     }
 
     if version.yanked != yanked {
         try!(version.yank(tx, yanked));
-        try!(git::yank(&**req.app(), &krate.name, &version.num, yanked));
+        let aliases = try!(krate.aliases(tx));
+        let job = Job::Yank {
+            krate: krate.published_name(&aliases, &version).to_string(),
+            version: version.num.to_string(),
+            yanked: yanked,
+        };
+        try!(job.enqueue(tx));
     }
 
     #[derive(RustcEncodable)]